@@ -0,0 +1,10 @@
+fn main() {
+    println!("cargo:rerun-if-changed=proto/jarl.proto");
+
+    #[cfg(feature = "grpc")]
+    {
+        // Vendored so that building does not require protoc to be installed
+        std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path().unwrap());
+        tonic_prost_build::compile_protos("proto/jarl.proto").unwrap();
+    }
+}
@@ -0,0 +1,411 @@
+use std::fmt;
+use std::io::{BufRead, Write};
+use std::net::ToSocketAddrs;
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+use crate::protocol::VERSION;
+use crate::Command;
+
+
+/// Time allowed for each command, connecting included, if not configured.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+
+
+/// What a client does when JARL cannot be reached, or does not reply in time.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Fallback {
+    /// Carry on as if JARL had replied with no delay, relying on the normal
+    /// handling of exceeded rate limits of the target service
+    #[default]
+    Proceed,
+    /// Carry on as if JARL had replied with this delay, in seconds. Negative
+    /// or non-finite delays count as 0
+    Delay(f32),
+    /// Return the error
+    Fail,
+}
+
+impl Fallback {
+
+    /// The delay to use for a command that failed with `error`.
+    fn apply(self, error: ClientError) -> Result<f32, ClientError> {
+        match self {
+            _ if !error.is_unavailable() => Err(error),
+            Fallback::Proceed => Ok(0.0),
+            Fallback::Delay(delay) => Ok(if is_valid(delay) { delay } else { 0.0 }),
+            Fallback::Fail => Err(error),
+        }
+    }
+
+}
+
+
+#[derive(Debug)]
+pub enum ClientError {
+    Io(std::io::Error),
+    Timeout,
+    /// The command was rejected by JARL, such as for an unknown service
+    Rejected { code: String, message: String },
+    InvalidReply(String),
+}
+
+impl ClientError {
+
+    /// Whether JARL could not be reached, did not reply as expected, or
+    /// replied that it is out of contact with its cluster, as opposed to
+    /// rejecting the command. Only these errors are covered by the client's
+    /// `Fallback`.
+    pub fn is_unavailable(&self) -> bool {
+        match self {
+            ClientError::Rejected { code, .. } => code == "unavailable",
+            _ => true,
+        }
+    }
+
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Io(error) => write!(f, "could not reach jarl: {}", error),
+            ClientError::Timeout => write!(f, "jarl did not reply in time"),
+            ClientError::Rejected { code, message } => write!(f, "{} ({})", message, code),
+            ClientError::InvalidReply(line) => write!(f, "invalid reply `{}`", line),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<std::io::Error> for ClientError {
+    fn from(error: std::io::Error) -> Self {
+        match error.kind() {
+            std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock => ClientError::Timeout,
+            _ => ClientError::Io(error),
+        }
+    }
+}
+
+
+/// Client of the line protocol served on the named port, keeping its
+/// connection open between commands.
+pub struct Client {
+    address: String,
+    timeout: Duration,
+    fallback: Fallback,
+    connection: Option<BufReader<TcpStream>>,
+}
+
+impl Client {
+
+    /// Client of the named port at `address`, such as `localhost:1230`. The
+    /// connection is opened by the first command.
+    pub fn new(address: impl Into<String>) -> Self {
+        Client {
+            address: address.into(),
+            timeout: DEFAULT_TIMEOUT,
+            fallback: Fallback::default(),
+            connection: None,
+        }
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn with_fallback(mut self, fallback: Fallback) -> Self {
+        self.fallback = fallback;
+        self
+    }
+
+    /// Records a request taking `cost` units of the limit of `service`, and
+    /// returns the seconds to wait before making it.
+    pub async fn acquire(&mut self, service: &str, cost: u32) -> Result<f32, ClientError> {
+        self.delay(Command::Acquire { service: service.to_string(), cost }).await
+    }
+
+    /// Returns the seconds a request would have to wait, without recording it.
+    pub async fn peek(&mut self, service: &str, cost: u32) -> Result<f32, ClientError> {
+        self.delay(Command::Peek { service: service.to_string(), cost }).await
+    }
+
+    /// Gives back the units of a request that was acquired but not made.
+    pub async fn release(&mut self, service: &str, cost: u32) -> Result<(), ClientError> {
+        self.delay(Command::Release { service: service.to_string(), cost }).await.map(|_delay| ())
+    }
+
+    /// Records a request and sleeps for its delay, returning the seconds slept.
+    pub async fn acquire_and_wait(&mut self, service: &str, cost: u32) -> Result<f32, ClientError> {
+        let delay = self.acquire(service, cost).await?;
+        tokio::time::sleep(Duration::try_from_secs_f32(delay).unwrap_or_default()).await;
+        Ok(delay)
+    }
+
+    async fn delay(&mut self, command: Command) -> Result<f32, ClientError> {
+        let outcome = match tokio::time::timeout(self.timeout, self.send(&command)).await {
+            Ok(outcome) => outcome,
+            Err(_elapsed) => Err(ClientError::Timeout),
+        };
+
+        // The reply to a command that failed may still arrive, so the
+        // connection cannot be used for the next one
+        if outcome.as_ref().is_err_and(ClientError::is_unavailable) {
+            self.connection = None;
+        }
+        outcome.or_else(|error| self.fallback.apply(error))
+    }
+
+    async fn send(&mut self, command: &Command) -> Result<f32, ClientError> {
+        let connection = match &mut self.connection {
+            Some(connection) => connection,
+            None => self.connection.insert(BufReader::new(TcpStream::connect(&self.address).await?)),
+        };
+
+        connection.get_mut().write_all(format!("{}\n", command).as_bytes()).await?;
+        let mut line = String::new();
+        connection.read_line(&mut line).await?;
+        parse_reply(&line)
+    }
+
+}
+
+
+/// Blocking counterpart of `Client`, for programs without an async runtime.
+pub struct BlockingClient {
+    address: String,
+    timeout: Duration,
+    fallback: Fallback,
+    connection: Option<std::io::BufReader<std::net::TcpStream>>,
+}
+
+impl BlockingClient {
+
+    /// Client of the named port at `address`, such as `localhost:1230`. The
+    /// connection is opened by the first command.
+    pub fn new(address: impl Into<String>) -> Self {
+        BlockingClient {
+            address: address.into(),
+            timeout: DEFAULT_TIMEOUT,
+            fallback: Fallback::default(),
+            connection: None,
+        }
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn with_fallback(mut self, fallback: Fallback) -> Self {
+        self.fallback = fallback;
+        self
+    }
+
+    /// Records a request taking `cost` units of the limit of `service`, and
+    /// returns the seconds to wait before making it.
+    pub fn acquire(&mut self, service: &str, cost: u32) -> Result<f32, ClientError> {
+        self.delay(Command::Acquire { service: service.to_string(), cost })
+    }
+
+    /// Returns the seconds a request would have to wait, without recording it.
+    pub fn peek(&mut self, service: &str, cost: u32) -> Result<f32, ClientError> {
+        self.delay(Command::Peek { service: service.to_string(), cost })
+    }
+
+    /// Gives back the units of a request that was acquired but not made.
+    pub fn release(&mut self, service: &str, cost: u32) -> Result<(), ClientError> {
+        self.delay(Command::Release { service: service.to_string(), cost }).map(|_delay| ())
+    }
+
+    /// Records a request and sleeps for its delay, returning the seconds slept.
+    pub fn acquire_and_wait(&mut self, service: &str, cost: u32) -> Result<f32, ClientError> {
+        let delay = self.acquire(service, cost)?;
+        std::thread::sleep(Duration::try_from_secs_f32(delay).unwrap_or_default());
+        Ok(delay)
+    }
+
+    fn delay(&mut self, command: Command) -> Result<f32, ClientError> {
+        let outcome = self.send(&command);
+        if outcome.as_ref().is_err_and(ClientError::is_unavailable) {
+            self.connection = None;
+        }
+        outcome.or_else(|error| self.fallback.apply(error))
+    }
+
+    fn send(&mut self, command: &Command) -> Result<f32, ClientError> {
+        let connection = match &mut self.connection {
+            Some(connection) => connection,
+            None => self.connection.insert(std::io::BufReader::new(self.connect()?)),
+        };
+
+        connection.get_mut().write_all(format!("{}\n", command).as_bytes())?;
+        let mut line = String::new();
+        connection.read_line(&mut line)?;
+        parse_reply(&line)
+    }
+
+    /// Connects to the first address `address` resolves to that accepts the
+    /// connection within the timeout.
+    fn connect(&self) -> Result<std::net::TcpStream, ClientError> {
+        let mut error = ClientError::Io(std::io::ErrorKind::AddrNotAvailable.into());
+        for address in self.address.to_socket_addrs()? {
+            match std::net::TcpStream::connect_timeout(&address, self.timeout) {
+                Ok(stream) => {
+                    stream.set_read_timeout(Some(self.timeout))?;
+                    stream.set_write_timeout(Some(self.timeout))?;
+                    return Ok(stream);
+                }
+                Err(failure) => error = failure.into(),
+            }
+        }
+        Err(error)
+    }
+
+}
+
+
+/// Whether `delay` can be slept for.
+fn is_valid(delay: f32) -> bool {
+    delay.is_finite() && delay >= 0.0
+}
+
+
+/// Parses a reply of the line protocol to the delay it holds, 0 for replies
+/// without one. Delays that are negative or not finite are invalid.
+fn parse_reply(line: &str) -> Result<f32, ClientError> {
+    let invalid = || ClientError::InvalidReply(line.trim().to_string());
+    if line.is_empty() {
+        return Err(ClientError::Io(std::io::ErrorKind::UnexpectedEof.into()));
+    }
+
+    let (version, rest) = line.trim().split_once(' ').ok_or_else(invalid)?;
+    if version != format!("JARL/{}", VERSION) {
+        return Err(invalid());
+    }
+
+    let (status, values) = rest.split_once(' ').unwrap_or((rest, ""));
+    match status {
+        "OK" if values.is_empty() => Ok(0.0),
+        "OK" => values.parse().ok().filter(|delay| is_valid(*delay)).ok_or_else(invalid),
+        "ERR" => {
+            let (code, message) = values.split_once(' ').unwrap_or((values, ""));
+            Err(ClientError::Rejected { code: code.to_string(), message: message.to_string() })
+        }
+        _ => Err(invalid()),
+    }
+}
+
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tokio::sync::mpsc;
+
+    use crate::{Config, Registry};
+    use crate::client::{parse_reply, BlockingClient, Client, ClientError, Fallback};
+    use crate::config::ServiceConfig;
+    use crate::server::Server;
+    use crate::testing::free_port;
+
+    async fn start(requests: u32) -> (Server, String) {
+        let mut config = Config::default();
+        config.server.ip = Some("127.0.0.1".parse().unwrap());
+        config.server.named_port = Some(free_port());
+        config.services.push(ServiceConfig::new("service", requests, 60));
+
+        let (sender, _receiver) = mpsc::channel(1);
+        let server = Server::start(&config, Arc::new(Registry::from_config(&config)), sender).await.unwrap();
+        (server, config.named_address().unwrap().to_string())
+    }
+
+    #[test]
+    fn parse_replies() {
+        assert_eq!(parse_reply("JARL/1 OK 1.500\n").unwrap(), 1.5);
+        assert_eq!(parse_reply("JARL/1 OK\n").unwrap(), 0.0);
+        assert!(matches!(
+            parse_reply("JARL/1 ERR unknown-service no service named `api`\n"),
+            Err(ClientError::Rejected { code, message }) if code == "unknown-service" && message == "no service named `api`"
+        ));
+        assert!(matches!(parse_reply("JARL/2 OK 1.000\n"), Err(ClientError::InvalidReply(_))));
+        assert!(matches!(parse_reply("0.000"), Err(ClientError::InvalidReply(_))));
+        for delay in ["NaN", "inf", "-1.000"] {
+            assert!(matches!(parse_reply(&format!("JARL/1 OK {}\n", delay)), Err(ClientError::InvalidReply(_))));
+        }
+        assert!(matches!(parse_reply(""), Err(ClientError::Io(_))));
+    }
+
+    #[tokio::test]
+    /// Commands share one connection, and errors replied by JARL are returned
+    /// whatever the fallback.
+    async fn async_client() {
+        let (server, address) = start(2).await;
+        let mut client = Client::new(address);
+
+        assert_eq!(client.peek("service", 2).await.unwrap(), 0.0);
+        assert_eq!(client.acquire_and_wait("service", 2).await.unwrap(), 0.0);
+        assert!(client.peek("service", 1).await.unwrap() > 0.0);
+        client.release("service", 1).await.unwrap();
+        assert_eq!(client.acquire("service", 1).await.unwrap(), 0.0);
+
+        let error = client.acquire("unknown", 1).await.unwrap_err();
+        assert!(matches!(error, ClientError::Rejected { .. }));
+
+        drop(client);
+        assert!(server.shutdown(Duration::from_secs(5)).await, "Connection should be closed.");
+    }
+
+    #[tokio::test]
+    /// Clients fall back to their configured delay when JARL is not
+    /// listening, or does not reply in time.
+    async fn fallback() {
+        let address = format!("127.0.0.1:{}", free_port());
+        assert_eq!(Client::new(&address).acquire("service", 1).await.unwrap(), 0.0);
+
+        let mut client = Client::new(&address).with_fallback(Fallback::Delay(2.5));
+        assert_eq!(client.acquire("service", 1).await.unwrap(), 2.5);
+
+        let mut client = Client::new(&address).with_fallback(Fallback::Delay(f32::NAN));
+        assert_eq!(client.acquire_and_wait("service", 1).await.unwrap(), 0.0);
+
+        let mut client = Client::new(&address).with_fallback(Fallback::Fail);
+        assert!(matches!(client.acquire("service", 1).await, Err(ClientError::Io(_))));
+
+        // Accepts connections, but never replies
+        let silent = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = Client::new(silent.local_addr().unwrap().to_string())
+            .with_timeout(Duration::from_millis(50))
+            .with_fallback(Fallback::Fail);
+        assert!(matches!(client.acquire("service", 1).await, Err(ClientError::Timeout)));
+
+        // A node out of contact with its cluster rejects requests
+        let (server, address) = start(1).await;
+        server.registry().set_available(false);
+        let mut client = Client::new(&address).with_fallback(Fallback::Delay(2.5));
+        assert_eq!(client.acquire("service", 1).await.unwrap(), 2.5);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn blocking_client() {
+        let (_server, address) = start(1).await;
+
+        tokio::task::spawn_blocking(move || {
+            let mut client = BlockingClient::new(address);
+            assert_eq!(client.acquire("service", 1).unwrap(), 0.0);
+            assert!(client.peek("service", 1).unwrap() > 0.0);
+            client.release("service", 1).unwrap();
+            assert_eq!(client.acquire_and_wait("service", 1).unwrap(), 0.0);
+            assert!(matches!(client.acquire("unknown", 1), Err(ClientError::Rejected { .. })));
+
+            let mut client = BlockingClient::new(format!("127.0.0.1:{}", free_port()))
+                .with_fallback(Fallback::Delay(1.0));
+            assert_eq!(client.acquire("service", 1).unwrap(), 1.0);
+        }).await.unwrap();
+    }
+}
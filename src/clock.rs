@@ -0,0 +1,142 @@
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+
+/// Source of the timestamps used by rate limiters, in seconds.
+pub trait Clock: Send + Sync {
+
+    /// Current time in seconds. Must never go backwards.
+    fn now(&self) -> f64;
+
+    /// Converts a timestamp of this clock to the UNIX clock, such as before
+    /// saving it to a file. Defaults to the timestamp itself.
+    fn local_to_unix(&self, timestamp: f64) -> f64 {
+        timestamp
+    }
+
+    /// Converts a UNIX timestamp to this clock. Defaults to the timestamp itself.
+    fn unix_to_local(&self, timestamp: f64) -> f64 {
+        timestamp
+    }
+
+}
+
+
+/// Clock used by default.
+pub fn default_clock() -> Arc<dyn Clock> {
+    Arc::new(MonotonicClock)
+}
+
+
+/// Instant of the first reading of the clock, and the UNIX time it was taken at.
+struct Anchor {
+    instant: Instant,
+    unix: f64,
+}
+
+static ANCHOR: OnceLock<Anchor> = OnceLock::new();
+
+
+/// Current UNIX time in seconds, or 0 if the system clock is set before 1970.
+fn unix_now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs_f64())
+        .unwrap_or_default()
+}
+
+
+/// Monotonic clock with sub-microsecond resolution, which starts at the UNIX
+/// time of its first reading in the process. Unlike the system clock, it never
+/// goes backwards or jumps when the system time is changed.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MonotonicClock;
+
+impl Clock for MonotonicClock {
+
+    fn now(&self) -> f64 {
+        let anchor = ANCHOR.get_or_init(|| Anchor { instant: Instant::now(), unix: unix_now() });
+        anchor.unix + anchor.instant.elapsed().as_secs_f64()
+    }
+
+    /// Keeps how long ago (or how far ahead) the timestamp is.
+    fn local_to_unix(&self, timestamp: f64) -> f64 {
+        unix_now() - (self.now() - timestamp)
+    }
+
+    /// Keeps how long ago (or how far ahead) the timestamp is.
+    fn unix_to_local(&self, timestamp: f64) -> f64 {
+        self.now() - (unix_now() - timestamp)
+    }
+
+}
+
+
+/// Clock that only moves when told to, for simulating time in tests. Clones
+/// share the same time.
+#[derive(Clone, Debug, Default)]
+pub struct ManualClock {
+    now: Arc<Mutex<f64>>,
+}
+
+impl ManualClock {
+
+    pub fn new(now: f64) -> Self {
+        ManualClock { now: Arc::new(Mutex::new(now)) }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration.as_secs_f64();
+    }
+
+    /// Moves the clock to `now`, unless it is earlier than the current time.
+    pub fn set(&self, now: f64) {
+        let mut current = self.now.lock().unwrap();
+        *current = current.max(now);
+    }
+
+}
+
+impl Clock for ManualClock {
+
+    fn now(&self) -> f64 {
+        *self.now.lock().unwrap()
+    }
+
+}
+
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::clock::{Clock, ManualClock, MonotonicClock};
+
+    #[test]
+    fn monotonic() {
+        let first = MonotonicClock.now();
+        let second = MonotonicClock.now();
+        assert!(second >= first, "Clock should never go backwards.");
+    }
+
+    #[test]
+    /// Converting to the UNIX clock and back keeps the age of a timestamp.
+    fn convert_unix() {
+        let timestamp = MonotonicClock.now() - 30.0;
+        let converted = MonotonicClock.unix_to_local(MonotonicClock.local_to_unix(timestamp));
+        assert!((converted - timestamp).abs() < 0.01);
+    }
+
+    #[test]
+    fn manual() {
+        let clock = ManualClock::new(100.0);
+        let shared = clock.clone();
+
+        clock.advance(Duration::from_millis(1500));
+        assert_eq!(shared.now(), 101.5);
+
+        clock.set(50.0);
+        assert_eq!(shared.now(), 101.5, "Clock should never go backwards.");
+    }
+}
@@ -0,0 +1,498 @@
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::task::JoinHandle;
+use tokio::time::MissedTickBehavior;
+
+use crate::{error, Config, Registry};
+use crate::config::ServiceConfig;
+
+mod lease;
+
+use lease::Leases;
+
+
+/// Longest heartbeat read from peers.
+const MAX_HEARTBEAT_LENGTH: usize = 64;
+
+
+/// How the nodes of a cluster share the limit of every service.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ClusterMode {
+    /// Even shares over UDP heartbeats, requests rejected by the nodes out of
+    /// contact with a majority of the cluster. Needs at least 3 nodes
+    #[default]
+    Quorum,
+    /// Shares leased over TCP by demand, kept by the nodes cut off from the
+    /// others
+    Lease,
+}
+
+
+/// What this node knows of one of its peers.
+#[derive(Clone, Copy, Debug, Default)]
+struct Peer {
+    /// Last time the peer sent a heartbeat
+    heard: Option<Instant>,
+    /// Until when this node may have recorded requests beyond its share
+    /// because the peer was left out of the split, which the peer has to
+    /// wait for before recording requests again
+    excluded_until: Option<Instant>,
+}
+
+
+/// Membership of this node in a cluster, from the heartbeats of its peers.
+///
+/// The limit of every service is split evenly between the nodes that sent a
+/// heartbeat within the `timeout`, plus the `period` of the service, as the
+/// requests a node recorded before going down still count for a period.
+/// Peers not heard from yet are counted since the node started.
+///
+/// A node that hears from less than a majority of the cluster stops
+/// recording requests after `timeout`, before the others take over its
+/// share, so that a partition cannot let both sides use the whole limit.
+/// When it comes back, its peers tell it how long they may still have
+/// requests recorded from its share, and it waits for them to expire.
+struct Membership {
+    address: SocketAddr,
+    started: Instant,
+    peers: HashMap<SocketAddr, Peer>,
+    timeout: Duration,
+    /// Services of the current configuration, to split the limit of
+    services: Vec<ServiceConfig>,
+    /// Until when requests are rejected, as peers may have recorded requests
+    /// from the share of this node
+    holdoff: Option<Instant>,
+    /// Peers that sent a heartbeat within the timeout at the last update, to
+    /// log the changes
+    alive: HashSet<SocketAddr>,
+    /// Peers whose heartbeats were ignored, to only log it once
+    mismatched: HashSet<SocketAddr>,
+    /// Whether there was a quorum and no holdoff at the last update, to log
+    /// the changes
+    status: Option<(bool, bool)>,
+}
+
+impl Membership {
+
+    fn new(address: SocketAddr, peers: &[SocketAddr], timeout: Duration, now: Instant) -> Self {
+        Membership {
+            address,
+            started: now,
+            peers: peers.iter().map(|peer| (*peer, Peer::default())).collect(),
+            timeout,
+            services: Vec::new(),
+            holdoff: None,
+            alive: HashSet::new(),
+            mismatched: HashSet::new(),
+            status: None,
+        }
+    }
+
+    /// Nodes in the cluster, this one included.
+    fn size(&self) -> usize {
+        self.peers.len() + 1
+    }
+
+    /// Heartbeat sent to `peer`, holding the size of the cluster and the
+    /// milliseconds the peer has to wait before recording requests.
+    fn heartbeat(&self, peer: SocketAddr, now: Instant) -> String {
+        let excluded_until = self.peers.get(&peer).and_then(|peer| peer.excluded_until);
+        let holdoff = excluded_until.map_or(Duration::ZERO, |until| until.saturating_duration_since(now));
+        format!("JARL/1 HEARTBEAT {} {}", self.size(), holdoff.as_millis())
+    }
+
+    /// Records a heartbeat received from `from`. Returns `false` if it was
+    /// ignored, as it does not come from a configured peer, is invalid, or
+    /// was sent by a node configured with a different cluster size.
+    fn heard(&mut self, from: SocketAddr, heartbeat: &[u8], now: Instant) -> bool {
+        if !self.peers.contains_key(&from) {
+            return false;
+        }
+        let heartbeat = std::str::from_utf8(heartbeat).unwrap_or_default();
+        let words: Vec<&str> = heartbeat.split_whitespace().collect();
+        let ["JARL/1", "HEARTBEAT", size, holdoff] = words.as_slice() else {
+            return false;
+        };
+        let (Ok(size), Ok(holdoff)) = (size.parse::<usize>(), holdoff.parse::<u64>()) else {
+            return false;
+        };
+        if size != self.size() {
+            if self.mismatched.insert(from) {
+                tracing::warn!(peer = %from, size, expected = self.size(), "ignoring heartbeats of a peer configured with a different cluster size");
+            }
+            return false;
+        }
+
+        let until = now + Duration::from_millis(holdoff);
+        self.holdoff = self.holdoff.max(Some(until));
+        if let Some(peer) = self.peers.get_mut(&from) {
+            peer.heard = Some(now);
+        }
+        true
+    }
+
+    /// Addresses of the nodes that sent a heartbeat within `hold`, this one
+    /// included, sorted.
+    fn members(&self, hold: Duration, now: Instant) -> Vec<SocketAddr> {
+        let mut members: Vec<SocketAddr> = self.peers.iter()
+            .filter(|(_address, peer)| self.within(peer, hold, now))
+            .map(|(address, _peer)| *address)
+            .chain(std::iter::once(self.address))
+            .collect();
+        members.sort();
+        members
+    }
+
+    /// Whether `peer` sent a heartbeat within `hold`, or has not been heard
+    /// from yet and this node started within `hold`.
+    fn within(&self, peer: &Peer, hold: Duration, now: Instant) -> bool {
+        now.duration_since(peer.heard.unwrap_or(self.started)) < hold
+    }
+
+    /// Whether this node heard from a majority of the cluster, itself
+    /// included, within the timeout.
+    fn has_quorum(&self, now: Instant) -> bool {
+        let heard = self.peers.values()
+            .filter(|peer| peer.heard.is_some_and(|heard| now.duration_since(heard) < self.timeout))
+            .count();
+        (heard + 1) * 2 > self.size()
+    }
+
+    /// Share of the limit of `service` enforced by this node. The remainder
+    /// of the split goes to the members with the lowest addresses, one
+    /// request each, so that the shares add up to the limit.
+    fn share(&self, service: &ServiceConfig, now: Instant) -> u32 {
+        let members = self.members(self.hold(service), now);
+        let rank = members.iter().position(|address| *address == self.address).unwrap_or_default() as u32;
+        let count = members.len() as u32;
+        service.requests / count + u32::from(rank < service.requests % count)
+    }
+
+    /// How long a peer is counted in the split of `service` after its last
+    /// heartbeat.
+    fn hold(&self, service: &ServiceConfig) -> Duration {
+        self.timeout + Duration::from_secs(service.period as u64)
+    }
+
+    /// Applies the share of every service, and whether requests can be
+    /// recorded, to `registry`.
+    fn update(&mut self, registry: &Registry, now: Instant) {
+        let alive: HashSet<SocketAddr> = self.peers.iter()
+            .filter(|(_address, peer)| peer.heard.is_some_and(|heard| now.duration_since(heard) < self.timeout))
+            .map(|(address, _peer)| *address)
+            .collect();
+        for peer in alive.difference(&self.alive) {
+            tracing::info!(%peer, "peer up");
+        }
+        for peer in self.alive.difference(&alive) {
+            tracing::warn!(%peer, "peer down");
+        }
+        self.alive = alive;
+
+        // Requests recorded from the share of an excluded peer since the last
+        // update count until a period after it, if any could be recorded
+        let mut excluded = Vec::new();
+        if registry.is_available() {
+            for service in &self.services {
+                let hold = self.hold(service);
+                let until = now + Duration::from_secs(service.period as u64);
+                excluded.extend(self.peers.iter()
+                    .filter(|(_address, peer)| !self.within(peer, hold, now))
+                    .map(|(address, _peer)| (*address, until)));
+            }
+        }
+        for (address, until) in excluded {
+            if let Some(peer) = self.peers.get_mut(&address) {
+                peer.excluded_until = peer.excluded_until.max(Some(until));
+            }
+        }
+
+        for service in &self.services {
+            let Some(keeper) = registry.get(&service.name) else {
+                continue;
+            };
+            let share = self.share(service, now);
+            let mut keeper = keeper.lock();
+            if keeper.limit() != share {
+                tracing::info!(service = service.name, requests = share, period = service.period, "limit split");
+                if let Err(error) = keeper.reconfigure(share, service.period) {
+                    error::report(&error);
+                }
+            }
+        }
+
+        let quorum = self.has_quorum(now);
+        let held_off = self.holdoff.filter(|holdoff| now < *holdoff);
+        let status = (quorum, held_off.is_none());
+        if self.status != Some(status) {
+            let (members, size) = (self.alive.len() + 1, self.size());
+            match (status, held_off) {
+                ((true, true), _) => tracing::info!(members, size, "quorum reached, recording requests"),
+                ((true, false), Some(until)) => {
+                    let seconds = until.duration_since(now).as_secs_f32();
+                    tracing::info!(members, size, seconds, "quorum reached, waiting for the requests of the peers to expire");
+                }
+                _ => tracing::warn!(members, size, "no quorum, rejecting requests"),
+            }
+            self.status = Some(status);
+        }
+        registry.set_available(quorum && held_off.is_none());
+    }
+
+}
+
+
+/// How this node shares the limits, depending on the mode of the cluster.
+enum Node {
+    Quorum(Arc<Mutex<Membership>>),
+    Lease(Arc<Mutex<Leases>>),
+}
+
+
+/// Node of a cluster sharing the limit of every service with its peers.
+pub struct Cluster {
+    node: Node,
+    registry: Arc<Registry>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Cluster {
+
+    /// Binds the cluster address of a validated configuration and starts
+    /// exchanging heartbeats or leases with the peers. Requests are rejected
+    /// until a majority of the cluster is heard from, or until this node is
+    /// granted a share of every limit.
+    pub async fn start(config: &Config, registry: Arc<Registry>) -> std::io::Result<Self> {
+        let cluster = &config.cluster;
+        let address = cluster.address.expect("a cluster is only started with an address");
+        registry.set_available(false);
+
+        let (node, tasks) = match cluster.mode {
+            ClusterMode::Quorum => {
+                let socket = UdpSocket::bind(address).await?;
+                let membership = Arc::new(Mutex::new(Membership::new(address, &cluster.peers, cluster.timeout(), Instant::now())));
+                let task = tokio::spawn(gossip(socket, membership.clone(), registry.clone(), cluster.interval()));
+                (Node::Quorum(membership), vec![task])
+            }
+            ClusterMode::Lease => {
+                let listener = TcpListener::bind(address).await?;
+                let leases = Arc::new(Mutex::new(Leases::new(address, &cluster.peers, cluster.timeout(), Instant::now())));
+                let rounds = Arc::new(tokio::sync::Notify::new());
+                let tasks = vec![
+                    tokio::spawn(lease::rebalance(leases.clone(), registry.clone(), cluster.interval(), rounds.clone())),
+                    tokio::spawn(lease::serve(listener, leases.clone(), rounds)),
+                ];
+                (Node::Lease(leases), tasks)
+            }
+        };
+        tracing::info!(%address, peers = cluster.peers.len(), mode = ?cluster.mode, listener = "cluster", "listening");
+
+        let cluster = Cluster { node, registry, tasks };
+        cluster.reload(config);
+        Ok(cluster)
+    }
+
+    /// Applies the services of a new validated configuration, each one
+    /// limited to its share of the limit. Changes to the peers are ignored.
+    pub fn reload(&self, config: &Config) {
+        let now = Instant::now();
+        match &self.node {
+            Node::Quorum(membership) => {
+                let mut membership = lock(membership);
+                self.registry.reload_with(config, |service| membership.share(service, now));
+                membership.services = config.services.clone();
+                membership.update(&self.registry, now);
+            }
+            Node::Lease(leases) => {
+                let mut leases = lock(leases);
+                self.registry.reload_with(config, |service| leases.limit(service));
+                leases.services = config.services.clone();
+                leases.update(&self.registry, now);
+            }
+        }
+    }
+
+}
+
+impl Drop for Cluster {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+fn lock<T>(state: &Mutex<T>) -> MutexGuard<'_, T> {
+    state.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Sends a heartbeat to every peer each `interval`, and updates the shares
+/// of the services whenever a heartbeat is received. Peers coming up are
+/// replied to right away, so that they reach a quorum without waiting for
+/// the next heartbeat.
+async fn gossip(socket: UdpSocket, membership: Arc<Mutex<Membership>>, registry: Arc<Registry>, interval: Duration) {
+    let mut ticks = tokio::time::interval(interval);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut buffer = [0; MAX_HEARTBEAT_LENGTH];
+
+    loop {
+        tokio::select! {
+            _ = ticks.tick() => {
+                let heartbeats: Vec<(SocketAddr, String)> = {
+                    let mut membership = lock(&membership);
+                    let now = Instant::now();
+                    membership.update(&registry, now);
+                    membership.peers.keys().map(|peer| (*peer, membership.heartbeat(*peer, now))).collect()
+                };
+                for (peer, heartbeat) in heartbeats {
+                    // Peers that are down are noticed by their missing heartbeats
+                    if let Err(error) = socket.send_to(heartbeat.as_bytes(), peer).await {
+                        tracing::debug!(%peer, %error, "could not send heartbeat");
+                    }
+                }
+            }
+            received = socket.recv_from(&mut buffer) => match received {
+                Ok((length, from)) => {
+                    let reply = {
+                        let mut membership = lock(&membership);
+                        let now = Instant::now();
+                        let coming_up = !membership.alive.contains(&from);
+                        let heard = membership.heard(from, &buffer[..length], now);
+                        if heard {
+                            membership.update(&registry, now);
+                        }
+                        (heard && coming_up).then(|| membership.heartbeat(from, now))
+                    };
+                    if let Some(heartbeat) = reply {
+                        let _ = socket.send_to(heartbeat.as_bytes(), from).await;
+                    }
+                }
+                Err(error) => error::report(&crate::Error::Connection(error)),
+            },
+        }
+    }
+}
+
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use crate::{Config, Registry};
+    use crate::cluster::{Cluster, Membership};
+    use crate::config::ServiceConfig;
+
+    fn address() -> SocketAddr {
+        "10.0.0.1:7946".parse().unwrap()
+    }
+
+    fn peers() -> Vec<SocketAddr> {
+        vec!["10.0.0.2:7946".parse().unwrap(), "10.0.0.3:7946".parse().unwrap()]
+    }
+
+    #[test]
+    /// Only heartbeats of configured peers with the same cluster size count.
+    fn heartbeats() {
+        let peers = peers();
+        let now = Instant::now();
+        let mut membership = Membership::new(address(), &peers, Duration::from_secs(5), now);
+
+        assert!(!membership.heard("10.0.0.4:7946".parse().unwrap(), b"JARL/1 HEARTBEAT 3 0", now));
+        assert!(!membership.heard(peers[0], b"JARL/1 HEARTBEAT 2 0", now));
+        assert!(!membership.heard(peers[0], b"JARL/1 HEARTBEAT 3", now));
+        assert!(!membership.has_quorum(now));
+
+        assert!(membership.heard(peers[0], membership.heartbeat(peers[1], now).as_bytes(), now));
+        assert!(membership.has_quorum(now));
+        assert!(!membership.has_quorum(now + Duration::from_secs(5)), "Quorum should be lost after the timeout.");
+    }
+
+    #[test]
+    /// The share of a peer that went down is taken over once its requests
+    /// are older than the period. Peers not heard from yet keep their share,
+    /// and the remainder goes to the lowest addresses.
+    fn split_limit() {
+        let peers = peers();
+        let now = Instant::now();
+        let mut membership = Membership::new(address(), &peers, Duration::from_secs(5), now);
+        let service = ServiceConfig::new("api", 100, 60);
+
+        assert_eq!(membership.share(&service, now), 34);
+        membership.heard(peers[0], b"JARL/1 HEARTBEAT 3 0", now);
+        membership.heard(peers[1], b"JARL/1 HEARTBEAT 3 0", now);
+        assert_eq!(membership.share(&service, now), 34);
+        let highest = Membership::new(peers[1], &[address(), peers[0]], Duration::from_secs(5), now);
+        assert_eq!(highest.share(&service, now), 33);
+
+        let later = now + Duration::from_secs(30);
+        membership.heard(peers[0], b"JARL/1 HEARTBEAT 3 0", later);
+        assert!(membership.has_quorum(later));
+        assert_eq!(membership.share(&service, later), 34, "A peer down for less than timeout and period keeps its share.");
+        assert_eq!(membership.share(&service, now + Duration::from_secs(65)), 50);
+    }
+
+    #[test]
+    /// A peer coming back after its share was taken over waits for the
+    /// requests recorded from it to expire.
+    fn holdoff() {
+        let peers = peers();
+        let now = Instant::now();
+        let registry = Registry::new();
+        let mut membership = Membership::new(address(), &peers, Duration::from_secs(5), now);
+        membership.services.push(ServiceConfig::new("api", 100, 60));
+
+        let later = now + Duration::from_secs(65);
+        membership.heard(peers[0], b"JARL/1 HEARTBEAT 3 0", later);
+        membership.update(&registry, later);
+        assert_eq!(membership.heartbeat(peers[0], later), "JARL/1 HEARTBEAT 3 0");
+        assert_eq!(membership.heartbeat(peers[1], later), "JARL/1 HEARTBEAT 3 60000");
+
+        let mut returning = Membership::new(peers[1], &[address(), peers[0]], Duration::from_secs(5), now);
+        returning.heard(peers[0], membership.heartbeat(peers[1], later).as_bytes(), later);
+        returning.update(&registry, later);
+        assert!(!registry.is_available(), "Requests should wait for the peers' requests to expire.");
+        let expired = later + Duration::from_secs(60);
+        returning.heard(peers[0], b"JARL/1 HEARTBEAT 3 0", expired);
+        returning.update(&registry, expired);
+        assert!(registry.is_available());
+    }
+
+    #[tokio::test]
+    /// Nodes reject requests until they hear from a majority of the cluster,
+    /// and then enforce their share of the limit, which add up to it.
+    async fn trio() {
+        let addresses: Vec<SocketAddr> = (0..3).map(|_| {
+            let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+            socket.local_addr().unwrap()
+        }).collect();
+
+        let mut nodes = Vec::new();
+        for address in &addresses {
+            let mut config = Config::default();
+            config.services.push(ServiceConfig::new("api", 10, 60));
+            config.cluster.address = Some(*address);
+            config.cluster.peers = addresses.iter().copied().filter(|peer| peer != address).collect();
+
+            let registry = Arc::new(Registry::from_config(&config));
+            let cluster = Cluster::start(&config, registry.clone()).await.unwrap();
+            nodes.push((registry, cluster));
+        }
+        assert!(nodes[0].0.get_available("api").is_err(), "Requests should be rejected without a quorum.");
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let mut limits: Vec<u32> = nodes.iter()
+            .map(|(registry, _cluster)| registry.get_available("api").unwrap().read().limit())
+            .collect();
+        limits.sort();
+        assert_eq!(limits, [3, 3, 4]);
+    }
+}
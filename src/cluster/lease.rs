@@ -0,0 +1,693 @@
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Notify;
+use tokio::task::JoinSet;
+use tokio::time::MissedTickBehavior;
+
+use crate::{error, server, Registry};
+use crate::cluster::lock;
+use crate::config::ServiceConfig;
+
+
+/// Longest lease exchange read from a peer.
+const MAX_EXCHANGE_LENGTH: u64 = 64 * 1024;
+
+
+/// Lease of a peer on a service, as last reported by the peer.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Report {
+    /// Requests recorded per interval
+    demand: f64,
+    /// Requests the peer may have recorded within the last period
+    held: u32,
+}
+
+
+/// Line of an exchange: the lease of a node on a service, with the share it
+/// claims in a request, or the share granted to the peer in a reply.
+struct Line {
+    service: String,
+    report: Report,
+    share: u32,
+}
+
+
+/// What this node knows of one of its peers.
+#[derive(Clone, Debug, Default)]
+struct Peer {
+    /// Last time a lease exchange with the peer succeeded, renewing its
+    /// leases
+    heard: Option<Instant>,
+    /// Leases of the peer, by service
+    reports: HashMap<String, Report>,
+}
+
+
+/// Share of the limit of a service leased by this node.
+#[derive(Clone, Copy, Debug, Default)]
+struct Share {
+    /// Requests per period enforced by this node, 0 until one is granted
+    requests: u32,
+    /// Larger share asked to the peers during the current round, if any
+    claim: Option<u32>,
+    /// Share enforced before the last shrink, and until when its requests
+    /// may still be recorded
+    released: Option<(u32, Instant)>,
+    /// Requests recorded per interval, averaged over the last rounds
+    demand: f64,
+    /// Requests recorded by the service at the last round
+    acquisitions: u64,
+}
+
+impl Share {
+
+    /// Requests this node may have recorded within the last period.
+    fn held(&self, now: Instant) -> u32 {
+        let released = self.released.filter(|(_requests, until)| now < *until).map_or(0, |(requests, _until)| requests);
+        self.requests.max(released)
+    }
+
+}
+
+
+/// Shares of the limit of every service leased by this node, rebalanced by
+/// demand with its peers.
+///
+/// Every `interval`, this node sends its leases to every peer over TCP and
+/// gets theirs in reply, renewing them until `timeout`. Shares that exceed
+/// the demand of this node are shrunk right away, while larger shares are
+/// claimed from the room left by the peers, and only enforced once every
+/// peer whose lease counts, and a majority of the cluster, granted them. A
+/// peer granting a claim counts it from then on, so that the peers cannot
+/// grow into the same room, and concurrent claims that do not fit together
+/// are settled in favour of the node with the lowest address.
+///
+/// Leases still count for a period after they expire, as the requests
+/// recorded before do, after which the survivors can reclaim the share. A
+/// node cut off from its peers keeps enforcing its share, but cannot grow
+/// it, unless the cluster is a pair.
+pub(super) struct Leases {
+    address: SocketAddr,
+    started: Instant,
+    peers: HashMap<SocketAddr, Peer>,
+    timeout: Duration,
+    /// Services of the current configuration, to share the limit of
+    pub(super) services: Vec<ServiceConfig>,
+    shares: HashMap<String, Share>,
+    /// Peers whose lease was renewed within the timeout at the last update,
+    /// to log the changes
+    alive: HashSet<SocketAddr>,
+    /// Peers whose exchanges were ignored, to only log it once
+    mismatched: HashSet<SocketAddr>,
+    /// Whether every service had a share at the last update, to log the
+    /// changes
+    available: Option<bool>,
+}
+
+impl Leases {
+
+    pub(super) fn new(address: SocketAddr, peers: &[SocketAddr], timeout: Duration, now: Instant) -> Self {
+        Leases {
+            address,
+            started: now,
+            peers: peers.iter().map(|peer| (*peer, Peer::default())).collect(),
+            timeout,
+            services: Vec::new(),
+            shares: HashMap::new(),
+            alive: HashSet::new(),
+            mismatched: HashSet::new(),
+            available: None,
+        }
+    }
+
+    /// Nodes in the cluster, this one included.
+    fn size(&self) -> usize {
+        self.peers.len() + 1
+    }
+
+    /// Limit of the rate limiter of `service`, which cannot be 0 while this
+    /// node waits for a share.
+    pub(super) fn limit(&self, service: &ServiceConfig) -> u32 {
+        self.shares.get(&service.name).map_or(0, |share| share.requests).max(1)
+    }
+
+    /// Whether the lease of `peer` on `service` still counts: it was renewed
+    /// within the timeout plus the period of the service, or the peer has not
+    /// been heard from yet and this node started within that time.
+    fn counts(&self, peer: &Peer, service: &ServiceConfig, now: Instant) -> bool {
+        let hold = self.timeout + Duration::from_secs(service.period as u64);
+        now.duration_since(peer.heard.unwrap_or(self.started)) < hold
+    }
+
+    /// Requests of `service` that `peer` may have recorded within the last
+    /// period. Peers not heard from yet are assumed to hold an even share.
+    fn held_by(&self, peer: &Peer, service: &ServiceConfig) -> u32 {
+        match (peer.reports.get(&service.name), peer.heard) {
+            (Some(report), _) => report.held,
+            (None, None) => service.requests / self.size() as u32,
+            (None, Some(_)) => 0,
+        }
+    }
+
+    /// Requests of `service` held by the peers whose lease counts, except
+    /// `except`.
+    fn others(&self, service: &ServiceConfig, except: Option<SocketAddr>, now: Instant) -> u32 {
+        self.peers.iter()
+            .filter(|(address, peer)| Some(**address) != except && self.counts(peer, service, now))
+            .fold(0, |total: u32, (_address, peer)| total.saturating_add(self.held_by(peer, service)))
+    }
+
+    /// Share of `service` this node aims for, from the demand of every node
+    /// whose lease counts.
+    fn target(&self, service: &ServiceConfig, now: Instant) -> u32 {
+        let own = self.shares.get(&service.name).map_or(0.0, |share| share.demand);
+        let demands: Vec<f64> = std::iter::once(own)
+            .chain(self.peers.values()
+                .filter(|peer| self.counts(peer, service, now))
+                .map(|peer| peer.reports.get(&service.name).map_or(0.0, |report| report.demand)))
+            .collect();
+        split(service.requests, &demands)[0]
+    }
+
+    /// Measures the demand of every service, shrinks the shares that exceed
+    /// it and claims larger ones. Returns the exchange to send to the peers.
+    fn round(&mut self, registry: &Registry, now: Instant) -> String {
+        for service in self.services.clone() {
+            let acquisitions = registry.get(&service.name).map_or(0, |keeper| keeper.metrics().acquisitions());
+            let share = self.shares.entry(service.name.clone()).or_default();
+            let recorded = acquisitions.saturating_sub(share.acquisitions);
+            share.acquisitions = acquisitions;
+            share.demand = (share.demand + recorded as f64) / 2.0;
+
+            let target = self.target(&service, now);
+            let room = service.requests.saturating_sub(self.others(&service, None, now));
+            let share = self.shares.entry(service.name.clone()).or_default();
+            if share.requests > target {
+                share.released = Some((share.held(now), now + Duration::from_secs(service.period as u64)));
+                share.requests = target;
+            }
+            share.claim = Some(target.min(room)).filter(|claim| *claim > share.requests);
+        }
+        self.update(registry, now);
+
+        self.exchange(|_name, share| (share.held(now), share.claim.unwrap_or(share.requests)))
+    }
+
+    /// Exchange holding the lease of this node on every service, as its
+    /// demand followed by `line(service, share)`: the requests it holds and
+    /// the share it claims in a request, or the requests it holds or claims
+    /// and the share granted to the peer in a reply.
+    fn exchange(&self, line: impl Fn(&str, &Share) -> (u32, u32)) -> String {
+        let mut exchange = format!("JARL/1 LEASE {} {}\n", self.address, self.size());
+        for service in &self.services {
+            let share = self.shares.get(&service.name).copied().unwrap_or_default();
+            let (held, last) = line(&service.name, &share);
+            exchange.push_str(&format!("{} {} {} {}\n", service.name, share.demand, held, last));
+        }
+        exchange.push('\n');
+        exchange
+    }
+
+    /// Parses an exchange sent by a peer with the same cluster size, into
+    /// its address and its leases, each with the share claimed or granted.
+    fn parse(&mut self, exchange: &str) -> Option<(SocketAddr, Vec<Line>)> {
+        let mut lines = exchange.lines();
+        let words: Vec<&str> = lines.next()?.split_whitespace().collect();
+        let ["JARL/1", "LEASE", from, size] = words.as_slice() else {
+            return None;
+        };
+        let (Ok(from), Ok(size)) = (from.parse::<SocketAddr>(), size.parse::<usize>()) else {
+            return None;
+        };
+        if !self.peers.contains_key(&from) {
+            return None;
+        }
+        if size != self.size() {
+            if self.mismatched.insert(from) {
+                tracing::warn!(peer = %from, size, expected = self.size(), "ignoring leases of a peer configured with a different cluster size");
+            }
+            return None;
+        }
+
+        let mut leases = Vec::new();
+        for line in lines.take_while(|line| !line.is_empty()) {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let [name, demand, held, share] = words.as_slice() else {
+                return None;
+            };
+            let demand = demand.parse::<f64>().ok().filter(|demand| demand.is_finite() && *demand >= 0.0)?;
+            let (Ok(held), Ok(share)) = (held.parse::<u32>(), share.parse::<u32>()) else {
+                return None;
+            };
+            leases.push(Line { service: name.to_string(), report: Report { demand, held }, share });
+        }
+        Some((from, leases))
+    }
+
+    /// Answers the request of a peer, granting the shares it claims that fit
+    /// in the room left by the other nodes. Returns the reply, holding the
+    /// leases of this node, and whether the peer just came up. Returns
+    /// `None` if the request was ignored, such as when it was not sent from
+    /// the IP of the peer it names.
+    fn grant(&mut self, request: &str, sender: IpAddr, now: Instant) -> Option<(String, bool)> {
+        let (from, leases) = self.parse(request)?;
+        // Peers connect from any port, so only their IP is checked
+        if from.ip().to_canonical() != sender.to_canonical() {
+            tracing::warn!(peer = %from, %sender, "ignoring leases sent on behalf of a peer");
+            return None;
+        }
+        let coming_up = !self.alive.contains(&from);
+
+        let mut granted = HashMap::new();
+        for Line { service: name, report, share: claim } in leases {
+            let Some(service) = self.services.iter().find(|service| service.name == name).cloned() else {
+                continue;
+            };
+            let others = self.others(&service, Some(from), now).saturating_add(claim);
+            let share = self.shares.get(&name).copied().unwrap_or_default();
+            let fits = |own: u32| others.saturating_add(own) <= service.requests;
+
+            let share = if claim <= report.held || fits(share.held(now).max(share.claim.unwrap_or(0))) {
+                claim
+            } else if from < self.address && fits(share.held(now)) {
+                // The claim of this node does not fit with the one of a peer
+                // with a lower address, so it is dropped
+                if let Some(share) = self.shares.get_mut(&name) {
+                    share.claim = None;
+                }
+                claim
+            } else {
+                report.held
+            };
+            granted.insert(name.clone(), share);
+
+            if let Some(peer) = self.peers.get_mut(&from) {
+                peer.reports.insert(name, Report { held: report.held.max(share), ..report });
+            }
+        }
+        if let Some(peer) = self.peers.get_mut(&from) {
+            peer.heard = Some(now);
+        }
+
+        let reply = self.exchange(|name, share| {
+            let held = share.held(now).max(share.claim.unwrap_or(0));
+            (held, granted.get(name).copied().unwrap_or(0))
+        });
+        Some((reply, coming_up))
+    }
+
+    /// Records the replies of the peers to the last round, and enforces the
+    /// claimed shares that were granted. Returns whether a peer was heard
+    /// from for the first time since it came up.
+    fn settle(&mut self, replies: Vec<(SocketAddr, String)>, registry: &Registry, now: Instant) -> bool {
+        let mut granters: HashMap<String, HashSet<SocketAddr>> = HashMap::new();
+        let mut coming_up = false;
+        for (address, reply) in replies {
+            let Some((from, leases)) = self.parse(&reply).filter(|(from, _leases)| *from == address) else {
+                continue;
+            };
+            coming_up |= !self.alive.contains(&from);
+            for Line { service: name, report, share } in leases {
+                let claim = self.shares.get(&name).and_then(|share| share.claim);
+                if claim.is_some_and(|claim| share >= claim) {
+                    granters.entry(name.clone()).or_default().insert(from);
+                }
+                if let Some(peer) = self.peers.get_mut(&from) {
+                    peer.reports.insert(name, report);
+                }
+            }
+            if let Some(peer) = self.peers.get_mut(&from) {
+                peer.heard = Some(now);
+            }
+        }
+
+        for service in &self.services {
+            let required: Vec<SocketAddr> = self.peers.iter()
+                .filter(|(_address, peer)| peer.heard.is_some() && self.counts(peer, service, now))
+                .map(|(address, _peer)| *address)
+                .collect();
+            let granters = granters.remove(&service.name).unwrap_or_default();
+            // Both nodes of a pair are needed for a majority, so the survivor
+            // reclaims the share of its peer once the lease expired
+            let majority = (granters.len() + 1) * 2 > self.size() || (self.size() == 2 && required.is_empty());
+            let granted = required.iter().all(|peer| granters.contains(peer)) && majority;
+
+            if let Some(share) = self.shares.get_mut(&service.name) {
+                if let Some(claim) = share.claim.take().filter(|_claim| granted) {
+                    share.requests = claim;
+                }
+            }
+        }
+        self.update(registry, now);
+        coming_up
+    }
+
+    /// Applies the share of every service, and whether requests can be
+    /// recorded, to `registry`.
+    pub(super) fn update(&mut self, registry: &Registry, now: Instant) {
+        let alive: HashSet<SocketAddr> = self.peers.iter()
+            .filter(|(_address, peer)| peer.heard.is_some_and(|heard| now.duration_since(heard) < self.timeout))
+            .map(|(address, _peer)| *address)
+            .collect();
+        for peer in alive.difference(&self.alive) {
+            tracing::info!(%peer, "peer up");
+        }
+        for peer in self.alive.difference(&alive) {
+            tracing::warn!(%peer, "peer down, its lease expired");
+        }
+        self.alive = alive;
+
+        self.shares.retain(|name, _share| self.services.iter().any(|service| &service.name == name));
+        for service in &self.services {
+            let Some(keeper) = registry.get(&service.name) else {
+                continue;
+            };
+            let limit = self.limit(service);
+            let mut keeper = keeper.lock();
+            // Shares follow the demand, so they change too often to be logged
+            // as information
+            if keeper.limit() != limit {
+                tracing::debug!(service = service.name, requests = limit, period = service.period, "limit split");
+                if let Err(error) = keeper.reconfigure(limit, service.period) {
+                    error::report(&error);
+                }
+            }
+        }
+
+        let available = self.services.iter().all(|service| self.shares.get(&service.name).is_some_and(|share| share.requests > 0));
+        if self.available != Some(available) {
+            match available {
+                true => tracing::info!("leased a share of every limit, recording requests"),
+                false => tracing::warn!("waiting for the peers to grant a share of every limit"),
+            }
+            self.available = Some(available);
+        }
+        registry.set_available(available);
+    }
+
+}
+
+
+/// Splits `limit` between nodes with the given demands: every node is
+/// guaranteed half of an even share, at least 1, and the rest is split in
+/// proportion to the demands, or evenly without any demand.
+fn split(limit: u32, demands: &[f64]) -> Vec<u32> {
+    let nodes = demands.len() as u32;
+    let floor = (limit / nodes / 2).max(1);
+    let rest = limit.saturating_sub(floor * nodes);
+    let total: f64 = demands.iter().sum();
+
+    demands.iter()
+        .map(|demand| match total > 0.0 {
+            true => floor + (rest as f64 * demand / total) as u32,
+            false => floor + rest / nodes,
+        })
+        .collect()
+}
+
+
+/// Rebalances the leases with the peers every `interval`, or right away
+/// when `rounds` is notified of a peer coming up.
+pub(super) async fn rebalance(leases: Arc<Mutex<Leases>>, registry: Arc<Registry>, interval: Duration, rounds: Arc<Notify>) {
+    let mut ticks = tokio::time::interval(interval);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            _ = ticks.tick() => {}
+            _ = rounds.notified() => {}
+        }
+
+        let (request, peers) = {
+            let mut leases = lock(&leases);
+            let request = leases.round(&registry, Instant::now());
+            (request, leases.peers.keys().copied().collect::<Vec<_>>())
+        };
+
+        let mut exchanges = JoinSet::new();
+        for peer in peers {
+            let request = request.clone();
+            exchanges.spawn(async move {
+                let reply = tokio::time::timeout(interval, exchange(peer, &request)).await;
+                (peer, reply)
+            });
+        }
+        let mut replies = Vec::new();
+        while let Some(Ok((peer, reply))) = exchanges.join_next().await {
+            // Peers that are down are noticed by their expiring leases
+            match reply {
+                Ok(Ok(reply)) => replies.push((peer, reply)),
+                Ok(Err(error)) => tracing::debug!(%peer, %error, "could not exchange leases"),
+                Err(_elapsed) => tracing::debug!(%peer, "timed out exchanging leases"),
+            }
+        }
+
+        if lock(&leases).settle(replies, &registry, Instant::now()) {
+            rounds.notify_one();
+        }
+    }
+}
+
+/// Sends the leases of this node to `peer` and reads the peer's reply.
+async fn exchange(peer: SocketAddr, request: &str) -> std::io::Result<String> {
+    let mut stream = TcpStream::connect(peer).await?;
+    stream.write_all(request.as_bytes()).await?;
+    read_exchange(&mut stream).await
+}
+
+/// Answers the lease requests of the peers, notifying `rounds` when a peer
+/// comes up, so that its share is rebalanced right away.
+pub(super) async fn serve(listener: TcpListener, leases: Arc<Mutex<Leases>>, rounds: Arc<Notify>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, address)) => {
+                tokio::spawn(answer(stream, address.ip(), leases.clone(), rounds.clone()));
+            }
+            Err(error) => server::accept_failed(error).await,
+        }
+    }
+}
+
+async fn answer(mut stream: TcpStream, sender: IpAddr, leases: Arc<Mutex<Leases>>, rounds: Arc<Notify>) {
+    let timeout = lock(&leases).timeout;
+    let Ok(Ok(request)) = tokio::time::timeout(timeout, read_exchange(&mut stream)).await else {
+        return;
+    };
+    let Some((reply, coming_up)) = lock(&leases).grant(&request, sender, Instant::now()) else {
+        return;
+    };
+    if coming_up {
+        rounds.notify_one();
+    }
+    if let Err(error) = reply_exchange(&mut stream, &reply).await {
+        tracing::debug!(%error, "could not reply leases");
+    }
+}
+
+async fn reply_exchange<S: AsyncWrite + Unpin>(stream: &mut S, reply: &str) -> std::io::Result<()> {
+    stream.write_all(reply.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Reads an exchange up to its empty line.
+async fn read_exchange(stream: &mut TcpStream) -> std::io::Result<String> {
+    let mut reader = BufReader::new(stream.take(MAX_EXCHANGE_LENGTH));
+    let mut exchange = String::new();
+    loop {
+        let length = reader.read_line(&mut exchange).await?;
+        if length == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        if exchange.ends_with("\n\n") {
+            return Ok(exchange);
+        }
+    }
+}
+
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use crate::{Config, Registry};
+    use crate::cluster::{Cluster, ClusterMode};
+    use crate::cluster::lease::{split, Leases};
+    use crate::config::ServiceConfig;
+
+    fn addresses() -> Vec<SocketAddr> {
+        vec!["10.0.0.1:7946".parse().unwrap(), "10.0.0.2:7946".parse().unwrap(), "10.0.0.3:7946".parse().unwrap()]
+    }
+
+    /// Leases of the node at `addresses()[index]`, with every peer heard from
+    /// and holding `held` requests of a service limited to 100 per minute.
+    fn leases(index: usize, held: u32, now: Instant) -> Leases {
+        let addresses = addresses();
+        let peers: Vec<SocketAddr> = addresses.iter().copied().filter(|address| *address != addresses[index]).collect();
+        let mut leases = Leases::new(addresses[index], &peers, Duration::from_secs(5), now);
+        leases.services.push(ServiceConfig::new("api", 100, 60));
+        for peer in peers {
+            let request = format!("JARL/1 LEASE {} 3\napi 0 {} {}\n\n", peer, held, held);
+            leases.grant(&request, peer.ip(), now).unwrap();
+        }
+        leases
+    }
+
+    #[test]
+    fn split_demand() {
+        assert_eq!(split(10, &[0.0, 0.0]), vec![5, 5]);
+        assert_eq!(split(100, &[30.0, 10.0]), vec![62, 37]);
+        assert_eq!(split(3, &[5.0, 0.0, 0.0]), vec![1, 1, 1]);
+    }
+
+    #[test]
+    /// Requests are ignored unless sent from the IP of the peer they name.
+    fn impersonation() {
+        let addresses = addresses();
+        let now = Instant::now();
+        let mut leases = leases(1, 20, now);
+
+        let request = format!("JARL/1 LEASE {} 3\napi 0 20 60\n\n", addresses[2]);
+        assert!(leases.grant(&request, addresses[0].ip(), now).is_none());
+        assert!(leases.grant(&request, addresses[2].ip(), now).is_some());
+    }
+
+    #[test]
+    /// Claims are granted if they fit in the room left by the other nodes,
+    /// and concurrent claims that do not fit together go to the lowest
+    /// address.
+    fn grants() {
+        let addresses = addresses();
+        let now = Instant::now();
+        let registry = Registry::new();
+        let mut leases = leases(1, 20, now);
+
+        let reply = leases.grant(&format!("JARL/1 LEASE {} 3\napi 0 20 60\n\n", addresses[2]), addresses[2].ip(), now).unwrap().0;
+        assert_eq!(reply, "JARL/1 LEASE 10.0.0.2:7946 3\napi 0 0 60\n\n");
+        let reply = leases.grant(&format!("JARL/1 LEASE {} 3\napi 0 20 50\n\n", addresses[0]), addresses[0].ip(), now).unwrap().0;
+        assert_eq!(reply, "JARL/1 LEASE 10.0.0.2:7946 3\napi 0 0 20\n\n", "The claim should not fit with the one granted.");
+
+        leases.grant(&format!("JARL/1 LEASE {} 3\napi 0 30 30\n\n", addresses[2]), addresses[2].ip(), now).unwrap();
+        leases.round(&registry, now);
+        assert_eq!(leases.shares["api"].claim, Some(33));
+        let reply = leases.grant(&format!("JARL/1 LEASE {} 3\napi 0 20 50\n\n", addresses[2]), addresses[2].ip(), now).unwrap().0;
+        assert!(reply.ends_with("api 0 33 20\n\n"), "The claim of a higher address should be rejected.");
+        let reply = leases.grant(&format!("JARL/1 LEASE {} 3\napi 0 20 50\n\n", addresses[0]), addresses[0].ip(), now).unwrap().0;
+        assert!(reply.ends_with("api 0 0 50\n\n"), "The claim of a lower address should be granted.");
+        assert_eq!(leases.shares["api"].claim, None);
+    }
+
+    #[test]
+    /// A claim is only enforced once granted by every peer whose lease
+    /// counts, and by a majority of the cluster.
+    fn settle_claims() {
+        let addresses = addresses();
+        let now = Instant::now();
+        let registry = Registry::new();
+        registry.insert("api", Box::new(crate::Keeper::try_new(1, 60).unwrap())).unwrap();
+        let mut leases = leases(0, 0, now);
+
+        leases.round(&registry, now);
+        assert_eq!(leases.shares["api"].claim, Some(33));
+        let replies = vec![(addresses[1], String::from("JARL/1 LEASE 10.0.0.2:7946 3\napi 0 0 33\n\n"))];
+        leases.settle(replies, &registry, now);
+        assert_eq!(leases.shares["api"].requests, 0, "Every peer whose lease counts should grant the claim.");
+        assert!(!registry.is_available());
+
+        leases.round(&registry, now);
+        let replies = addresses[1..].iter()
+            .map(|address| (*address, format!("JARL/1 LEASE {} 3\napi 0 0 33\n\n", address)))
+            .collect();
+        leases.settle(replies, &registry, now);
+        assert_eq!(leases.shares["api"].requests, 33);
+        assert_eq!(registry.get("api").unwrap().read().limit(), 33);
+        assert!(registry.is_available());
+    }
+
+    #[test]
+    /// The share of a peer whose lease expired is reclaimed once its
+    /// requests are older than the period, with the grant of a majority.
+    fn reclaim() {
+        let addresses = addresses();
+        let now = Instant::now();
+        let registry = Registry::new();
+        let mut leases = leases(0, 33, now);
+
+        let later = now + Duration::from_secs(66);
+        let reply = format!("JARL/1 LEASE {} 3\napi 0 33 33\n\n", addresses[1]);
+        leases.settle(vec![(addresses[1], reply)], &registry, later);
+        leases.round(&registry, later);
+        assert_eq!(leases.shares["api"].claim, Some(50));
+        let reply = format!("JARL/1 LEASE {} 3\napi 0 33 50\n\n", addresses[1]);
+        leases.settle(vec![(addresses[1], reply)], &registry, later);
+        assert_eq!(leases.shares["api"].requests, 50);
+
+        let alone = later + Duration::from_secs(66);
+        leases.round(&registry, alone);
+        assert_eq!(leases.shares["api"].claim, Some(100));
+        leases.settle(Vec::new(), &registry, alone);
+        assert_eq!(leases.shares["api"].requests, 50, "A node cut off from the cluster should keep its share.");
+    }
+
+    #[test]
+    /// The survivor of a pair reclaims the share of its peer once the lease
+    /// of the peer expired and its requests are older than the period.
+    fn reclaim_pair() {
+        let addresses = addresses();
+        let now = Instant::now();
+        let registry = Registry::new();
+        let mut leases = Leases::new(addresses[0], &addresses[1..2], Duration::from_secs(5), now);
+        leases.services.push(ServiceConfig::new("api", 100, 60));
+        leases.grant(&format!("JARL/1 LEASE {} 2\napi 0 50 50\n\n", addresses[1]), addresses[1].ip(), now).unwrap();
+        leases.round(&registry, now);
+        leases.settle(vec![(addresses[1], format!("JARL/1 LEASE {} 2\napi 0 50 50\n\n", addresses[1]))], &registry, now);
+        assert_eq!(leases.shares["api"].requests, 50);
+
+        let later = now + Duration::from_secs(30);
+        leases.round(&registry, later);
+        leases.settle(Vec::new(), &registry, later);
+        assert_eq!(leases.shares["api"].requests, 50, "The share of the peer should count for a period.");
+
+        let expired = now + Duration::from_secs(66);
+        leases.round(&registry, expired);
+        leases.settle(Vec::new(), &registry, expired);
+        assert_eq!(leases.shares["api"].requests, 100);
+    }
+
+    #[tokio::test]
+    /// Two nodes reject requests until they granted each other a share, and
+    /// then enforce half of the limit each.
+    async fn pair() {
+        let addresses: Vec<SocketAddr> = (0..2).map(|_| {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap()
+        }).collect();
+
+        let mut nodes = Vec::new();
+        for (address, peer) in [(addresses[0], addresses[1]), (addresses[1], addresses[0])] {
+            let mut config = Config::default();
+            config.services.push(ServiceConfig::new("api", 10, 60));
+            config.cluster.mode = ClusterMode::Lease;
+            config.cluster.address = Some(address);
+            config.cluster.peers = vec![peer];
+
+            let registry = Arc::new(Registry::from_config(&config));
+            let cluster = Cluster::start(&config, registry.clone()).await.unwrap();
+            nodes.push((registry, cluster));
+        }
+        assert!(nodes[0].0.get_available("api").is_err(), "Requests should be rejected without a share.");
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        for (registry, _cluster) in &nodes {
+            let keeper = registry.get_available("api").unwrap();
+            assert_eq!(keeper.read().limit(), 5);
+        }
+    }
+}
@@ -0,0 +1,864 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::{Algorithm, Cli};
+use crate::cluster::ClusterMode;
+use crate::protocol;
+
+
+/// Seconds to wait for accepted connections when shutting down, if not configured.
+pub const DEFAULT_SHUTDOWN_TIMEOUT: u64 = 10;
+
+/// Seconds between saves of the state file, if not configured.
+pub const DEFAULT_STATE_INTERVAL: u64 = 30;
+
+/// Seconds between heartbeats sent to the peers of a cluster, if not
+/// configured.
+pub const DEFAULT_CLUSTER_INTERVAL: u64 = 1;
+
+/// Seconds without a heartbeat after which a peer is considered down, if not
+/// configured.
+pub const DEFAULT_CLUSTER_TIMEOUT: u64 = 5;
+
+/// Prefix of the keys of the store, if not configured.
+pub const DEFAULT_STORE_PREFIX: &str = "jarl";
+
+/// Permissions of the Unix socket, if not configured: read and write for the
+/// owner and group.
+pub const DEFAULT_UNIX_SOCKET_MODE: u32 = 0o660;
+
+
+/// Listener settings shared by all services.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Network interface to bind to, normally 0.0.0.0
+    pub ip: Option<IpAddr>,
+
+    /// Port for clients sending the name of the service before reading the delay
+    pub named_port: Option<u16>,
+
+    /// Port for administrative commands, such as reloading the configuration
+    pub admin_port: Option<u16>,
+
+    /// Port for the HTTP/JSON API
+    pub http_port: Option<u16>,
+
+    /// Port for the gRPC API, only available when built with the `grpc` feature
+    pub grpc_port: Option<u16>,
+
+    /// Port for `GET /metrics`, in the Prometheus text format
+    pub metrics_port: Option<u16>,
+
+    /// UDP port for datagrams holding a request id and a command of the line
+    /// protocol. May be the same number as a TCP port
+    pub udp_port: Option<u16>,
+
+    /// Unix domain socket for clients of the line protocol, served like the
+    /// named port
+    pub unix_socket: Option<PathBuf>,
+
+    /// Permissions of the Unix socket, such as `0o660`, defaults to
+    /// `DEFAULT_UNIX_SOCKET_MODE`
+    pub unix_socket_mode: Option<u32>,
+
+    /// Seconds to wait for accepted connections to be handled when shutting
+    /// down, defaults to `DEFAULT_SHUTDOWN_TIMEOUT`
+    pub shutdown_timeout: Option<u64>,
+
+    /// Worker threads handling connections, defaults to 1. Only the gcra
+    /// algorithm handles the requests for a single service on several
+    /// threads at once. Only read on startup
+    pub threads: Option<usize>,
+}
+
+impl ServerConfig {
+
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_timeout.unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT))
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads.unwrap_or(1)
+    }
+
+    pub fn unix_socket_mode(&self) -> u32 {
+        self.unix_socket_mode.unwrap_or(DEFAULT_UNIX_SOCKET_MODE)
+    }
+
+}
+
+
+/// Where and how often the requests recorded by every service are saved.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct StateConfig {
+    /// File to save the state to, and to restore it from on startup
+    pub path: Option<PathBuf>,
+
+    /// Seconds between saves, defaults to `DEFAULT_STATE_INTERVAL`. The state
+    /// is also saved when shutting down
+    pub interval: Option<u64>,
+}
+
+impl StateConfig {
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval.unwrap_or(DEFAULT_STATE_INTERVAL))
+    }
+
+}
+
+
+/// Peers sharing the limit of every service, each node enforcing its share of
+/// it. Only read on startup.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ClusterConfig {
+    /// How the limit is shared, defaults to `quorum`, which needs at least 3
+    /// nodes
+    #[serde(default)]
+    pub mode: ClusterMode,
+
+    /// Address to exchange heartbeats, over UDP, or leases, over TCP, with
+    /// the peers on
+    pub address: Option<SocketAddr>,
+
+    /// Addresses of the other nodes of the cluster
+    #[serde(default)]
+    pub peers: Vec<SocketAddr>,
+
+    /// Seconds between heartbeats, defaults to `DEFAULT_CLUSTER_INTERVAL`
+    pub interval: Option<u64>,
+
+    /// Seconds without a heartbeat or lease exchange after which a peer is
+    /// considered down, defaults to `DEFAULT_CLUSTER_TIMEOUT`
+    pub timeout: Option<u64>,
+}
+
+impl ClusterConfig {
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval.unwrap_or(DEFAULT_CLUSTER_INTERVAL))
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout.unwrap_or(DEFAULT_CLUSTER_TIMEOUT))
+    }
+
+    /// Nodes in the cluster, this one included.
+    pub fn size(&self) -> usize {
+        self.peers.len() + 1
+    }
+
+}
+
+
+/// External store sharing the sliding logs of the services with other
+/// replicas of jarl. Only read on startup.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct StoreConfig {
+    /// Address of a Redis server, as HOST:PORT
+    pub redis: Option<String>,
+
+    /// Password sent to the server when connecting, if it requires one
+    pub password: Option<String>,
+
+    /// Prefix of the keys holding the logs, defaults to
+    /// `DEFAULT_STORE_PREFIX`
+    pub prefix: Option<String>,
+}
+
+impl StoreConfig {
+
+    pub fn prefix(&self) -> &str {
+        self.prefix.as_deref().unwrap_or(DEFAULT_STORE_PREFIX)
+    }
+
+}
+
+
+/// Rate limit and optional dedicated listener of a single service.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ServiceConfig {
+    pub name: String,
+
+    /// Maximum number of requests to allow within the period
+    #[serde(alias = "limit")]
+    pub requests: u32,
+
+    /// Period to enforce rate over, in seconds
+    pub period: u32,
+
+    /// Dedicated port serving the delay of this service without any data
+    /// being sent by clients
+    pub port: Option<u16>,
+
+    /// Network interface to bind the dedicated port to, defaults to the
+    /// server's interface
+    pub ip: Option<IpAddr>,
+
+    #[serde(default)]
+    pub algorithm: Algorithm,
+}
+
+impl ServiceConfig {
+
+    pub fn new(name: &str, requests: u32, period: u32) -> Self {
+        ServiceConfig {
+            name: name.to_string(),
+            requests,
+            period,
+            port: None,
+            ip: None,
+            algorithm: Algorithm::default(),
+        }
+    }
+
+}
+
+
+/// Full description of the services and listeners of a jarl process, as read
+/// from a TOML configuration file and/or the command line.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub server: ServerConfig,
+
+    #[serde(default)]
+    pub state: StateConfig,
+
+    #[serde(default)]
+    pub cluster: ClusterConfig,
+
+    #[serde(default)]
+    pub store: StoreConfig,
+
+    #[serde(default, rename = "service")]
+    pub services: Vec<ServiceConfig>,
+}
+
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, String),
+    MissingIp,
+    NoListeners,
+    InvalidName(String),
+    DuplicateService(String),
+    DuplicateAddress(SocketAddr),
+    MissingLimit(String),
+    ZeroRequests(String),
+    ZeroPeriod(String),
+    ZeroPort(String),
+    ZeroInterval,
+    ZeroThreads,
+    GrpcUnavailable,
+    InvalidSocketMode(u32),
+    MissingClusterAddress,
+    NoPeers,
+    InvalidPeer(SocketAddr),
+    ClusterTimeout,
+    PairQuorum,
+    LimitBelowClusterSize(String),
+    StoreWithCluster,
+    StoreWithState,
+    UnstoredAlgorithm(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, error) =>
+                write!(f, "could not read {}: {}", path.display(), error),
+            ConfigError::Parse(path, message) =>
+                write!(f, "could not parse {}: {}", path.display(), message),
+            ConfigError::MissingIp =>
+                write!(f, "no network interface to bind to, set --ip or `ip` under [server]"),
+            ConfigError::NoListeners =>
+                write!(f, "no port to listen on, set a service `port` or the server `named_port`, `http_port`, `grpc_port`, `udp_port` or `unix_socket`"),
+            ConfigError::InvalidName(name) =>
+                write!(f, "invalid service name `{}`, names must be non-empty, contain no whitespace and not be a command such as STATUS", name),
+            ConfigError::DuplicateService(name) =>
+                write!(f, "service `{}` is defined more than once", name),
+            ConfigError::DuplicateAddress(address) =>
+                write!(f, "address {} is used by more than one listener", address),
+            ConfigError::MissingLimit(name) =>
+                write!(f, "service `{}` needs both requests and period", name),
+            ConfigError::ZeroRequests(name) =>
+                write!(f, "service `{}` must allow at least 1 request per period", name),
+            ConfigError::ZeroPeriod(name) =>
+                write!(f, "service `{}` must have a period of at least 1 second", name),
+            ConfigError::ZeroPort(name) =>
+                write!(f, "listener `{}` must use a port from 1 to 65535", name),
+            ConfigError::ZeroInterval =>
+                write!(f, "the state must be saved at an interval of at least 1 second"),
+            ConfigError::ZeroThreads =>
+                write!(f, "at least 1 thread is needed to handle connections"),
+            ConfigError::GrpcUnavailable =>
+                write!(f, "`grpc_port` is set, but jarl was built without the `grpc` feature"),
+            ConfigError::InvalidSocketMode(mode) =>
+                write!(f, "invalid `unix_socket_mode` {:#o}, permissions must be at most 0o777", mode),
+            ConfigError::MissingClusterAddress =>
+                write!(f, "cluster peers are set, but no `address` to exchange heartbeats on"),
+            ConfigError::NoPeers =>
+                write!(f, "a cluster `address` is set, but no `peers`"),
+            ConfigError::InvalidPeer(address) =>
+                write!(f, "peer {} is listed more than once, or is the address of this node", address),
+            ConfigError::ClusterTimeout =>
+                write!(f, "the cluster `timeout` must be longer than its `interval`, which must be at least 1 second"),
+            ConfigError::PairQuorum =>
+                write!(f, "a `quorum` cluster of 2 nodes stops when either of them is down, add a node or use the `lease` mode"),
+            ConfigError::LimitBelowClusterSize(name) =>
+                write!(f, "service `{}` must allow at least 1 request per period for every node of the cluster", name),
+            ConfigError::StoreWithCluster =>
+                write!(f, "a store cannot be used by the nodes of a cluster, which split the limit between them"),
+            ConfigError::StoreWithState =>
+                write!(f, "a store cannot be used with a state file, as the store already keeps the requests"),
+            ConfigError::UnstoredAlgorithm(name) =>
+                write!(f, "service `{}` must use the sliding-log or reservation algorithm to be kept in the store", name),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+
+impl Config {
+
+    /// Reads a TOML configuration file. The result is not validated.
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|error| ConfigError::Io(path.to_path_buf(), error))?;
+
+        toml::from_str(&contents)
+            .map_err(|error| ConfigError::Parse(path.to_path_buf(), error.to_string()))
+    }
+
+    /// Builds the configuration from the file given in `--config`, if any,
+    /// with the CLI flags taking precedence over its values.
+    pub fn from_cli(cli: &Cli) -> Result<Self, ConfigError> {
+        let mut config = match &cli.config {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
+        };
+
+        config.apply_cli(cli)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Overrides the values of this configuration with the ones given as CLI
+    /// flags. Services not present in the configuration are added to it.
+    pub fn apply_cli(&mut self, cli: &Cli) -> Result<(), ConfigError> {
+        if cli.ip.is_some() {
+            self.server.ip = cli.ip;
+        }
+        if cli.named_port.is_some() {
+            self.server.named_port = cli.named_port;
+        }
+        if cli.admin_port.is_some() {
+            self.server.admin_port = cli.admin_port;
+        }
+        if cli.http_port.is_some() {
+            self.server.http_port = cli.http_port;
+        }
+        if cli.grpc_port.is_some() {
+            self.server.grpc_port = cli.grpc_port;
+        }
+        if cli.metrics_port.is_some() {
+            self.server.metrics_port = cli.metrics_port;
+        }
+        if cli.udp_port.is_some() {
+            self.server.udp_port = cli.udp_port;
+        }
+        if cli.unix_socket.is_some() {
+            self.server.unix_socket = cli.unix_socket.clone();
+        }
+        if cli.unix_socket_mode.is_some() {
+            self.server.unix_socket_mode = cli.unix_socket_mode;
+        }
+        if cli.threads.is_some() {
+            self.server.threads = cli.threads;
+        }
+        if cli.shutdown_timeout.is_some() {
+            self.server.shutdown_timeout = cli.shutdown_timeout;
+        }
+        if cli.state_file.is_some() {
+            self.state.path = cli.state_file.clone();
+        }
+        if cli.state_interval.is_some() {
+            self.state.interval = cli.state_interval;
+        }
+        if let Some(mode) = cli.cluster_mode {
+            self.cluster.mode = mode;
+        }
+        if cli.cluster_address.is_some() {
+            self.cluster.address = cli.cluster_address;
+        }
+        if !cli.peers.is_empty() {
+            self.cluster.peers = cli.peers.clone();
+        }
+        if cli.redis.is_some() {
+            self.store.redis = cli.redis.clone();
+        }
+
+        if let Some(name) = &cli.service {
+            let service = match self.service_mut(name) {
+                Some(service) => service,
+                None => {
+                    let (Some(requests), Some(period)) = (cli.requests, cli.period) else {
+                        return Err(ConfigError::MissingLimit(name.clone()));
+                    };
+                    self.services.push(ServiceConfig::new(name, requests, period));
+                    self.services.last_mut().unwrap()
+                }
+            };
+
+            service.requests = cli.requests.unwrap_or(service.requests);
+            service.period = cli.period.unwrap_or(service.period);
+            service.port = cli.port.or(service.port);
+            service.algorithm = cli.algorithm.unwrap_or(service.algorithm);
+        }
+
+        for spec in &cli.services {
+            let service = match self.service_mut(&spec.name) {
+                Some(service) => service,
+                None => {
+                    self.services.push(ServiceConfig::new(&spec.name, spec.requests, spec.period));
+                    self.services.last_mut().unwrap()
+                }
+            };
+
+            service.requests = spec.requests;
+            service.period = spec.period;
+            service.algorithm = spec.algorithm.unwrap_or(service.algorithm);
+        }
+
+        Ok(())
+    }
+
+    /// Checks the configuration for values that would prevent jarl from
+    /// starting or enforcing a rate limit.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.state.interval == Some(0) {
+            return Err(ConfigError::ZeroInterval);
+        }
+        if self.server.threads == Some(0) {
+            return Err(ConfigError::ZeroThreads);
+        }
+        if let Some(mode) = self.server.unix_socket_mode.filter(|mode| *mode > 0o777) {
+            return Err(ConfigError::InvalidSocketMode(mode));
+        }
+        self.validate_cluster()?;
+        self.validate_store()?;
+
+        // The interface is only needed by network listeners, so that jarl can
+        // listen on a Unix socket alone
+        let has_ports = [self.server.named_port, self.server.admin_port, self.server.http_port,
+            self.server.grpc_port, self.server.metrics_port, self.server.udp_port].iter().any(Option::is_some);
+        if has_ports && self.server.ip.is_none() {
+            return Err(ConfigError::MissingIp);
+        }
+        let ip = self.server.ip;
+
+        let mut names = HashSet::new();
+        let mut addresses = HashSet::new();
+
+        if let Some(port) = self.server.named_port {
+            if port == 0 {
+                return Err(ConfigError::ZeroPort(String::from("named_port")));
+            }
+            addresses.insert(SocketAddr::new(ip.ok_or(ConfigError::MissingIp)?, port));
+        }
+        if self.server.grpc_port.is_some() && !cfg!(feature = "grpc") {
+            return Err(ConfigError::GrpcUnavailable);
+        }
+        if self.server.udp_port == Some(0) {
+            return Err(ConfigError::ZeroPort(String::from("udp_port")));
+        }
+
+        let ports = [
+            ("admin_port", self.server.admin_port),
+            ("http_port", self.server.http_port),
+            ("grpc_port", self.server.grpc_port),
+            ("metrics_port", self.server.metrics_port),
+        ];
+        for (name, port) in ports {
+            let Some(port) = port else {
+                continue;
+            };
+            if port == 0 {
+                return Err(ConfigError::ZeroPort(String::from(name)));
+            }
+            let address = SocketAddr::new(ip.ok_or(ConfigError::MissingIp)?, port);
+            if !addresses.insert(address) {
+                return Err(ConfigError::DuplicateAddress(address));
+            }
+        }
+
+        for service in &self.services {
+            if service.name.is_empty() || service.name.contains(char::is_whitespace) || protocol::is_command(&service.name) {
+                return Err(ConfigError::InvalidName(service.name.clone()));
+            }
+            if !names.insert(service.name.as_str()) {
+                return Err(ConfigError::DuplicateService(service.name.clone()));
+            }
+            if service.requests == 0 {
+                return Err(ConfigError::ZeroRequests(service.name.clone()));
+            }
+            if service.period == 0 {
+                return Err(ConfigError::ZeroPeriod(service.name.clone()));
+            }
+
+            if let Some(port) = service.port {
+                if port == 0 {
+                    return Err(ConfigError::ZeroPort(service.name.clone()));
+                }
+                let address = SocketAddr::new(service.ip.or(ip).ok_or(ConfigError::MissingIp)?, port);
+                if !addresses.insert(address) {
+                    return Err(ConfigError::DuplicateAddress(address));
+                }
+            }
+        }
+
+        if addresses.is_empty() && self.server.udp_port.is_none() && self.server.unix_socket.is_none() {
+            return Err(ConfigError::NoListeners);
+        }
+
+        Ok(())
+    }
+
+    fn validate_cluster(&self) -> Result<(), ConfigError> {
+        let cluster = &self.cluster;
+        let Some(address) = cluster.address else {
+            return match cluster.peers.is_empty() {
+                true => Ok(()),
+                false => Err(ConfigError::MissingClusterAddress),
+            };
+        };
+        if cluster.peers.is_empty() {
+            return Err(ConfigError::NoPeers);
+        }
+        if cluster.interval == Some(0) || cluster.timeout() <= cluster.interval() {
+            return Err(ConfigError::ClusterTimeout);
+        }
+
+        let mut peers = HashSet::from([address]);
+        if let Some(peer) = cluster.peers.iter().find(|peer| !peers.insert(**peer)) {
+            return Err(ConfigError::InvalidPeer(*peer));
+        }
+        if cluster.mode == ClusterMode::Quorum && cluster.size() == 2 {
+            return Err(ConfigError::PairQuorum);
+        }
+        if let Some(service) = self.services.iter().find(|service| (service.requests as usize) < cluster.size()) {
+            return Err(ConfigError::LimitBelowClusterSize(service.name.clone()));
+        }
+        Ok(())
+    }
+
+    fn validate_store(&self) -> Result<(), ConfigError> {
+        if self.store.redis.is_none() {
+            return Ok(());
+        }
+        if self.cluster.address.is_some() {
+            return Err(ConfigError::StoreWithCluster);
+        }
+        if self.state.path.is_some() {
+            return Err(ConfigError::StoreWithState);
+        }
+        let stored = |service: &&ServiceConfig| matches!(service.algorithm, Algorithm::SlidingLog | Algorithm::Reservation);
+        if let Some(service) = self.services.iter().find(|service| !stored(service)) {
+            return Err(ConfigError::UnstoredAlgorithm(service.name.clone()));
+        }
+        Ok(())
+    }
+
+    /// Address of the named port, if enabled.
+    pub fn named_address(&self) -> Option<SocketAddr> {
+        Some(SocketAddr::new(self.server.ip?, self.server.named_port?))
+    }
+
+    /// Address of the admin port, if enabled.
+    pub fn admin_address(&self) -> Option<SocketAddr> {
+        Some(SocketAddr::new(self.server.ip?, self.server.admin_port?))
+    }
+
+    /// Address of the HTTP port, if enabled.
+    pub fn http_address(&self) -> Option<SocketAddr> {
+        Some(SocketAddr::new(self.server.ip?, self.server.http_port?))
+    }
+
+    /// Address of the gRPC port, if enabled.
+    pub fn grpc_address(&self) -> Option<SocketAddr> {
+        Some(SocketAddr::new(self.server.ip?, self.server.grpc_port?))
+    }
+
+    /// Address of the metrics port, if enabled.
+    pub fn metrics_address(&self) -> Option<SocketAddr> {
+        Some(SocketAddr::new(self.server.ip?, self.server.metrics_port?))
+    }
+
+    /// Address of the UDP port, if enabled.
+    pub fn udp_address(&self) -> Option<SocketAddr> {
+        Some(SocketAddr::new(self.server.ip?, self.server.udp_port?))
+    }
+
+    /// Path and permissions of the Unix socket, if enabled.
+    pub fn unix_socket(&self) -> Option<(&Path, u32)> {
+        Some((self.server.unix_socket.as_deref()?, self.server.unix_socket_mode()))
+    }
+
+    /// Address of the dedicated port of a service, if enabled.
+    pub fn service_address(&self, service: &ServiceConfig) -> Option<SocketAddr> {
+        Some(SocketAddr::new(service.ip.or(self.server.ip)?, service.port?))
+    }
+
+    fn service_mut(&mut self, name: &str) -> Option<&mut ServiceConfig> {
+        self.services.iter_mut().find(|service| service.name == name)
+    }
+
+}
+
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use crate::{Algorithm, Cli};
+    use crate::cluster::ClusterMode;
+    use crate::config::{Config, ConfigError, ServiceConfig, DEFAULT_STATE_INTERVAL, DEFAULT_STORE_PREFIX, DEFAULT_UNIX_SOCKET_MODE};
+
+    const EXAMPLE: &str = r#"
+        [server]
+        ip = "0.0.0.0"
+        named_port = 1230
+
+        [state]
+        path = "/var/lib/jarl/state.toml"
+
+        [[service]]
+        name = "PaymentGateway"
+        requests = 100
+        period = 1
+        port = 1234
+        algorithm = "sliding-log"
+
+        [[service]]
+        name = "SocialMedia"
+        limit = 200
+        period = 60
+    "#;
+
+    fn example() -> Config {
+        toml::from_str(EXAMPLE).unwrap()
+    }
+
+    #[test]
+    fn parse_example() {
+        let config = example();
+        config.validate().unwrap();
+
+        assert_eq!(config.named_address().unwrap().to_string(), "0.0.0.0:1230");
+        assert_eq!(config.state.interval().as_secs(), DEFAULT_STATE_INTERVAL);
+        assert_eq!(config.services.len(), 2);
+
+        let payments = &config.services[0];
+        assert_eq!(payments.algorithm, Algorithm::SlidingLog);
+        assert_eq!(config.service_address(payments).unwrap().to_string(), "0.0.0.0:1234");
+
+        let social = &config.services[1];
+        assert_eq!((social.requests, social.period, social.port), (200, 60, None));
+    }
+
+    #[test]
+    fn reject_unknown_fields() {
+        assert!(toml::from_str::<Config>("[server]\nadress = \"0.0.0.0\"").is_err());
+        assert!(toml::from_str::<Config>("[[service]]\nname = \"a\"\nrequests = 1\nperiod = 1\nalgorithm = \"magic\"").is_err());
+    }
+
+    #[test]
+    fn validation_errors() {
+        let mut config = example();
+        config.services[1].period = 0;
+        assert!(matches!(config.validate(), Err(ConfigError::ZeroPeriod(name)) if name == "SocialMedia"));
+
+        let mut config = example();
+        config.services[0].requests = 0;
+        assert!(matches!(config.validate(), Err(ConfigError::ZeroRequests(_))));
+
+        let mut config = example();
+        config.services.push(ServiceConfig::new("SocialMedia", 1, 1));
+        assert!(matches!(config.validate(), Err(ConfigError::DuplicateService(_))));
+
+        let mut config = example();
+        config.services[1].port = Some(1230);
+        assert!(matches!(config.validate(), Err(ConfigError::DuplicateAddress(_))));
+
+        let mut config = example();
+        config.state.interval = Some(0);
+        assert!(matches!(config.validate(), Err(ConfigError::ZeroInterval)));
+
+        let mut config = example();
+        config.server.threads = Some(0);
+        assert!(matches!(config.validate(), Err(ConfigError::ZeroThreads)));
+
+        let mut config = example();
+        config.server.admin_port = Some(1230);
+        assert!(matches!(config.validate(), Err(ConfigError::DuplicateAddress(_))));
+
+        let mut config = example();
+        config.services[1].name = String::from("Social Media");
+        assert!(matches!(config.validate(), Err(ConfigError::InvalidName(_))));
+
+        let mut config = example();
+        config.services[1].name = String::from("Status");
+        assert!(matches!(config.validate(), Err(ConfigError::InvalidName(_))));
+
+        let mut config = example();
+        config.server.ip = None;
+        assert!(matches!(config.validate(), Err(ConfigError::MissingIp)));
+
+        let mut config = example();
+        config.server.named_port = None;
+        config.services[0].port = None;
+        assert!(matches!(config.validate(), Err(ConfigError::NoListeners)));
+    }
+
+    #[test]
+    fn cluster_errors() {
+        let cli = Cli::try_parse_from([
+            "jarl", "--ip", "127.0.0.1", "--named-port", "1230", "--define", "api=2/1",
+            "--cluster-address", "127.0.0.1:7946", "--peer", "127.0.0.2:7946", "--cluster-mode", "lease",
+        ]).unwrap();
+        let mut config = Config::default();
+        config.apply_cli(&cli).unwrap();
+        config.validate().unwrap();
+        assert_eq!(config.cluster.size(), 2);
+        assert_eq!(config.cluster.mode, ClusterMode::Lease);
+
+        let mut cluster = config.clone();
+        cluster.cluster.peers.push("127.0.0.1:7946".parse().unwrap());
+        assert!(matches!(cluster.validate(), Err(ConfigError::InvalidPeer(_))));
+
+        let mut cluster = config.clone();
+        cluster.cluster.peers.push("127.0.0.3:7946".parse().unwrap());
+        assert!(matches!(cluster.validate(), Err(ConfigError::LimitBelowClusterSize(name)) if name == "api"));
+
+        let mut cluster = config.clone();
+        cluster.cluster.mode = ClusterMode::Quorum;
+        assert!(matches!(cluster.validate(), Err(ConfigError::PairQuorum)));
+
+        let mut cluster = config.clone();
+        cluster.cluster.timeout = Some(1);
+        assert!(matches!(cluster.validate(), Err(ConfigError::ClusterTimeout)));
+
+        let mut cluster = config.clone();
+        cluster.cluster.address = None;
+        assert!(matches!(cluster.validate(), Err(ConfigError::MissingClusterAddress)));
+
+        let mut cluster = config;
+        cluster.cluster.peers.clear();
+        assert!(matches!(cluster.validate(), Err(ConfigError::NoPeers)));
+    }
+
+    #[test]
+    fn store_errors() {
+        let cli = Cli::try_parse_from([
+            "jarl", "--ip", "127.0.0.1", "--named-port", "1230", "--define", "api=2/1", "--redis", "127.0.0.1:6379",
+        ]).unwrap();
+        let mut config = Config::default();
+        config.apply_cli(&cli).unwrap();
+        config.validate().unwrap();
+        assert_eq!(config.store.redis.as_deref(), Some("127.0.0.1:6379"));
+        assert_eq!(config.store.prefix(), DEFAULT_STORE_PREFIX);
+
+        let mut store = config.clone();
+        store.services[0].algorithm = Algorithm::Reservation;
+        store.validate().unwrap();
+        store.services[0].algorithm = Algorithm::TokenBucket;
+        assert!(matches!(store.validate(), Err(ConfigError::UnstoredAlgorithm(name)) if name == "api"));
+
+        let mut store = config.clone();
+        store.state.path = Some("/var/lib/jarl/state.toml".into());
+        assert!(matches!(store.validate(), Err(ConfigError::StoreWithState)));
+
+        let mut store = config;
+        store.cluster.mode = ClusterMode::Lease;
+        store.cluster.address = Some("127.0.0.1:7946".parse().unwrap());
+        store.cluster.peers.push("127.0.0.2:7946".parse().unwrap());
+        assert!(matches!(store.validate(), Err(ConfigError::StoreWithCluster)));
+    }
+
+    #[test]
+    /// A Unix socket is enough to start, without any network interface.
+    fn unix_socket_only() {
+        let mut config = Config::default();
+        config.server.unix_socket = Some("/run/jarl.sock".into());
+        config.validate().unwrap();
+        assert_eq!(config.unix_socket().unwrap().1, DEFAULT_UNIX_SOCKET_MODE);
+
+        config.server.unix_socket_mode = Some(0o1777);
+        assert!(matches!(config.validate(), Err(ConfigError::InvalidSocketMode(_))));
+
+        config.server.unix_socket_mode = None;
+        config.server.http_port = Some(8080);
+        assert!(matches!(config.validate(), Err(ConfigError::MissingIp)));
+
+        let cli = Cli::try_parse_from(["jarl", "--unix-socket", "/run/jarl.sock", "--unix-socket-mode", "600"]).unwrap();
+        let mut config = Config::default();
+        config.apply_cli(&cli).unwrap();
+        assert_eq!(config.unix_socket().unwrap().1, 0o600);
+    }
+
+    #[test]
+    #[cfg(not(feature = "grpc"))]
+    fn grpc_needs_feature() {
+        let mut config = example();
+        config.server.grpc_port = Some(1240);
+        assert!(matches!(config.validate(), Err(ConfigError::GrpcUnavailable)));
+    }
+
+    #[test]
+    /// Flags given on the CLI take precedence over the values of the file.
+    fn cli_overrides() {
+        let cli = Cli::try_parse_from([
+            "jarl", "--ip", "127.0.0.1", "--service", "PaymentGateway", "--requests", "50",
+            "--algorithm", "gcra", "--define", "SocialMedia=10/1", "--define", "Search=5/1:fixed-window",
+        ]).unwrap();
+
+        let mut config = example();
+        config.apply_cli(&cli).unwrap();
+        config.validate().unwrap();
+
+        assert_eq!(config.server.ip.unwrap().to_string(), "127.0.0.1");
+        assert_eq!(config.server.named_port, Some(1230));
+
+        let payments = &config.services[0];
+        assert_eq!((payments.requests, payments.period, payments.port), (50, 1, Some(1234)));
+        assert_eq!(payments.algorithm, Algorithm::Gcra);
+
+        let social = &config.services[1];
+        assert_eq!((social.requests, social.period), (10, 1));
+        assert_eq!(social.algorithm, Algorithm::SlidingLog);
+
+        assert_eq!(config.services[2].algorithm, Algorithm::FixedWindow);
+    }
+
+    #[test]
+    fn cli_service_needs_limit() {
+        let cli = Cli::try_parse_from(["jarl", "--ip", "127.0.0.1", "--service", "Unknown", "--port", "1"]).unwrap();
+        let mut config = example();
+        assert!(matches!(config.apply_cli(&cli), Err(ConfigError::MissingLimit(_))));
+    }
+}
@@ -0,0 +1,106 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::ConfigError;
+
+
+/// Failures handled without stopping jarl, since the process started.
+static FAILURES: AtomicU64 = AtomicU64::new(0);
+
+
+#[derive(Debug)]
+pub enum Error {
+    /// A rate limiter was given a limit of 0 requests per period
+    ZeroLimit,
+    /// A rate limiter was given a period of 0 seconds
+    ZeroPeriod,
+    Config(ConfigError),
+    /// A listener could not be bound to the address or path given first
+    Bind(String, std::io::Error),
+    /// A connection could not be accepted or replied to, such as when the
+    /// client disconnects before reading its reply
+    Connection(std::io::Error),
+    /// The runtime handling connections could not be started
+    Runtime(std::io::Error),
+    /// The external store of the requests could not be reached, or failed to
+    /// run a command
+    Store(std::io::Error),
+    /// A connection to the service was closed without a reply, as requests
+    /// cannot be recorded until the cluster or the store is reached
+    Unavailable(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::ZeroLimit => write!(f, "max requests per period must be greater than 0"),
+            Error::ZeroPeriod => write!(f, "period must be greater than 0"),
+            Error::Config(error) => write!(f, "{}", error),
+            Error::Bind(listener, error) => write!(f, "could not listen on {}: {}", listener, error),
+            Error::Connection(error) => write!(f, "connection failed: {}", error),
+            Error::Runtime(error) => write!(f, "could not start the runtime: {}", error),
+            Error::Store(error) => write!(f, "store failed: {}", error),
+            Error::Unavailable(service) => write!(f, "request for {} dropped: requests cannot be recorded", service),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Config(error) => Some(error),
+            Error::Bind(_, error) | Error::Connection(error) | Error::Runtime(error) | Error::Store(error) => Some(error),
+            Error::ZeroLimit | Error::ZeroPeriod | Error::Unavailable(_) => None,
+        }
+    }
+}
+
+impl From<ConfigError> for Error {
+    fn from(error: ConfigError) -> Self {
+        Error::Config(error)
+    }
+}
+
+
+/// Checks the limit of a rate limiter before building it.
+pub fn check_limit(limit: u32, period: u32) -> Result<(), Error> {
+    if limit == 0 {
+        return Err(Error::ZeroLimit);
+    }
+    if period == 0 {
+        return Err(Error::ZeroPeriod);
+    }
+    Ok(())
+}
+
+/// Logs a failure that jarl recovered from, and counts it.
+pub fn report(error: &Error) {
+    FAILURES.fetch_add(1, Ordering::Relaxed);
+    tracing::warn!(%error, "request failed");
+}
+
+/// Number of failures reported since the process started.
+pub fn failures() -> u64 {
+    FAILURES.load(Ordering::Relaxed)
+}
+
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use crate::error::{check_limit, failures, report, Error};
+
+    #[test]
+    fn limit_errors() {
+        assert!(check_limit(1, 1).is_ok());
+        assert!(matches!(check_limit(0, 1), Err(Error::ZeroLimit)));
+        assert!(matches!(check_limit(1, 0), Err(Error::ZeroPeriod)));
+    }
+
+    #[test]
+    fn count_failures() {
+        let before = failures();
+        report(&Error::Connection(std::io::ErrorKind::BrokenPipe.into()));
+        assert!(failures() > before);
+    }
+}
@@ -0,0 +1,201 @@
+use std::pin::Pin;
+use std::sync::Arc;
+
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, watch};
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::{Stream, StreamExt};
+use tonic::{Request, Response, Status, Streaming};
+
+use crate::{protocol, ProtocolError, RateLimiter, Registry};
+
+/// Messages, client and server generated from `proto/jarl.proto`.
+pub mod proto {
+    tonic::include_proto!("jarl.v1");
+}
+
+use proto::rate_limiter_server::{RateLimiter as RateLimiterRpc, RateLimiterServer};
+use proto::{AcquireRequest, AcquireResponse, ServiceStatus, StatusRequest, StatusResponse};
+
+
+/// The `RateLimiter` gRPC service, backed by the services of a registry.
+pub struct GrpcService {
+    registry: Arc<Registry>,
+}
+
+impl GrpcService {
+
+    pub fn new(registry: Arc<Registry>) -> Self {
+        GrpcService { registry }
+    }
+
+}
+
+impl From<ProtocolError> for Status {
+    fn from(error: ProtocolError) -> Self {
+        match error {
+            ProtocolError::UnknownService(_) => Status::not_found(error.to_string()),
+            ProtocolError::Unavailable => Status::unavailable(error.to_string()),
+            _ => Status::invalid_argument(error.to_string()),
+        }
+    }
+}
+
+fn acquire(registry: &Registry, request: &AcquireRequest, peek: bool) -> Result<AcquireResponse, ProtocolError> {
+    let keeper = match peek {
+        false => registry.get_available(&request.service)?,
+        true => registry.get(&request.service).ok_or_else(|| ProtocolError::UnknownService(request.service.clone()))?,
+    };
+
+    // Unset fields of proto3 messages are 0
+    let cost = request.cost.max(1);
+    let delay = match peek {
+        false => keeper.try_get_weighted_delay(cost),
+        true => keeper.lock().try_peek_weighted_delay(cost),
+    }.map_err(protocol::unavailable)?;
+    let quota = keeper.read().quota();
+
+    Ok(AcquireResponse { delay, remaining: quota.remaining, reset: quota.reset })
+}
+
+/// Status of the service `name`, or of every service if it is empty.
+fn status(registry: &Registry, name: &str) -> Result<StatusResponse, ProtocolError> {
+    let services = match name.is_empty() {
+        true => registry.entries().iter()
+            .map(|(name, keeper)| service_status(name, &**keeper.read()))
+            .collect(),
+        false => {
+            let keeper = registry.get(name)
+                .ok_or_else(|| ProtocolError::UnknownService(name.to_string()))?;
+            let status = service_status(name, &**keeper.read());
+            vec![status]
+        }
+    };
+    Ok(StatusResponse { services })
+}
+
+fn service_status(name: &str, keeper: &dyn RateLimiter) -> ServiceStatus {
+    let quota = keeper.quota();
+    ServiceStatus {
+        name: name.to_string(),
+        algorithm: keeper.algorithm().to_string(),
+        requests: keeper.limit(),
+        period: keeper.period(),
+        remaining: quota.remaining,
+        reset: quota.reset,
+    }
+}
+
+#[tonic::async_trait]
+impl RateLimiterRpc for GrpcService {
+
+    async fn acquire(&self, request: Request<AcquireRequest>) -> Result<Response<AcquireResponse>, Status> {
+        let request = request.into_inner();
+        Ok(Response::new(self.registry.run(move |registry| acquire(registry, &request, false)).await?))
+    }
+
+    async fn peek(&self, request: Request<AcquireRequest>) -> Result<Response<AcquireResponse>, Status> {
+        let request = request.into_inner();
+        Ok(Response::new(self.registry.run(move |registry| acquire(registry, &request, true)).await?))
+    }
+
+    async fn status(&self, request: Request<StatusRequest>) -> Result<Response<StatusResponse>, Status> {
+        let name = request.into_inner().service;
+        Ok(Response::new(self.registry.run(move |registry| status(registry, &name)).await?))
+    }
+
+    type AcquireStreamStream = Pin<Box<dyn Stream<Item = Result<AcquireResponse, Status>> + Send>>;
+
+    async fn acquire_stream(
+        &self,
+        request: Request<Streaming<AcquireRequest>>,
+    ) -> Result<Response<Self::AcquireStreamStream>, Status> {
+        let registry = self.registry.clone();
+        let responses = request.into_inner().then(move |request| {
+            let registry = registry.clone();
+            async move {
+                let request = request?;
+                Ok(registry.run(move |registry| acquire(registry, &request, false)).await?)
+            }
+        });
+
+        Ok(Response::new(Box::pin(responses)))
+    }
+
+}
+
+
+/// Serves the gRPC API on the connections received from `incoming`, until
+/// `closing` is set. Requests being handled by then are completed before
+/// returning.
+pub async fn serve(incoming: mpsc::Receiver<TcpStream>, registry: Arc<Registry>, mut closing: watch::Receiver<bool>) {
+    let incoming = ReceiverStream::new(incoming).map(Ok::<_, std::io::Error>);
+    let closed = async move {
+        let _ = closing.wait_for(|closing| *closing).await;
+    };
+
+    let _ = tonic::transport::Server::builder()
+        .add_service(RateLimiterServer::new(GrpcService::new(registry)))
+        .serve_with_incoming_shutdown(incoming, closed)
+        .await;
+}
+
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::sync::mpsc;
+    use tokio_stream::StreamExt;
+    use tonic::Code;
+
+    use crate::{Config, Registry};
+    use crate::config::ServiceConfig;
+    use crate::grpc::proto::rate_limiter_client::RateLimiterClient;
+    use crate::grpc::proto::{AcquireRequest, StatusRequest};
+    use crate::server::Server;
+    use crate::testing::free_port;
+
+    fn request(service: &str, cost: u32) -> AcquireRequest {
+        AcquireRequest { service: service.to_string(), cost }
+    }
+
+    #[tokio::test]
+    /// The gRPC API shares the rate limits of the other listeners.
+    async fn rate_limiter_service() {
+        let mut config = Config::default();
+        config.server.ip = Some("127.0.0.1".parse().unwrap());
+        config.server.grpc_port = Some(free_port());
+        config.services.push(ServiceConfig::new("service", 3, 60));
+
+        let (sender, _receiver) = mpsc::channel(1);
+        let server = Server::start(&config, Arc::new(Registry::from_config(&config)), sender).await.unwrap();
+        let mut client = RateLimiterClient::connect(format!("http://{}", config.grpc_address().unwrap())).await.unwrap();
+
+        let peeked = client.peek(request("service", 3)).await.unwrap().into_inner();
+        assert_eq!((peeked.delay, peeked.remaining), (0.0, 3));
+
+        let acquired = client.acquire(request("service", 0)).await.unwrap().into_inner();
+        assert_eq!((acquired.delay, acquired.remaining), (0.0, 2));
+
+        let status = client.status(StatusRequest::default()).await.unwrap().into_inner();
+        assert_eq!(status.services.len(), 1);
+        assert_eq!(status.services[0].algorithm, "sliding-log");
+        assert_eq!(status.services[0].remaining, 2);
+
+        let error = client.acquire(request("unknown", 1)).await.unwrap_err();
+        assert_eq!(error.code(), Code::NotFound);
+
+        // Replies to streamed requests come in order, and the stream ends at
+        // the first unknown service
+        let requests = tokio_stream::iter(vec![request("service", 2), request("service", 1), request("unknown", 1)]);
+        let mut responses = client.acquire_stream(requests).await.unwrap().into_inner();
+        assert_eq!(responses.next().await.unwrap().unwrap().delay, 0.0);
+        assert!(responses.next().await.unwrap().unwrap().delay > 0.0);
+        assert_eq!(responses.next().await.unwrap().unwrap_err().code(), Code::NotFound);
+
+        drop(client);
+        assert!(server.shutdown(std::time::Duration::from_secs(5)).await);
+    }
+}
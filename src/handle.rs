@@ -0,0 +1,160 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::{Algorithm, Error, Quota, RateLimiter, SharedLimiter, TimeKeeper};
+
+
+/// Thread-safe handle to a rate limiter, for enforcing a rate limit within the
+/// same process instead of asking a jarl server. Clones share the same
+/// recorded requests.
+#[derive(Clone)]
+pub struct Limiter {
+    keeper: TimeKeeper,
+}
+
+impl Limiter {
+
+    /// Limiter allowing `requests` requests every `period` seconds, using the
+    /// default algorithm. Other algorithms can be used through `From`, such
+    /// as `Limiter::from(Algorithm::Gcra.build(100, 1))`.
+    pub fn new(requests: u32, period: u32) -> Self {
+        Limiter::from(Algorithm::default().build(requests, period))
+    }
+
+    /// Creates a limiter as `new` does, returning an error instead of
+    /// panicking if the limit or the period is 0.
+    pub fn try_new(requests: u32, period: u32) -> Result<Self, Error> {
+        Ok(Limiter::from(Algorithm::default().try_build(requests, period)?))
+    }
+
+    /// Records a request if it can be made right now. Otherwise nothing is
+    /// recorded, and the error holds how long the request would have to wait.
+    pub fn check(&self) -> Result<(), Duration> {
+        self.check_weighted(1)
+    }
+
+    /// Records a request costing `cost` units of the limit if it can be made
+    /// right now, as `check` does.
+    pub fn check_weighted(&self, cost: u32) -> Result<(), Duration> {
+        let mut keeper = self.keeper.lock();
+        match keeper.peek_weighted_delay(cost) {
+            delay if delay > 0.0 => Err(Duration::from_secs_f32(delay)),
+            _ => {
+                keeper.get_weighted_delay(cost);
+                Ok(())
+            }
+        }
+    }
+
+    /// Records a request and returns how long to wait before making it, as
+    /// the `ACQUIRE` command of the server does.
+    pub fn acquire(&self, cost: u32) -> Duration {
+        Duration::from_secs_f32(self.keeper.get_weighted_delay(cost))
+    }
+
+    /// Records a request and waits until it can be made.
+    pub async fn until_ready(&self) {
+        self.until_ready_weighted(1).await
+    }
+
+    /// Records a request costing `cost` units of the limit and waits until it
+    /// can be made.
+    pub async fn until_ready_weighted(&self, cost: u32) {
+        let delay = self.acquire(cost);
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Gives back the units of a request that was not made after all.
+    pub fn release(&self, cost: u32) {
+        self.keeper.lock().release(cost);
+    }
+
+    pub fn quota(&self) -> Quota {
+        self.keeper.read().quota()
+    }
+
+    /// The shared rate limiter behind this handle, such as to register it in
+    /// a `Registry` served by a server in the same process.
+    pub fn keeper(&self) -> &TimeKeeper {
+        &self.keeper
+    }
+
+}
+
+impl From<Box<dyn RateLimiter>> for Limiter {
+    fn from(limiter: Box<dyn RateLimiter>) -> Self {
+        Limiter { keeper: Arc::new(SharedLimiter::new(limiter)) }
+    }
+}
+
+/// Shares the recorded requests of a service of a `Registry`.
+impl From<TimeKeeper> for Limiter {
+    fn from(keeper: TimeKeeper) -> Self {
+        Limiter { keeper }
+    }
+}
+
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use crate::{Algorithm, Keeper, Limiter, ManualClock, Registry};
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    /// Checking records a request only if it can be made right now.
+    fn check() {
+        assert_send_sync::<Limiter>();
+        let clock = ManualClock::new(100.0);
+        let limiter = Limiter::from(Algorithm::FixedWindow.build_with_clock(2, 10, Arc::new(clock.clone())));
+
+        assert_eq!(limiter.check_weighted(2), Ok(()));
+        assert_eq!(limiter.check(), Err(Duration::from_secs(10)));
+        assert_eq!(limiter.quota().remaining, 0, "Rejected request should not be recorded.");
+
+        limiter.release(1);
+        assert_eq!(limiter.clone().check(), Ok(()));
+
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(limiter.check(), Ok(()));
+    }
+
+    #[test]
+    /// Clones share the limit across threads.
+    fn shared_between_threads() {
+        let limiter = Limiter::new(50, 60);
+        let threads: Vec<_> = (0..4).map(|_| {
+            let limiter = limiter.clone();
+            std::thread::spawn(move || (0..20).filter(|_| limiter.check().is_ok()).count())
+        }).collect();
+
+        let allowed: usize = threads.into_iter().map(|thread| thread.join().unwrap()).sum();
+        assert_eq!(allowed, 50);
+    }
+
+    #[tokio::test]
+    async fn until_ready() {
+        let limiter = Limiter::from(Algorithm::Gcra.build(100, 1));
+        let start = Instant::now();
+        for _ in 0..105 {
+            limiter.until_ready().await;
+        }
+        assert!(start.elapsed() >= Duration::from_millis(40), "Requests beyond the burst should wait.");
+    }
+
+    #[test]
+    /// A limiter built from a service of a registry shares its requests.
+    fn from_registry() {
+        let registry = Registry::new();
+        let keeper = registry.insert("api", Box::new(Keeper::try_new(1, 60).unwrap())).unwrap();
+
+        assert_eq!(Limiter::from(keeper).check(), Ok(()));
+        assert!(registry.get("api").unwrap().lock().peek_weighted_delay(1) > 0.0);
+    }
+}
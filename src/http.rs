@@ -0,0 +1,465 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Serialize;
+use tokio::io::*;
+use tokio::net::TcpStream;
+
+use crate::{error, metrics, protocol, Algorithm, ProtocolError, RateLimiter, Registry};
+
+
+/// Longest request line or header read from clients, plus newline.
+const MAX_LINE_LENGTH: u64 = 8192;
+
+/// Most headers read from a request.
+const MAX_HEADERS: usize = 100;
+
+/// Largest request body read, and discarded, as no endpoint expects one.
+const MAX_BODY_LENGTH: u64 = 65536;
+
+/// Longest wait for the whole request, so that idle or slow clients do not
+/// hold a connection forever.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+
+/// Request line of an HTTP request, the only part the API looks at besides
+/// the length of the body.
+#[derive(Debug, PartialEq)]
+struct Request {
+    method: String,
+    path: String,
+    query: String,
+}
+
+#[derive(Debug, PartialEq)]
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    /// Methods allowed on the path, sent with `405 Method Not Allowed`
+    allow: Option<&'static str>,
+    body: String,
+}
+
+impl Response {
+
+    fn json(status: u16, body: &impl Serialize) -> Self {
+        let body = serde_json::to_string(body).expect("API responses are always serializable");
+        Response { status, content_type: "application/json", allow: None, body }
+    }
+
+    /// Metrics in the Prometheus text format.
+    fn metrics(body: String) -> Self {
+        Response { status: 200, content_type: "text/plain; version=0.0.4", allow: None, body }
+    }
+
+    fn error(status: u16, code: &str, message: &str) -> Self {
+        Response::json(status, &ErrorBody { error: ErrorDetail { code, message } })
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            408 => "Request Timeout",
+            503 => "Service Unavailable",
+            _ => "Internal Server Error",
+        }
+    }
+
+}
+
+impl From<ProtocolError> for Response {
+    fn from(error: ProtocolError) -> Self {
+        let status = match error {
+            ProtocolError::UnknownService(_) => 404,
+            ProtocolError::Unavailable => 503,
+            _ => 400,
+        };
+        Response::error(status, error.code(), &error.to_string())
+    }
+}
+
+
+#[derive(Serialize)]
+struct Acquired<'a> {
+    service: &'a str,
+    /// Seconds to wait before making the request
+    delay: f32,
+    remaining: u32,
+    reset: f32,
+}
+
+#[derive(Serialize)]
+struct ServiceStatus<'a> {
+    name: &'a str,
+    algorithm: Algorithm,
+    requests: u32,
+    period: u32,
+    remaining: u32,
+    reset: f32,
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    error: ErrorDetail<'a>,
+}
+
+#[derive(Serialize)]
+struct ErrorDetail<'a> {
+    code: &'a str,
+    message: &'a str,
+}
+
+
+/// Reads a single HTTP/1.x request and replies with a JSON body, closing the
+/// connection afterwards. Requests that cannot be parsed are replied to with
+/// `400 Bad Request`, and requests not received within `READ_TIMEOUT` with
+/// `408 Request Timeout`.
+pub async fn handle_http_connection(stream: TcpStream, registry: Arc<Registry>) {
+    respond(stream, &registry, route).await;
+}
+
+/// Replies to a single HTTP/1.x request for `GET /metrics` with the metrics
+/// of every service, in the Prometheus text format.
+pub async fn handle_metrics_connection(stream: TcpStream, registry: Arc<Registry>) {
+    respond(stream, &registry, route_metrics).await;
+}
+
+async fn respond(stream: TcpStream, registry: &Arc<Registry>, route: fn(&Request, &Registry) -> Response) {
+    let mut reader = BufReader::new(stream);
+
+    let response = match tokio::time::timeout(READ_TIMEOUT, read_request(&mut reader)).await {
+        Ok(Ok(request)) => registry.run(move |registry| route(&request, registry)).await,
+        Ok(Err(None)) => return,
+        Ok(Err(Some(message))) => Response::error(400, "bad-request", message),
+        Err(_elapsed) => Response::error(408, "request-timeout", "request not received in time"),
+    };
+
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        response.status, response.reason(), response.content_type, response.body.len(),
+    );
+    if let Some(allow) = response.allow {
+        head.push_str(&format!("Allow: {}\r\n", allow));
+    }
+    head.push_str("\r\n");
+    head.push_str(&response.body);
+
+    let stream = reader.get_mut();
+    match stream.write_all(head.as_bytes()).await {
+        Ok(()) => {
+            let _ = stream.shutdown().await;
+        }
+        Err(error) => error::report(&crate::Error::Connection(error)),
+    }
+}
+
+/// Reads a line, without its line ending. Fails with `None` if the stream
+/// ended before it.
+async fn read_line(reader: &mut BufReader<TcpStream>) -> std::result::Result<String, Option<&'static str>> {
+    let mut line = String::new();
+    let length = reader.take(MAX_LINE_LENGTH).read_line(&mut line).await.map_err(|_| None)?;
+
+    if length == 0 {
+        return Err(None);
+    }
+    if !line.ends_with('\n') {
+        return Err(Some("line too long"));
+    }
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// Reads the request line and headers of a request, and discards its body.
+/// Fails with the reason the request is invalid, or `None` if the client
+/// closed the connection.
+async fn read_request(reader: &mut BufReader<TcpStream>) -> std::result::Result<Request, Option<&'static str>> {
+    let line = read_line(reader).await?;
+    let mut parts = line.split(' ');
+    let (Some(method), Some(target), Some(version), None) = (parts.next(), parts.next(), parts.next(), parts.next()) else {
+        return Err(Some("invalid request line"));
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Err(Some("unsupported HTTP version"));
+    }
+
+    let mut length = 0;
+    let mut headers = 0;
+    loop {
+        let header = read_line(reader).await?;
+        if header.is_empty() {
+            break;
+        }
+
+        headers += 1;
+        if headers > MAX_HEADERS {
+            return Err(Some("too many headers"));
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse().map_err(|_| Some("invalid Content-Length"))?;
+            }
+        }
+    }
+
+    if length > MAX_BODY_LENGTH {
+        return Err(Some("body too large"));
+    }
+    copy(&mut reader.take(length), &mut sink()).await.map_err(|_| None)?;
+
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    Ok(Request { method: method.to_string(), path: path.to_string(), query: query.to_string() })
+}
+
+fn route(request: &Request, registry: &Registry) -> Response {
+    let segments: Option<Vec<String>> = request.path.strip_prefix('/')
+        .unwrap_or(&request.path)
+        .split('/')
+        .map(percent_decode)
+        .collect();
+    let Some(segments) = segments else {
+        return Response::error(400, "bad-request", "invalid percent-encoding in path");
+    };
+    let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+    let method = request.method.as_str();
+
+    match segments.as_slice() {
+        ["v1", "acquire", service] => match method {
+            "POST" => acquire(service, &request.query, registry).unwrap_or_else(Response::from),
+            _ => method_not_allowed("POST"),
+        },
+        ["v1", "services"] => match method {
+            "GET" => services(registry),
+            _ => method_not_allowed("GET"),
+        },
+        ["v1", "services", service] => match method {
+            "GET" => status(service, registry).unwrap_or_else(Response::from),
+            _ => method_not_allowed("GET"),
+        },
+        _ => Response::error(404, "not-found", &format!("no endpoint at `{}`", request.path)),
+    }
+}
+
+fn route_metrics(request: &Request, registry: &Registry) -> Response {
+    match (request.path.as_str(), request.method.as_str()) {
+        ("/metrics", "GET") => Response::metrics(metrics::render(registry)),
+        ("/metrics", _) => method_not_allowed("GET"),
+        _ => Response::error(404, "not-found", &format!("no endpoint at `{}`", request.path)),
+    }
+}
+
+fn method_not_allowed(allow: &'static str) -> Response {
+    let message = format!("only {} is allowed on this endpoint", allow);
+    Response { allow: Some(allow), ..Response::error(405, "method-not-allowed", &message) }
+}
+
+/// `POST /v1/acquire/{service}[?cost=N]`
+fn acquire(service: &str, query: &str, registry: &Registry) -> std::result::Result<Response, ProtocolError> {
+    let mut cost = 1;
+    for (name, value) in query.split('&').filter_map(|parameter| parameter.split_once('=')) {
+        if name == "cost" {
+            cost = value.parse().ok().filter(|cost| *cost > 0)
+                .ok_or_else(|| ProtocolError::InvalidCost(value.to_string()))?;
+        }
+    }
+
+    let keeper = registry.get_available(service)?;
+    let delay = keeper.try_get_weighted_delay(cost).map_err(protocol::unavailable)?;
+    let quota = keeper.read().quota();
+
+    Ok(Response::json(200, &Acquired { service, delay, remaining: quota.remaining, reset: quota.reset }))
+}
+
+/// `GET /v1/services`
+fn services(registry: &Registry) -> Response {
+    let entries = registry.entries();
+    let services: Vec<ServiceStatus> = entries.iter()
+        .map(|(name, keeper)| service_status(name, &**keeper.read()))
+        .collect();
+    Response::json(200, &services)
+}
+
+/// `GET /v1/services/{service}`
+fn status(service: &str, registry: &Registry) -> std::result::Result<Response, ProtocolError> {
+    let keeper = registry.get(service).ok_or_else(|| ProtocolError::UnknownService(service.to_string()))?;
+    let status = service_status(service, &**keeper.read());
+    Ok(Response::json(200, &status))
+}
+
+fn service_status<'a>(name: &'a str, keeper: &dyn RateLimiter) -> ServiceStatus<'a> {
+    let quota = keeper.quota();
+    ServiceStatus {
+        name,
+        algorithm: keeper.algorithm(),
+        requests: keeper.limit(),
+        period: keeper.period(),
+        remaining: quota.remaining,
+        reset: quota.reset,
+    }
+}
+
+/// Decodes the `%XX` escapes of a path segment. Returns `None` if an escape is
+/// invalid or the result is not UTF-8.
+fn percent_decode(segment: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(segment.len());
+    let mut rest = segment.as_bytes();
+
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            // from_str_radix alone would accept a sign, such as in `%+5`
+            let hex = tail.get(..2).filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))?;
+            bytes.push(u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::io::*;
+    use tokio::net::{TcpListener, TcpStream};
+
+    use crate::{Keeper, Registry};
+    use crate::http::{handle_http_connection, handle_metrics_connection, percent_decode};
+
+    /// Sends a raw request to a handler of `registry`, of the metrics port if
+    /// `metrics` is set, returning the head and the body of the response.
+    async fn exchange(registry: &Arc<Registry>, request: &str, metrics: bool) -> (String, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let registry = registry.clone();
+        tokio::spawn(async move {
+            let (stream, _address) = listener.accept().await.unwrap();
+            match metrics {
+                true => handle_metrics_connection(stream, registry).await,
+                false => handle_http_connection(stream, registry).await,
+            }
+        });
+
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        (head.to_string(), body.to_string())
+    }
+
+    /// Sends a raw request to the API, returning the status code, the headers
+    /// and the body of the response.
+    async fn request(registry: &Arc<Registry>, request: &str) -> (u16, String, serde_json::Value) {
+        let (head, body) = exchange(registry, request, false).await;
+        let status = head[9..12].parse().unwrap();
+        (status, head, serde_json::from_str(&body).unwrap())
+    }
+
+    fn registry() -> Arc<Registry> {
+        let registry = Registry::new();
+        registry.insert("api", Box::new(Keeper::try_new(2, 60).unwrap())).unwrap();
+        registry.insert("other", Box::new(Keeper::try_new(5, 1).unwrap())).unwrap();
+        Arc::new(registry)
+    }
+
+    #[tokio::test]
+    /// Acquiring replies with the delay and the quota left afterwards.
+    async fn acquire() {
+        let registry = registry();
+        let (status, _head, body) = request(&registry, "POST /v1/acquire/api HTTP/1.1\r\nHost: jarl\r\n\r\n").await;
+        assert_eq!(status, 200);
+        assert_eq!(body["service"], "api");
+        assert_eq!(body["delay"], 0.0);
+        assert_eq!(body["remaining"], 1);
+        let reset = body["reset"].as_f64().unwrap();
+        assert!(reset > 59.0 && reset <= 60.0, "Reset should be a period after the request.");
+
+        let (_status, _head, body) = request(
+            &registry, "POST /v1/acquire/api?cost=2 HTTP/1.1\r\nContent-Length: 2\r\n\r\n{}",
+        ).await;
+        assert!(body["delay"].as_f64().unwrap() > 0.0, "Requests beyond the limit should be delayed.");
+        assert_eq!(body["remaining"], 0);
+    }
+
+    #[tokio::test]
+    async fn list_services() {
+        let registry = registry();
+        let (status, _head, body) = request(&registry, "GET /v1/services HTTP/1.1\r\n\r\n").await;
+        assert_eq!(status, 200);
+        assert_eq!(body[0]["name"], "api");
+        assert_eq!(body[0]["algorithm"], "sliding-log");
+        assert_eq!(body[1]["name"], "other");
+        assert_eq!(body[1]["requests"], 5);
+        assert_eq!(body[1]["period"], 1);
+        assert_eq!(body[1]["remaining"], 5);
+
+        let (status, _head, body) = request(&registry, "GET /v1/services/other HTTP/1.0\r\n\r\n").await;
+        assert_eq!(status, 200);
+        assert_eq!(body["name"], "other");
+    }
+
+    #[tokio::test]
+    /// Errors are replied with a status code and a JSON body holding a stable
+    /// code and a description.
+    async fn errors() {
+        let registry = registry();
+        let cases = [
+            ("POST /v1/acquire/missing HTTP/1.1\r\n\r\n", 404, "unknown-service"),
+            ("POST /v1/acquire/api?cost=0 HTTP/1.1\r\n\r\n", 400, "invalid-cost"),
+            ("GET /v1/acquire/api HTTP/1.1\r\n\r\n", 405, "method-not-allowed"),
+            ("GET /v2/services HTTP/1.1\r\n\r\n", 404, "not-found"),
+            ("GET /v1/services HTTP/2\r\n\r\n", 400, "bad-request"),
+        ];
+
+        for (line, expected, code) in cases {
+            let (status, head, body) = request(&registry, line).await;
+            assert_eq!((status, body["error"]["code"].as_str().unwrap()), (expected, code), "{}", line);
+            if status == 405 {
+                assert!(head.lines().any(|header| header == "Allow: POST"));
+            }
+        }
+    }
+
+    #[tokio::test]
+    /// The metrics port only serves `GET /metrics`, counting the requests
+    /// recorded through every other listener.
+    async fn metrics() {
+        let registry = registry();
+        request(&registry, "POST /v1/acquire/api HTTP/1.1\r\n\r\n").await;
+
+        let (head, body) = exchange(&registry, "GET /metrics HTTP/1.1\r\n\r\n", true).await;
+        assert!(head.starts_with("HTTP/1.1 200 OK"));
+        assert!(head.lines().any(|header| header == "Content-Type: text/plain; version=0.0.4"));
+        assert!(body.lines().any(|line| line == "jarl_acquisitions_total{service=\"api\"} 1"));
+
+        let (head, _body) = exchange(&registry, "POST /metrics HTTP/1.1\r\n\r\n", true).await;
+        assert!(head.starts_with("HTTP/1.1 405"));
+        let (head, _body) = exchange(&registry, "GET /v1/services HTTP/1.1\r\n\r\n", true).await;
+        assert!(head.starts_with("HTTP/1.1 404"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    /// Clients that do not send a whole request in time are replied to with
+    /// a timeout, instead of holding the connection.
+    async fn slow_request() {
+        let (head, body) = exchange(&registry(), "POST /v1/acquire/api HTTP/1.1\r\nHost: ", false).await;
+        assert!(head.starts_with("HTTP/1.1 408"));
+        assert!(body.contains("request-timeout"));
+    }
+
+    #[test]
+    fn decode_path() {
+        assert_eq!(percent_decode("Payment%20Gateway").unwrap(), "Payment Gateway");
+        assert_eq!(percent_decode("caf%C3%A9").unwrap(), "café");
+        assert_eq!(percent_decode("100%"), None);
+        assert_eq!(percent_decode("%zz"), None);
+        assert_eq!(percent_decode("%+5"), None);
+    }
+}
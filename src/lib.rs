@@ -1,138 +1,516 @@
-use std::time::{SystemTime, UNIX_EPOCH};
-use ::bounded_vec_deque::BoundedVecDeque;
+use std::str::FromStr;
+use std::sync::Arc;
 use clap::Parser;
 
+pub mod client;
+pub mod cluster;
+pub mod clock;
+pub mod config;
+pub mod error;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod handle;
+pub mod http;
+pub mod limiter;
+pub mod logging;
+pub mod metrics;
+pub mod protocol;
+pub mod registry;
+pub mod server;
+pub mod state;
+pub mod store;
+pub mod udp;
 
+#[cfg(test)]
+mod testing;
+
+pub use clock::{Clock, ManualClock, MonotonicClock};
+pub use config::{Config, ConfigError};
+pub use error::Error;
+pub use handle::Limiter;
+pub use limiter::{Algorithm, LimiterState, Quota, RateLimiter};
+pub use metrics::ServiceMetrics;
+pub use protocol::{Command, ProtocolError, Response};
+pub use registry::{Registry, SharedLimiter, TimeKeeper};
+pub use state::{KeeperState, Snapshot};
+pub use store::{LogStore, MemoryLog, Store};
+
+use store::Window;
+
+
+
+/// Sliding log of the requests made to a service, delaying the requests
+/// beyond the limit.
+///
+/// The log is kept in memory unless given another store, in which case a
+/// request whose delay cannot be recorded waits for a full period, and is
+/// reported as a failure, unless recorded with `try_get_weighted_delay`.
 pub struct Keeper {
     limit: u32,
     period_in_secs: f64,
-    queue: BoundedVecDeque<f64>,
-    backoff_count: f32,
+    store: Box<dyn LogStore>,
     base_delay: f32,
+    /// Whether the log records the time each request was scheduled for,
+    /// instead of the time it was received
+    reserve: bool,
+    clock: Arc<dyn Clock>,
 }
 
 impl Keeper {
-    
+
+    /// Creates a Keeper allowing `limit` requests every `period` seconds.
+    /// Panics if the limit or the period is 0.
+    #[deprecated(note = "use `try_new`, which returns an error instead of panicking")]
     pub fn new(limit: u32, period: u32) -> Self {
+        Keeper::build(limit, period, false)
+    }
+
+    /// Creates a Keeper allowing `limit` requests every `period` seconds,
+    /// returning an error if the limit or the period is 0.
+    pub fn try_new(limit: u32, period: u32) -> Result<Self, Error> {
+        error::check_limit(limit, period)?;
+        Ok(Keeper::build(limit, period, false))
+    }
+
+    /// Creates a Keeper, panicking if the limit or the period is 0.
+    pub(crate) fn build(limit: u32, period: u32, reserve: bool) -> Self {
         assert!(limit > 0, "Max requests per period must be greater than 0.");
         assert!(period > 0, "Period must be greater than 0.");
 
         Keeper {
             limit,
             period_in_secs: period as f64,
-            queue: BoundedVecDeque::new((limit + 1) as usize),
-            backoff_count: 0.0,
+            store: Box::new(MemoryLog::new(limit)),
             base_delay: (period as f32 / limit as f32).max(0.01),
+            reserve,
+            clock: clock::default_clock(),
         }
     }
 
+    /// Takes the timestamps of requests from `clock` instead of the default
+    /// monotonic clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Keeps the log in `store` instead of memory, such as to share it with
+    /// other replicas. Requests already recorded are not carried over.
+    pub fn with_store(mut self, store: Box<dyn LogStore>) -> Self {
+        self.store = store;
+        self
+    }
+
+    /// Creates a Keeper in reservation mode, in which every request reserves
+    /// the next free slot within the rate limit and the queue records the
+    /// time of that slot. Requests that have to wait are also spaced by at
+    /// least `period / limit` seconds, so that they are spread over the
+    /// period instead of all being scheduled for the same moment.
+    #[deprecated(note = "use `try_reserving`, which returns an error instead of panicking")]
+    pub fn reserving(limit: u32, period: u32) -> Self {
+        Keeper::build(limit, period, true)
+    }
+
+    /// Creates a Keeper in reservation mode as `reserving` does, returning an
+    /// error if the limit or the period is 0.
+    pub fn try_reserving(limit: u32, period: u32) -> Result<Self, Error> {
+        error::check_limit(limit, period)?;
+        Ok(Keeper::build(limit, period, true))
+    }
+
+    pub fn is_reserving(&self) -> bool {
+        self.reserve
+    }
+
     pub fn get_delay(&mut self) -> f32 {
-        let time_since_epoch = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("System time error");
+        self.get_weighted_delay(1)
+    }
+
+    /// Records a request costing `cost` units of the limit, each of them
+    /// taking a timestamp in the queue. The cost is clamped between 1 and the
+    /// limit. If the store fails, the failure is reported and the request
+    /// waits for a full period.
+    pub fn get_weighted_delay(&mut self, cost: u32) -> f32 {
+        let delay = self.try_get_weighted_delay(cost);
+        self.or_period(delay)
+    }
+
+    /// Records a request as `get_weighted_delay` does, returning the error of
+    /// the store if it fails.
+    pub fn try_get_weighted_delay(&mut self, cost: u32) -> Result<f32, Error> {
+        self.delay(cost, true)
+    }
+
+    /// Returns the delay a request costing `cost` units would get, without
+    /// recording it.
+    pub fn peek_weighted_delay(&mut self, cost: u32) -> f32 {
+        let delay = self.try_peek_weighted_delay(cost);
+        self.or_period(delay)
+    }
+
+    /// Returns the delay as `peek_weighted_delay` does, or the error of the
+    /// store if it fails.
+    pub fn try_peek_weighted_delay(&mut self, cost: u32) -> Result<f32, Error> {
+        self.delay(cost, false)
+    }
+
+    fn delay(&mut self, cost: u32, record: bool) -> Result<f32, Error> {
+        let cost = cost.clamp(1, self.limit);
+        let (window, now) = (self.window(), self.clock.now());
+        match self.reserve {
+            true => self.store.reserve(&window, now, cost, record),
+            false => self.store.acquire(&window, now, cost, record),
+        }
+    }
 
-        let timestamp = time_since_epoch.as_secs() as f64 + 
-                             time_since_epoch.subsec_millis() as f64 * 0.001;
-        self.queue.push_back(timestamp);
+    fn or_period(&self, delay: Result<f32, Error>) -> f32 {
+        delay.unwrap_or_else(|error| {
+            error::report(&error);
+            self.period_in_secs as f32
+        })
+    }
+
+    fn window(&self) -> Window {
+        Window { limit: self.limit, period: self.period_in_secs, base_delay: self.base_delay }
+    }
+
+    /// Drops the timestamps of the `cost` most recent units, clamped to the
+    /// limit, along with their share of the backoff count.
+    pub fn release(&mut self, cost: u32) {
+        if let Err(error) = self.store.release(cost.min(self.limit)) {
+            error::report(&error);
+        }
+    }
+
+    /// Units whose timestamp is no longer within the period, and seconds until
+    /// the newest one is.
+    pub fn quota(&self) -> Quota {
+        let now = self.clock.now();
+        let timestamps = match self.store.state(now) {
+            Ok(state) => state.timestamps,
+            Err(error) => {
+                error::report(&error);
+                return Quota { remaining: 0, reset: self.period_in_secs as f32 };
+            }
+        };
+        let within = timestamps.iter().filter(|timestamp| now - *timestamp < self.period_in_secs).count();
+        let newest = timestamps.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+
+        Quota {
+            remaining: self.limit.saturating_sub(within as u32),
+            reset: (newest + self.period_in_secs - now).max(0.0) as f32,
+        }
+    }
+
+    /// Applies a new rate limit, keeping the most recent timestamps already
+    /// recorded so that requests made before the change still count against
+    /// the new limit. Fails if the limit or the period is 0, or if the store
+    /// could not drop the timestamps beyond the new limit, which still applies.
+    pub fn reconfigure(&mut self, limit: u32, period: u32) -> Result<(), Error> {
+        error::check_limit(limit, period)?;
+
+        self.limit = limit;
+        self.period_in_secs = period as f64;
+        self.base_delay = (period as f32 / limit as f32).max(0.01);
+        self.store.resize(limit)
+    }
+
+    pub fn backoff_count(&self) -> f32 {
+        self.store.backoff_count()
+    }
+
+    /// Requests recorded by this Keeper, to be restored after a restart.
+    pub fn state(&self) -> KeeperState {
+        self.store.state(self.clock.now()).unwrap_or_else(|error| {
+            error::report(&error);
+            KeeperState::default()
+        })
+    }
 
-        if self.queue.len() == (self.limit + 1) as usize {
-            let last = self.queue.pop_front().unwrap_or_default();
-            let diff = timestamp - last;
+    /// Replaces the recorded requests with the ones of a previous state.
+    /// Requests older than the period are dropped, and so is the backoff
+    /// count if none are left.
+    pub fn restore(&mut self, state: &KeeperState) {
+        let now = self.clock.now();
+        let mut timestamps: Vec<f64> = state.timestamps.iter()
+            .copied()
+            .filter(|timestamp| now - timestamp < self.period_in_secs)
+            .collect();
+        timestamps.sort_by(f64::total_cmp);
+
+        let excess = timestamps.len().saturating_sub(self.limit as usize);
+        timestamps.drain(..excess);
+        let backoff_count = if timestamps.is_empty() { 0.0 } else { state.backoff_count };
+        if let Err(error) = self.store.replace(&self.window(), now, &KeeperState { timestamps, backoff_count }) {
+            error::report(&error);
+        }
+    }
 
+    pub fn limit(&self) -> u32 {
+        self.limit
+    }
 
-            if diff < self.period_in_secs {
-                let adjustment = (self.period_in_secs - diff) as f32;
-                self.backoff_count += 1.0;
+    pub fn period(&self) -> u32 {
+        self.period_in_secs as u32
+    }
 
-                return self.base_delay * self.backoff_count + adjustment;
+}
+
+
+/// Rate limit of a named service, given on the CLI as
+/// `NAME=REQUESTS/PERIOD[:ALGORITHM]`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServiceSpec {
+    pub name: String,
+    pub requests: u32,
+    pub period: u32,
+    pub algorithm: Option<Algorithm>,
+}
+
+impl FromStr for ServiceSpec {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (name, limit) = value.split_once('=')
+            .ok_or_else(|| format!("expected NAME=REQUESTS/PERIOD, got `{}`", value))?;
+        let (limit, algorithm) = match limit.split_once(':') {
+            Some((limit, algorithm)) => {
+                let algorithm = <Algorithm as clap::ValueEnum>::from_str(algorithm.trim(), true)
+                    .map_err(|_| format!("unknown algorithm `{}`", algorithm))?;
+                (limit, Some(algorithm))
             }
+            None => (limit, None),
+        };
+        let (requests, period) = limit.split_once('/')
+            .ok_or_else(|| format!("expected REQUESTS/PERIOD after `=`, got `{}`", limit))?;
+
+        let name = name.trim();
+        if name.is_empty() || name.contains(char::is_whitespace) || protocol::is_command(name) {
+            return Err(format!("invalid service name `{}`", name));
+        }
+
+        let requests: u32 = requests.trim().parse()
+            .map_err(|_| format!("invalid number of requests `{}`", requests))?;
+        let period: u32 = period.trim().parse()
+            .map_err(|_| format!("invalid period `{}`", period))?;
+
+        if requests == 0 || period == 0 {
+            return Err(String::from("requests and period must be greater than 0"));
         }
 
-        self.backoff_count = 0.0;
-        0.0
+        Ok(ServiceSpec { name: name.to_string(), requests, period, algorithm })
     }
+}
 
+/// Parses file permissions given in octal on the CLI, such as `660` or `0o660`.
+fn parse_mode(value: &str) -> Result<u32, String> {
+    let digits = value.strip_prefix("0o").unwrap_or(value);
+    match u32::from_str_radix(digits, 8) {
+        Ok(mode) if mode <= 0o777 => Ok(mode),
+        _ => Err(format!("expected permissions in octal from 0 to 777, got `{}`", value)),
+    }
 }
 
 
 #[derive(Parser)]
 pub struct Cli {
-    /// Name of the service to rate-limit, not used by the code,
-    /// serving as a CLI reference only
+    /// TOML file declaring services, limits and listeners. Flags given on the
+    /// command line override the values of the file
+    #[arg(long)]
+    pub config: Option<std::path::PathBuf>,
+
+    /// Name of the service served on --port. Clients sending this name to
+    /// --named-port share the same rate limit
     #[arg(long)]
-    service: String,
+    pub service: Option<String>,
     
     /// Maximum number of requests to allow within the period, with a minimum 
     /// possible number of 1 request
-    #[arg(long)]
+    #[arg(long, requires = "service")]
     #[arg(value_parser = clap::value_parser!(u32).range(1..))]
-    pub requests: u32,
+    pub requests: Option<u32>,
     
     /// Period to enforce rate over in seconds, with a minimum possible period
     /// of 1 second
-    #[arg(long)]
+    #[arg(long, requires = "service")]
     #[arg(value_parser = clap::value_parser!(u32).range(1..))]
-    pub period: u32,
+    pub period: Option<u32>,
     
     /// Network interface to bind to, normally 0.0.0.0
+    #[arg(long, required_unless_present_any = ["config", "unix_socket"])]
+    pub ip: Option<std::net::IpAddr>,
+
+    /// Rate-limiting algorithm of --service, defaults to sliding-log
+    #[arg(long, requires = "service", value_enum)]
+    pub algorithm: Option<Algorithm>,
+
+    /// Port to bind to, from 1 to 65535. Connections to this port are served
+    /// the delay for --service without sending any data
+    #[arg(long, requires = "service")]
+    #[arg(value_parser = clap::value_parser!(u16).range(1..))]
+    pub port: Option<u16>,
+
+    /// Additional service to rate-limit, as NAME=REQUESTS/PERIOD, optionally
+    /// followed by :ALGORITHM. May be given multiple times
+    #[arg(long = "define", value_name = "NAME=REQUESTS/PERIOD[:ALGORITHM]")]
+    pub services: Vec<ServiceSpec>,
+
+    /// Port to bind to for clients sending commands of the line protocol, or
+    /// the name of the service followed by a newline before reading the delay
     #[arg(long)]
-    pub ip: std::net::IpAddr,
+    #[arg(value_parser = clap::value_parser!(u16).range(1..))]
+    pub named_port: Option<u16>,
 
-    /// Port to bind to, from 1 to 65535
+    /// Port to bind to for administrative commands, such as RELOAD
     #[arg(long)]
     #[arg(value_parser = clap::value_parser!(u16).range(1..))]
-    pub port: u16,
-}
+    pub admin_port: Option<u16>,
+
+    /// Port to bind to for the HTTP/JSON API
+    #[arg(long)]
+    #[arg(value_parser = clap::value_parser!(u16).range(1..))]
+    pub http_port: Option<u16>,
+
+    /// Port to bind to for the gRPC API. Requires building with the `grpc`
+    /// feature
+    #[arg(long)]
+    #[arg(value_parser = clap::value_parser!(u16).range(1..))]
+    pub grpc_port: Option<u16>,
 
+    /// Port to bind to for `GET /metrics`, serving per-service metrics in the
+    /// Prometheus text format
+    #[arg(long)]
+    #[arg(value_parser = clap::value_parser!(u16).range(1..))]
+    pub metrics_port: Option<u16>,
+
+    /// UDP port to bind to for datagrams holding a request id followed by a
+    /// command of the line protocol
+    #[arg(long)]
+    #[arg(value_parser = clap::value_parser!(u16).range(1..))]
+    pub udp_port: Option<u16>,
+
+    /// Unix domain socket to bind to for clients of the line protocol, served
+    /// like --named-port. A stale socket file left at this path is replaced
+    #[arg(long, value_name = "PATH")]
+    pub unix_socket: Option<std::path::PathBuf>,
+
+    /// Permissions of --unix-socket in octal, defaults to 660
+    #[arg(long, value_name = "MODE", requires = "unix_socket")]
+    #[arg(value_parser = parse_mode)]
+    pub unix_socket_mode: Option<u32>,
+
+    /// Worker threads handling connections, defaults to 1. Only gcra handles
+    /// the requests for a single service on every thread at once, the other
+    /// algorithms handle them one at a time
+    #[arg(long)]
+    #[arg(value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    pub threads: Option<usize>,
+
+    /// Seconds to wait for accepted connections to be handled after SIGTERM
+    /// or SIGINT, defaults to 10
+    #[arg(long, value_name = "SECONDS")]
+    pub shutdown_timeout: Option<u64>,
+
+    /// File to save the requests recorded by every service to, periodically
+    /// and on shutdown, and to restore them from on startup
+    #[arg(long, value_name = "PATH")]
+    pub state_file: Option<std::path::PathBuf>,
+
+    /// Seconds between saves of --state-file, defaults to 30
+    #[arg(long, value_name = "SECONDS")]
+    #[arg(value_parser = clap::value_parser!(u64).range(1..))]
+    pub state_interval: Option<u64>,
+
+    /// Address to exchange heartbeats (quorum) or leases (lease) with the
+    /// other nodes of a cluster on. The limit of every service is split
+    /// between the nodes
+    #[arg(long, value_name = "ADDRESS", requires = "peers")]
+    pub cluster_address: Option<std::net::SocketAddr>,
+
+    /// How the nodes of the cluster share the limit of every service,
+    /// defaults to quorum
+    #[arg(long, value_name = "MODE", value_enum, requires = "cluster_address")]
+    pub cluster_mode: Option<cluster::ClusterMode>,
+
+    /// Cluster address of another node of the cluster. May be given multiple
+    /// times
+    #[arg(long = "peer", value_name = "ADDRESS", requires = "cluster_address")]
+    pub peers: Vec<std::net::SocketAddr>,
+
+    /// Address of a Redis server keeping the sliding logs of the services,
+    /// shared with the other replicas of jarl using it, as HOST:PORT
+    #[arg(long, value_name = "ADDRESS")]
+    pub redis: Option<String>,
+
+    /// Verbosity of the logs written to stdout: off, error, warn, info, debug
+    /// or trace. Defaults to the JARL_LOG environment variable, or else info
+    #[arg(long, value_name = "LEVEL")]
+    pub log_level: Option<tracing::level_filters::LevelFilter>,
+
+    /// Format of the logs
+    #[arg(long, value_enum, default_value_t)]
+    pub log_format: logging::LogFormat,
+}
 
 // Unit tests
 #[cfg(test)]
 mod tests {
-    use std::thread::sleep;
+    use std::sync::Arc;
     use std::time::Duration;
 
-    use crate::Keeper;
+    use crate::{parse_mode, Algorithm, Error, Keeper, KeeperState, ManualClock, ServiceSpec};
 
     #[test]
     /// The base delay is the maximum value between the expected average time for each
     /// request within the period (max requests / period) and 0.01.
     fn base_delay_values() {
-        let keeper_1 = Keeper::new(1, 1);
+        let keeper_1 = Keeper::try_new(1, 1).unwrap();
         assert_eq!(keeper_1.base_delay, 1.0);
 
-        let keeper_2 = Keeper::new(10, 1);
+        let keeper_2 = Keeper::try_new(10, 1).unwrap();
         assert_eq!(keeper_2.base_delay, 0.1);
 
-        let keeper_3 = Keeper::new(10000, 1);
+        let keeper_3 = Keeper::try_new(10000, 1).unwrap();
         assert_eq!(keeper_3.base_delay, 0.01);
     }
 
     #[test]
     #[should_panic]
+    #[allow(deprecated)]
     fn reject_period_of_zero() {
         let _keeper = Keeper::new(1, 0);
     }
 
     #[test]
     #[should_panic]
+    #[allow(deprecated)]
     fn reject_max_zero_requests() {
         let _keeper = Keeper::new(0, 1);
     }
 
+    #[test]
+    fn try_new() {
+        assert!(Keeper::try_new(1, 1).is_ok());
+        assert!(matches!(Keeper::try_new(0, 1), Err(Error::ZeroLimit)));
+        assert!(matches!(Keeper::try_new(1, 0), Err(Error::ZeroPeriod)));
+    }
+
     #[test]
     /// Ensure functionality for one request per second scenario
     fn minimum_rate() {
-        let mut keeper = Keeper::new(1, 1);
+        let clock = ManualClock::new(100.0);
+        let mut keeper = Keeper::try_new(1, 1).unwrap().with_clock(Arc::new(clock.clone()));
 
         keeper.get_delay();
-        // Expect second request within a second to return some delay > 0
+        // Expect second request within a second to return the rest of the
+        // period plus the base delay
         let delay_1 = keeper.get_delay();
-        assert!(delay_1 > 0.0, "Delay should be greater than 0.");
+        assert_eq!(delay_1, 2.0);
 
         // By waiting the delay, Keeper should reset after a new get_delay call
-        sleep(Duration::from_millis((delay_1 * 1000.0) as u64));
+        clock.advance(Duration::from_secs_f32(delay_1));
         let delay_2 = keeper.get_delay();
-        assert!(keeper.backoff_count == 0.0, "Backoff count should have reset.");
+        assert!(keeper.backoff_count() == 0.0, "Backoff count should have reset.");
 
         // After the reset, the delay returned should be 0
         assert!(delay_2 == 0.0, "Delay should be 0 after a reset.");
@@ -141,23 +519,182 @@ mod tests {
     #[test]
     /// Ensure functionality for a more common scenario (requests > 1 and period > 1)
     fn normal_rate() {
-        let mut keeper = Keeper::new(100, 5);
+        let clock = ManualClock::new(100.0);
+        let mut keeper = Keeper::try_new(100, 5).unwrap().with_clock(Arc::new(clock.clone()));
 
         for _ in 0..100 {
             assert!(keeper.get_delay() == 0.0, "Delay for requests within rate limit should be 0.");
         }
-        // Expect 101st request within period to return some delay > 0
+        // Expect 101st request within period to return the rest of the period
+        // plus the base delay
         let delay_1 = keeper.get_delay();
-        assert!(delay_1 > 0.0, "Delay should be greater than 0.");
+        assert_eq!(delay_1, 5.05);
 
         // By waiting the delay, Keeper should reset after a new get_delay call
-        sleep(Duration::from_millis((delay_1 * 1000.0) as u64));
+        clock.advance(Duration::from_secs_f32(delay_1));
         let delay_2 = keeper.get_delay();
-        assert!(keeper.backoff_count == 0.0, "Backoff count should have reset.");
+        assert!(keeper.backoff_count() == 0.0, "Backoff count should have reset.");
 
         // After the reset, the delay returned should be 0
         assert!(delay_2 == 0.0, "Delay should be 0 after a reset.");
     }
 
+    #[test]
+    /// Each unit of a weighted request takes a timestamp, and the request
+    /// waits until enough of the older ones are a period old.
+    fn weighted_requests() {
+        let clock = ManualClock::new(0.0);
+        let mut keeper = Keeper::try_new(10, 10).unwrap().with_clock(Arc::new(clock.clone()));
+        assert_eq!(keeper.get_weighted_delay(8), 0.0);
+
+        // Two units of the first request must expire: 5 seconds left, plus a
+        // base delay of 1 second for each of the 4 units
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(keeper.get_weighted_delay(4), 9.0);
+
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(keeper.get_weighted_delay(2), 0.0);
+        assert_eq!(keeper.backoff_count(), 0.0);
+
+        // Costs beyond the limit count as the limit
+        assert_eq!(keeper.get_weighted_delay(100), 10.0 + 10.0);
+        assert_eq!(keeper.state().timestamps.len(), 10);
+    }
+
+    #[test]
+    /// An hour of traffic at exactly the rate limit is never delayed, while
+    /// one extra request per period always is.
+    fn simulated_hour() {
+        let clock = ManualClock::new(0.0);
+        let mut keeper = Keeper::try_new(60, 60).unwrap().with_clock(Arc::new(clock.clone()));
+
+        for _ in 0..3600 {
+            assert_eq!(keeper.get_delay(), 0.0);
+            clock.advance(Duration::from_secs(1));
+        }
+
+        let mut delayed = 0;
+        for second in 0..3600 {
+            if keeper.get_delay() > 0.0 {
+                delayed += 1;
+            }
+            if second % 60 != 0 {
+                clock.advance(Duration::from_secs(1));
+            }
+        }
+        assert!(delayed >= 60, "Requests beyond the limit should be delayed.");
+    }
+
+    #[test]
+    /// Reconfiguring a Keeper keeps the requests already made within the period.
+    fn reconfigure_keeps_timestamps() {
+        let mut keeper = Keeper::try_new(3, 60).unwrap();
+        for _ in 0..3 {
+            keeper.get_delay();
+        }
+
+        // Raising the limit lets new requests through
+        keeper.reconfigure(4, 60).unwrap();
+        assert_eq!(keeper.state().timestamps.len(), 3);
+        assert_eq!(keeper.get_delay(), 0.0);
+        assert!(keeper.get_delay() > 0.0, "Delay should be greater than 0 after the new limit.");
+
+        // Lowering the limit keeps the newest timestamps, and the next request is throttled
+        keeper.reconfigure(2, 30).unwrap();
+        assert_eq!(keeper.state().timestamps.len(), 2);
+        assert_eq!(keeper.base_delay, 15.0);
+        assert!(keeper.get_delay() > 0.0, "Delay should be greater than 0 after lowering the limit.");
+        assert_eq!((keeper.limit(), keeper.period()), (2, 30));
+
+        assert!(matches!(keeper.reconfigure(0, 30), Err(Error::ZeroLimit)));
+        assert!(matches!(keeper.reconfigure(2, 0), Err(Error::ZeroPeriod)));
+        assert_eq!((keeper.limit(), keeper.period()), (2, 30));
+    }
+
+    #[test]
+    /// In reservation mode, requests beyond the limit are scheduled for the
+    /// slots freed by the oldest requests, and spaced by `period / limit`.
+    fn reservation_slots() {
+        let clock = ManualClock::new(100.0);
+        let mut keeper = Keeper::try_reserving(4, 2).unwrap().with_clock(Arc::new(clock.clone()));
+        for _ in 0..4 {
+            assert_eq!(keeper.get_delay(), 0.0);
+        }
+
+        assert_eq!(keeper.get_delay(), 2.0);
+        assert_eq!(keeper.get_delay(), 2.5);
+        assert_eq!(keeper.get_delay(), 3.0);
+        assert_eq!(keeper.get_delay(), 3.5);
+        assert_eq!(keeper.get_delay(), 4.0);
+        assert_eq!(keeper.state().timestamps, [102.5, 103.0, 103.5, 104.0]);
+
+        // Slots are freed as time passes
+        clock.set(106.5);
+        assert_eq!(keeper.get_delay(), 0.0);
+        assert_eq!(keeper.backoff_count(), 0.0);
+
+        // Every unit of a weighted request reserves the same slot
+        let mut keeper = Keeper::try_reserving(4, 2).unwrap().with_clock(Arc::new(ManualClock::new(100.0)));
+        assert_eq!(keeper.get_weighted_delay(3), 0.0);
+        assert_eq!(keeper.get_weighted_delay(2), 2.0);
+        assert_eq!(keeper.get_delay(), 2.5);
+        assert_eq!(keeper.state().timestamps, [100.0, 102.0, 102.0, 102.5]);
+    }
+
+    #[test]
+    /// Restoring a state drops the requests older than the period.
+    fn restore_state() {
+        let mut keeper = Keeper::try_new(2, 10).unwrap();
+        keeper.get_delay();
+        keeper.get_delay();
+        keeper.get_delay();
+
+        let state = keeper.state();
+        assert_eq!(state.timestamps.len(), 2);
+        assert!(state.backoff_count > 0.0);
+
+        let mut restored = Keeper::try_new(2, 10).unwrap();
+        restored.restore(&state);
+        assert_eq!(restored.state(), state);
+
+        let now = state.timestamps[1];
+        let expired = KeeperState { timestamps: vec![now - 60.0, now - 30.0, now], backoff_count: 3.0 };
+        restored.restore(&expired);
+        assert_eq!(restored.state().timestamps.len(), 1);
+        assert_eq!(restored.backoff_count(), 3.0);
+
+        let expired = KeeperState { timestamps: vec![now - 60.0], backoff_count: 3.0 };
+        restored.restore(&expired);
+        assert!(restored.state().timestamps.is_empty());
+        assert_eq!(restored.backoff_count(), 0.0, "Backoff count should reset without requests.");
+    }
+
+    #[test]
+    fn parse_service_spec() {
+        let spec: ServiceSpec = "payments=100/1".parse().unwrap();
+        assert_eq!(spec, ServiceSpec { name: String::from("payments"), requests: 100, period: 1, algorithm: None });
+
+        let spec: ServiceSpec = "payments=100/1:token-bucket".parse().unwrap();
+        assert_eq!(spec.algorithm, Some(Algorithm::TokenBucket));
+
+        assert!("payments".parse::<ServiceSpec>().is_err());
+        assert!("payments=100".parse::<ServiceSpec>().is_err());
+        assert!("payments=0/1".parse::<ServiceSpec>().is_err());
+        assert!("payments=1/0".parse::<ServiceSpec>().is_err());
+        assert!("=1/1".parse::<ServiceSpec>().is_err());
+        assert!("acquire=1/1".parse::<ServiceSpec>().is_err());
+        assert!("payments=1/1:magic".parse::<ServiceSpec>().is_err());
+    }
+
+    #[test]
+    fn parse_socket_mode() {
+        assert_eq!(parse_mode("660"), Ok(0o660));
+        assert_eq!(parse_mode("0o600"), Ok(0o600));
+        assert_eq!(parse_mode("0777"), Ok(0o777));
+        assert!(parse_mode("1000").is_err());
+        assert!(parse_mode("rw").is_err());
+        assert!(parse_mode("").is_err());
+    }
+
 
 }
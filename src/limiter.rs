@@ -0,0 +1,409 @@
+use std::fmt;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{clock, error, Clock, Error, Keeper, KeeperState};
+
+mod fixed_window;
+mod gcra;
+mod leaky_bucket;
+mod sliding_window;
+mod token_bucket;
+
+pub use fixed_window::{FixedWindow, WindowCount};
+pub use gcra::Gcra;
+pub use leaky_bucket::LeakyBucket;
+pub use sliding_window::SlidingWindowCounter;
+pub use token_bucket::TokenBucket;
+
+
+/// Calculates how long a request must wait so that no more than `limit`
+/// requests are made to a service within `period` seconds.
+///
+/// Every call to `get_delay` is counted as a request that will be made once
+/// the returned delay has passed.
+pub trait RateLimiter: Send + Sync {
+
+    fn algorithm(&self) -> Algorithm;
+
+    /// Records a new request costing `cost` units of the limit, returning the
+    /// number of seconds to wait until all of them are free. The cost is
+    /// clamped between 1 and the limit, as a request costing more than the
+    /// limit could never be made within a single period.
+    fn get_weighted_delay(&mut self, cost: u32) -> f32;
+
+    /// Records a new request as `get_weighted_delay` does, returning an error
+    /// instead of a full period if the external store of the requests fails.
+    fn try_get_weighted_delay(&mut self, cost: u32) -> Result<f32, Error> {
+        Ok(self.get_weighted_delay(cost))
+    }
+
+    /// Records a new request as `get_weighted_delay` does, without exclusive
+    /// access to the rate limiter, so that requests for the same service can
+    /// be handled by several threads at once. Only algorithms keeping their
+    /// state in atomics support it, the others return `None`.
+    fn get_weighted_delay_shared(&self, _cost: u32) -> Option<f32> {
+        None
+    }
+
+    /// Records a new request costing a single unit, returning the number of
+    /// seconds to wait before making it.
+    fn get_delay(&mut self) -> f32 {
+        self.get_weighted_delay(1)
+    }
+
+    /// Returns the delay a request costing `cost` units would get, without
+    /// recording it.
+    fn peek_weighted_delay(&mut self, cost: u32) -> f32 {
+        let state = self.state();
+        let delay = self.get_weighted_delay(cost);
+        self.restore(&state);
+        delay
+    }
+
+    /// Returns the delay as `peek_weighted_delay` does, or an error if the
+    /// external store of the requests fails.
+    fn try_peek_weighted_delay(&mut self, cost: u32) -> Result<f32, Error> {
+        Ok(self.peek_weighted_delay(cost))
+    }
+
+    /// Gives back `cost` units of the most recent requests, such as when a
+    /// request was not made after all. The cost is clamped to the limit.
+    fn release(&mut self, cost: u32);
+
+    /// Units that can be acquired right now without a delay, and seconds
+    /// until every recorded unit is freed.
+    fn quota(&self) -> Quota;
+
+    /// Applies a new rate limit, keeping as much of the recorded requests as
+    /// the algorithm allows. Fails if the limit or the period is 0.
+    fn reconfigure(&mut self, limit: u32, period: u32) -> Result<(), Error>;
+
+    fn limit(&self) -> u32;
+
+    fn period(&self) -> u32;
+
+    /// Units the delay of the next delayed request backs off by, for the
+    /// algorithms increasing the delay of consecutive delayed requests.
+    fn backoff_count(&self) -> f32 {
+        0.0
+    }
+
+    /// Recorded requests, to be restored after a restart.
+    fn state(&self) -> LimiterState;
+
+    /// Replaces the recorded requests with the ones of a previous state. States
+    /// of a different algorithm are ignored.
+    fn restore(&mut self, state: &LimiterState);
+
+}
+
+
+/// What is left of the limit of a rate limiter.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct Quota {
+    /// Units that can be acquired without a delay
+    pub remaining: u32,
+    /// Seconds until the whole limit is available again
+    pub reset: f32,
+}
+
+
+/// Rate-limiting algorithm used by a service.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Algorithm {
+    /// Log of the timestamps of the last `requests` requests, with a linear
+    /// penalty for every request beyond the limit.
+    #[default]
+    SlidingLog,
+    /// Bucket of `requests` tokens, refilled continuously over the period.
+    /// Allows bursts of up to `requests` requests.
+    TokenBucket,
+    /// Queue draining at a constant rate, spacing every request by
+    /// `period / requests` seconds. Does not allow bursts.
+    LeakyBucket,
+    /// Counter of requests reset at the start of every period.
+    FixedWindow,
+    /// Counters of the current and previous periods, weighted by how much of
+    /// the previous period still overlaps the last `period` seconds.
+    SlidingWindow,
+    /// Generic cell rate algorithm, tracking the theoretical arrival time of
+    /// the next request. Allows bursts of up to `requests` requests. The only
+    /// algorithm recording requests without a lock.
+    Gcra,
+    /// Log of the slots reserved by the last `requests` requests. Requests
+    /// beyond the limit reserve the next free slot, spaced by at least
+    /// `period / requests` seconds.
+    Reservation,
+}
+
+impl Algorithm {
+
+    /// Builds an empty rate limiter of this algorithm.
+    pub fn build(self, limit: u32, period: u32) -> Box<dyn RateLimiter> {
+        self.build_with_clock(limit, period, clock::default_clock())
+    }
+
+    /// Builds an empty rate limiter of this algorithm, returning an error
+    /// instead of panicking if the limit or the period is 0.
+    pub fn try_build(self, limit: u32, period: u32) -> Result<Box<dyn RateLimiter>, Error> {
+        error::check_limit(limit, period)?;
+        Ok(self.build(limit, period))
+    }
+
+    /// Builds an empty rate limiter of this algorithm, taking the current time
+    /// from `clock`.
+    pub fn build_with_clock(self, limit: u32, period: u32, clock: Arc<dyn Clock>) -> Box<dyn RateLimiter> {
+        match self {
+            Algorithm::SlidingLog => Box::new(Keeper::build(limit, period, false).with_clock(clock)),
+            Algorithm::TokenBucket => Box::new(TokenBucket::new(limit, period).with_clock(clock)),
+            Algorithm::LeakyBucket => Box::new(LeakyBucket::new(limit, period).with_clock(clock)),
+            Algorithm::FixedWindow => Box::new(FixedWindow::new(limit, period).with_clock(clock)),
+            Algorithm::SlidingWindow => Box::new(SlidingWindowCounter::new(limit, period).with_clock(clock)),
+            Algorithm::Gcra => Box::new(Gcra::new(limit, period).with_clock(clock)),
+            Algorithm::Reservation => Box::new(Keeper::build(limit, period, true).with_clock(clock)),
+        }
+    }
+
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Algorithm::SlidingLog => "sliding-log",
+            Algorithm::TokenBucket => "token-bucket",
+            Algorithm::LeakyBucket => "leaky-bucket",
+            Algorithm::FixedWindow => "fixed-window",
+            Algorithm::SlidingWindow => "sliding-window",
+            Algorithm::Gcra => "gcra",
+            Algorithm::Reservation => "reservation",
+        };
+        f.write_str(name)
+    }
+}
+
+
+/// Recorded requests of a rate limiter, tagged with its algorithm.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "algorithm", rename_all = "kebab-case")]
+pub enum LimiterState {
+    SlidingLog(KeeperState),
+    TokenBucket {
+        /// Tokens left at `updated`, negative if requests are waiting for them
+        tokens: f64,
+        updated: f64,
+    },
+    LeakyBucket {
+        /// Timestamp at which the next request can be made
+        next: f64,
+    },
+    FixedWindow {
+        windows: Vec<WindowCount>,
+    },
+    SlidingWindow {
+        windows: Vec<WindowCount>,
+    },
+    Gcra {
+        /// Theoretical arrival time of the next request
+        tat: f64,
+    },
+    Reservation(KeeperState),
+}
+
+
+impl LimiterState {
+
+    /// Applies `convert` to every timestamp of the state, such as when moving
+    /// it between the monotonic and UNIX clocks.
+    pub fn map_timestamps(&self, convert: impl Fn(f64) -> f64) -> Self {
+        let windows = |windows: &[WindowCount]| windows.iter()
+            .map(|window| WindowCount { start: convert(window.start), count: window.count })
+            .collect();
+
+        match self {
+            LimiterState::SlidingLog(state) => LimiterState::SlidingLog(state.map_timestamps(&convert)),
+            LimiterState::TokenBucket { tokens, updated } =>
+                LimiterState::TokenBucket { tokens: *tokens, updated: convert(*updated) },
+            LimiterState::LeakyBucket { next } => LimiterState::LeakyBucket { next: convert(*next) },
+            LimiterState::FixedWindow { windows: counts } => LimiterState::FixedWindow { windows: windows(counts) },
+            LimiterState::SlidingWindow { windows: counts } => LimiterState::SlidingWindow { windows: windows(counts) },
+            LimiterState::Gcra { tat } => LimiterState::Gcra { tat: convert(*tat) },
+            LimiterState::Reservation(state) => LimiterState::Reservation(state.map_timestamps(&convert)),
+        }
+    }
+
+}
+
+
+impl RateLimiter for Keeper {
+
+    fn algorithm(&self) -> Algorithm {
+        if self.is_reserving() { Algorithm::Reservation } else { Algorithm::SlidingLog }
+    }
+
+    fn get_weighted_delay(&mut self, cost: u32) -> f32 {
+        Keeper::get_weighted_delay(self, cost)
+    }
+
+    fn try_get_weighted_delay(&mut self, cost: u32) -> Result<f32, Error> {
+        Keeper::try_get_weighted_delay(self, cost)
+    }
+
+    fn peek_weighted_delay(&mut self, cost: u32) -> f32 {
+        Keeper::peek_weighted_delay(self, cost)
+    }
+
+    fn try_peek_weighted_delay(&mut self, cost: u32) -> Result<f32, Error> {
+        Keeper::try_peek_weighted_delay(self, cost)
+    }
+
+    fn release(&mut self, cost: u32) {
+        Keeper::release(self, cost)
+    }
+
+    fn quota(&self) -> Quota {
+        Keeper::quota(self)
+    }
+
+    fn reconfigure(&mut self, limit: u32, period: u32) -> Result<(), Error> {
+        Keeper::reconfigure(self, limit, period)
+    }
+
+    fn limit(&self) -> u32 {
+        Keeper::limit(self)
+    }
+
+    fn period(&self) -> u32 {
+        Keeper::period(self)
+    }
+
+    fn backoff_count(&self) -> f32 {
+        Keeper::backoff_count(self)
+    }
+
+    fn state(&self) -> LimiterState {
+        match self.is_reserving() {
+            false => LimiterState::SlidingLog(Keeper::state(self)),
+            true => LimiterState::Reservation(Keeper::state(self)),
+        }
+    }
+
+    fn restore(&mut self, state: &LimiterState) {
+        match (self.is_reserving(), state) {
+            (false, LimiterState::SlidingLog(state)) | (true, LimiterState::Reservation(state)) => {
+                Keeper::restore(self, state);
+            }
+            _ => {}
+        }
+    }
+
+}
+
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use clap::ValueEnum;
+
+    use crate::ManualClock;
+    use crate::limiter::{Algorithm, LimiterState, Quota, WindowCount};
+
+    #[test]
+    /// Every algorithm lets the first `limit` requests through and restores
+    /// only states of its own kind.
+    fn build_every_algorithm() {
+        for algorithm in Algorithm::value_variants() {
+            let mut limiter = algorithm.build(3, 60);
+            assert_eq!(limiter.algorithm(), *algorithm);
+            assert_eq!((limiter.limit(), limiter.period()), (3, 60));
+
+            for _ in 0..3 {
+                let delay = limiter.get_delay();
+                if *algorithm == Algorithm::LeakyBucket {
+                    assert!(delay <= 40.0, "{} should space requests by 20 seconds.", algorithm);
+                } else {
+                    assert_eq!(delay, 0.0, "{} should not delay requests within the limit.", algorithm);
+                }
+            }
+            assert!(limiter.get_delay() > 0.0, "{} should delay requests beyond the limit.", algorithm);
+
+            let state = limiter.state();
+            let mut restored = algorithm.build(3, 60);
+            restored.restore(&state);
+            assert_eq!(restored.state(), state, "{} should restore its own state.", algorithm);
+
+            let other = Algorithm::value_variants().iter().find(|other| *other != algorithm).unwrap();
+            let before = restored.state();
+            restored.restore(&other.build(3, 60).state());
+            assert_eq!(restored.state(), before, "{} should ignore states of {}.", algorithm, other);
+        }
+    }
+
+    #[test]
+    /// A request costing the whole limit uses it up, and costs beyond the
+    /// limit count as the limit.
+    fn weighted_requests() {
+        for algorithm in Algorithm::value_variants() {
+            let clock = ManualClock::new(100.0);
+            let mut limiter = algorithm.build_with_clock(4, 60, Arc::new(clock.clone()));
+            assert_eq!(limiter.get_weighted_delay(4), 0.0, "{} should let the first request through.", algorithm);
+            assert!(limiter.get_delay() > 0.0, "{} should delay requests beyond the limit.", algorithm);
+
+            let mut first = algorithm.build_with_clock(4, 60, Arc::new(clock.clone()));
+            let mut second = algorithm.build_with_clock(4, 60, Arc::new(clock.clone()));
+            first.get_weighted_delay(4);
+            second.get_weighted_delay(100);
+            assert_eq!(first.state(), second.state(), "{} should clamp costs to the limit.", algorithm);
+        }
+    }
+
+    #[test]
+    /// Every algorithm reports its whole limit as remaining until a request
+    /// is made, and none once a request is delayed.
+    fn quota() {
+        for algorithm in Algorithm::value_variants() {
+            let clock = ManualClock::new(100.0);
+            let mut limiter = algorithm.build_with_clock(4, 60, Arc::new(clock.clone()));
+            let remaining = if *algorithm == Algorithm::LeakyBucket { 1 } else { 4 };
+            assert_eq!(limiter.quota(), Quota { remaining, reset: 0.0 }, "{} should start with a full quota.", algorithm);
+
+            limiter.get_weighted_delay(4);
+            limiter.get_delay();
+            let quota = limiter.quota();
+            assert_eq!(quota.remaining, 0, "{} should have no units left.", algorithm);
+            assert!(quota.reset > 0.0, "{} should take time to reset.", algorithm);
+
+            clock.advance(std::time::Duration::from_secs_f32(quota.reset));
+            assert_eq!(limiter.quota().remaining, remaining, "{} should be reset after {}s.", algorithm, quota.reset);
+        }
+    }
+
+    #[test]
+    fn algorithm_names() {
+        for algorithm in Algorithm::value_variants() {
+            let name = algorithm.to_string();
+            assert_eq!(Algorithm::from_str(&name, false).unwrap(), *algorithm);
+
+            let parsed: Algorithm = toml::from_str::<toml::Value>(&format!("a = \"{}\"", name))
+                .unwrap()["a"].clone().try_into().unwrap();
+            assert_eq!(parsed, *algorithm);
+        }
+    }
+
+    #[test]
+    fn map_timestamps() {
+        let state = LimiterState::FixedWindow { windows: vec![WindowCount { start: 10.0, count: 2 }] };
+        let expected = LimiterState::FixedWindow { windows: vec![WindowCount { start: 15.0, count: 2 }] };
+        assert_eq!(state.map_timestamps(|timestamp| timestamp + 5.0), expected);
+    }
+
+    #[test]
+    fn state_format() {
+        let state = LimiterState::Gcra { tat: 10.5 };
+        assert_eq!(toml::to_string(&state).unwrap(), "algorithm = \"gcra\"\ntat = 10.5\n");
+    }
+}
@@ -0,0 +1,273 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::clock::{self, Clock};
+use crate::error::{self, Error};
+use crate::limiter::{Algorithm, LimiterState, Quota, RateLimiter};
+
+
+/// Number of requests made within the window starting at `start`.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub struct WindowCount {
+    pub start: f64,
+    pub count: u32,
+}
+
+
+/// Request counters of consecutive windows of `period` seconds, aligned to
+/// multiples of the period. Windows after the current one hold requests that
+/// were delayed into them.
+pub(crate) struct Windows {
+    period_in_secs: f64,
+    first: i64,
+    counts: VecDeque<u32>,
+}
+
+impl Windows {
+
+    pub(crate) fn new(period: f64) -> Self {
+        Windows { period_in_secs: period, first: 0, counts: VecDeque::new() }
+    }
+
+    pub(crate) fn period_in_secs(&self) -> f64 {
+        self.period_in_secs
+    }
+
+    pub(crate) fn index(&self, timestamp: f64) -> i64 {
+        (timestamp / self.period_in_secs).floor() as i64
+    }
+
+    pub(crate) fn start(&self, index: i64) -> f64 {
+        index as f64 * self.period_in_secs
+    }
+
+    pub(crate) fn count(&self, index: i64) -> u32 {
+        usize::try_from(index - self.first).ok()
+            .and_then(|position| self.counts.get(position))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    pub(crate) fn add(&mut self, index: i64, count: u32) {
+        if self.counts.is_empty() {
+            self.first = index;
+        }
+        while index < self.first {
+            self.counts.push_front(0);
+            self.first -= 1;
+        }
+
+        let position = (index - self.first) as usize;
+        if position >= self.counts.len() {
+            self.counts.resize(position + 1, 0);
+        }
+        self.counts[position] += count;
+    }
+
+    /// Start of the latest window holding requests.
+    pub(crate) fn last_start(&self) -> Option<f64> {
+        self.counts.iter()
+            .rposition(|count| *count > 0)
+            .map(|position| self.start(self.first + position as i64))
+    }
+
+    /// Removes `count` requests, starting with the latest windows.
+    pub(crate) fn remove(&mut self, mut count: u32) {
+        while let Some(last) = self.counts.back_mut() {
+            let removed = count.min(*last);
+            *last -= removed;
+            count -= removed;
+
+            if *last > 0 {
+                break;
+            }
+            self.counts.pop_back();
+        }
+    }
+
+    /// Drops the windows before `index`.
+    pub(crate) fn expire(&mut self, index: i64) {
+        while index > self.first && !self.counts.is_empty() {
+            self.counts.pop_front();
+            self.first += 1;
+        }
+    }
+
+    /// Moves the recorded requests to the windows of a new period.
+    pub(crate) fn set_period(&mut self, period: f64) {
+        let state = self.state();
+        self.period_in_secs = period;
+        self.counts.clear();
+        for window in state {
+            let index = self.index(window.start);
+            self.add(index, window.count);
+        }
+    }
+
+    pub(crate) fn state(&self) -> Vec<WindowCount> {
+        self.counts.iter()
+            .zip(self.first..)
+            .filter(|(count, _)| **count > 0)
+            .map(|(count, index)| WindowCount { start: self.start(index), count: *count })
+            .collect()
+    }
+
+    pub(crate) fn restore(&mut self, windows: &[WindowCount]) {
+        self.counts.clear();
+        for window in windows {
+            // Starts moved between clocks may be slightly off the window boundary
+            let index = (window.start / self.period_in_secs).round() as i64;
+            self.add(index, window.count);
+        }
+    }
+
+}
+
+
+/// Counter of the requests made within the current window of `period`
+/// seconds. Once `limit` units are counted, further requests are delayed to
+/// the start of the first window with room left for their cost.
+pub struct FixedWindow {
+    limit: u32,
+    windows: Windows,
+    clock: Arc<dyn Clock>,
+}
+
+impl FixedWindow {
+
+    pub fn new(limit: u32, period: u32) -> Self {
+        assert!(limit > 0, "Max requests per period must be greater than 0.");
+        assert!(period > 0, "Period must be greater than 0.");
+
+        FixedWindow {
+            limit,
+            windows: Windows::new(period as f64),
+            clock: clock::default_clock(),
+        }
+    }
+
+    /// Takes the current time from `clock` instead of the default monotonic
+    /// clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    fn delay_at(&mut self, now: f64, cost: u32) -> f32 {
+        let mut index = self.windows.index(now);
+        self.windows.expire(index);
+
+        while self.windows.count(index) + cost > self.limit {
+            index += 1;
+        }
+        self.windows.add(index, cost);
+
+        (self.windows.start(index) - now).max(0.0) as f32
+    }
+
+}
+
+impl RateLimiter for FixedWindow {
+
+    fn algorithm(&self) -> Algorithm {
+        Algorithm::FixedWindow
+    }
+
+    fn get_weighted_delay(&mut self, cost: u32) -> f32 {
+        self.delay_at(self.clock.now(), cost.clamp(1, self.limit))
+    }
+
+    fn release(&mut self, cost: u32) {
+        self.windows.remove(cost.min(self.limit));
+    }
+
+    fn quota(&self) -> Quota {
+        let now = self.clock.now();
+        let index = self.windows.index(now);
+        let end = self.windows.last_start().map_or(now, |start| start + self.windows.period_in_secs());
+
+        Quota {
+            remaining: self.limit.saturating_sub(self.windows.count(index)),
+            reset: (end - now).max(0.0) as f32,
+        }
+    }
+
+    fn reconfigure(&mut self, limit: u32, period: u32) -> Result<(), Error> {
+        error::check_limit(limit, period)?;
+
+        self.limit = limit;
+        self.windows.set_period(period as f64);
+        Ok(())
+    }
+
+    fn limit(&self) -> u32 {
+        self.limit
+    }
+
+    fn period(&self) -> u32 {
+        self.windows.period_in_secs() as u32
+    }
+
+    fn state(&self) -> LimiterState {
+        LimiterState::FixedWindow { windows: self.windows.state() }
+    }
+
+    fn restore(&mut self, state: &LimiterState) {
+        if let LimiterState::FixedWindow { windows } = state {
+            self.windows.restore(windows);
+        }
+    }
+
+}
+
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use crate::RateLimiter;
+    use crate::limiter::{FixedWindow, WindowCount};
+
+    #[test]
+    /// Requests beyond the limit are delayed to the next windows with room.
+    fn delay_to_next_window() {
+        let mut window = FixedWindow::new(2, 10);
+        assert_eq!(window.delay_at(101.0, 1), 0.0);
+        assert_eq!(window.delay_at(102.0, 1), 0.0);
+        assert_eq!(window.delay_at(105.0, 1), 5.0);
+        assert_eq!(window.delay_at(105.0, 1), 5.0);
+        assert_eq!(window.delay_at(105.0, 1), 15.0);
+
+        // The next window is already full with the delayed requests
+        assert_eq!(window.delay_at(111.0, 1), 9.0);
+        assert_eq!(window.delay_at(135.0, 1), 0.0);
+    }
+
+    #[test]
+    /// Weighted requests are delayed to the first window with room for their
+    /// whole cost.
+    fn weighted_requests() {
+        let mut window = FixedWindow::new(4, 10);
+        assert_eq!(window.delay_at(101.0, 3), 0.0);
+        assert_eq!(window.delay_at(102.0, 2), 8.0);
+        assert_eq!(window.delay_at(103.0, 1), 0.0);
+        assert_eq!(window.delay_at(103.0, 3), 17.0);
+
+        // Lighter requests still fit in the windows skipped by heavier ones
+        assert_eq!(window.delay_at(103.0, 1), 7.0);
+    }
+
+    #[test]
+    /// Changing the period moves the recorded requests to the windows
+    /// containing them.
+    fn change_period() {
+        let mut window = FixedWindow::new(1, 10);
+        assert_eq!(window.delay_at(105.0, 1), 0.0);
+        assert_eq!(window.delay_at(105.0, 1), 5.0);
+        window.reconfigure(2, 20).unwrap();
+
+        assert_eq!(window.windows.state(), vec![WindowCount { start: 100.0, count: 2 }]);
+        assert_eq!(window.delay_at(106.0, 1), 14.0);
+    }
+}
@@ -0,0 +1,176 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::clock::{self, Clock};
+use crate::error::{self, Error};
+use crate::limiter::{Algorithm, LimiterState, Quota, RateLimiter};
+
+
+/// Generic cell rate algorithm. Instead of counting requests, it tracks the
+/// theoretical arrival time (TAT) of the next request if requests were evenly
+/// spaced by `period / limit` seconds, and lets requests through as long as
+/// they are no more than `limit - 1` intervals ahead of it.
+///
+/// The TAT is the whole state, so it is kept in an atomic and requests can be
+/// recorded by several threads at once without a lock.
+pub struct Gcra {
+    limit: u32,
+    period_in_secs: f64,
+    /// Bits of the TAT, as an `f64`
+    tat: AtomicU64,
+    clock: Arc<dyn Clock>,
+}
+
+impl Gcra {
+
+    pub fn new(limit: u32, period: u32) -> Self {
+        assert!(limit > 0, "Max requests per period must be greater than 0.");
+        assert!(period > 0, "Period must be greater than 0.");
+
+        Gcra {
+            limit,
+            period_in_secs: period as f64,
+            tat: AtomicU64::new(f64::NEG_INFINITY.to_bits()),
+            clock: clock::default_clock(),
+        }
+    }
+
+    /// Takes the current time from `clock` instead of the default monotonic
+    /// clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    fn interval(&self) -> f64 {
+        self.period_in_secs / self.limit as f64
+    }
+
+    fn tat(&self) -> f64 {
+        f64::from_bits(self.tat.load(Ordering::Acquire))
+    }
+
+    fn set_tat(&mut self, tat: f64) {
+        *self.tat.get_mut() = tat.to_bits();
+    }
+
+    fn delay_at(&self, now: f64, cost: u32) -> f32 {
+        let interval = self.interval() * cost as f64;
+        let next = |tat: u64| (f64::from_bits(tat).max(now) + interval).to_bits();
+        let (Ok(previous) | Err(previous)) = self.tat.fetch_update(Ordering::AcqRel, Ordering::Acquire, |tat| Some(next(tat)));
+
+        // A request may go through once its last unit is no more than a
+        // period ahead of the current time
+        let tat = f64::from_bits(next(previous));
+        (tat - self.period_in_secs - now).max(0.0) as f32
+    }
+
+}
+
+impl RateLimiter for Gcra {
+
+    fn algorithm(&self) -> Algorithm {
+        Algorithm::Gcra
+    }
+
+    fn get_weighted_delay(&mut self, cost: u32) -> f32 {
+        self.delay_at(self.clock.now(), cost.clamp(1, self.limit))
+    }
+
+    fn get_weighted_delay_shared(&self, cost: u32) -> Option<f32> {
+        Some(self.delay_at(self.clock.now(), cost.clamp(1, self.limit)))
+    }
+
+    fn release(&mut self, cost: u32) {
+        let tat = self.tat() - self.interval() * cost.min(self.limit) as f64;
+        self.set_tat(tat);
+    }
+
+    fn quota(&self) -> Quota {
+        let now = self.clock.now();
+        let tat = self.tat().max(now);
+        // Rounded to absorb the error of adding up intervals
+        let remaining = ((now + self.period_in_secs - tat) / self.interval() + 1e-9).floor();
+
+        Quota {
+            remaining: remaining.clamp(0.0, self.limit as f64) as u32,
+            reset: (tat - now) as f32,
+        }
+    }
+
+    fn reconfigure(&mut self, limit: u32, period: u32) -> Result<(), Error> {
+        error::check_limit(limit, period)?;
+
+        // Requests already made keep counting as a share of the period
+        let now = self.clock.now();
+        let pending = (self.tat() - now).max(0.0) / self.period_in_secs;
+        self.limit = limit;
+        self.period_in_secs = period as f64;
+        if pending > 0.0 {
+            self.set_tat(now + pending * self.period_in_secs);
+        }
+        Ok(())
+    }
+
+    fn limit(&self) -> u32 {
+        self.limit
+    }
+
+    fn period(&self) -> u32 {
+        self.period_in_secs as u32
+    }
+
+    fn state(&self) -> LimiterState {
+        LimiterState::Gcra { tat: self.tat() }
+    }
+
+    fn restore(&mut self, state: &LimiterState) {
+        if let LimiterState::Gcra { tat } = *state {
+            self.set_tat(tat);
+        }
+    }
+
+}
+
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::ManualClock;
+    use crate::limiter::{Gcra, RateLimiter};
+
+    #[test]
+    /// A burst of `limit` requests goes through, and the following ones are
+    /// spaced by `period / limit`.
+    fn burst_then_spacing() {
+        let gcra = Gcra::new(4, 2);
+        for _ in 0..4 {
+            assert_eq!(gcra.delay_at(100.0, 1), 0.0);
+        }
+        assert_eq!(gcra.delay_at(100.0, 1), 0.5);
+        assert_eq!(gcra.delay_at(100.0, 1), 1.0);
+        assert_eq!(gcra.delay_at(101.0, 1), 0.5);
+
+        // After being idle for a full period, a new burst is allowed
+        for _ in 0..4 {
+            assert_eq!(gcra.delay_at(110.0, 1), 0.0);
+        }
+    }
+
+    #[test]
+    /// Requests recorded by several threads at once are all counted.
+    fn shared_requests() {
+        // Intervals of 1/1024 seconds add up without rounding errors
+        let gcra = Arc::new(Gcra::new(1024, 1).with_clock(Arc::new(ManualClock::new(100.0))));
+        let threads: Vec<_> = (0..4).map(|_| {
+            let gcra = gcra.clone();
+            std::thread::spawn(move || (0..512).filter(|_| gcra.get_weighted_delay_shared(1) == Some(0.0)).count())
+        }).collect();
+
+        let allowed: usize = threads.into_iter().map(|thread| thread.join().unwrap()).sum();
+        assert_eq!(allowed, 1024);
+        assert_eq!(gcra.quota().remaining, 0);
+    }
+}
@@ -0,0 +1,134 @@
+use std::sync::Arc;
+
+use crate::clock::{self, Clock};
+use crate::error::{self, Error};
+use crate::limiter::{Algorithm, LimiterState, Quota, RateLimiter};
+
+
+/// Queue draining one request every `period / limit` seconds. Requests are
+/// never closer to each other than that interval, times the cost of the
+/// earlier request, even after the service has been idle, which smooths
+/// bursts into a constant rate.
+pub struct LeakyBucket {
+    limit: u32,
+    period_in_secs: f64,
+    next: f64,
+    clock: Arc<dyn Clock>,
+}
+
+impl LeakyBucket {
+
+    pub fn new(limit: u32, period: u32) -> Self {
+        assert!(limit > 0, "Max requests per period must be greater than 0.");
+        assert!(period > 0, "Period must be greater than 0.");
+
+        LeakyBucket {
+            limit,
+            period_in_secs: period as f64,
+            next: f64::NEG_INFINITY,
+            clock: clock::default_clock(),
+        }
+    }
+
+    /// Takes the current time from `clock` instead of the default monotonic
+    /// clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    fn interval(&self) -> f64 {
+        self.period_in_secs / self.limit as f64
+    }
+
+    fn delay_at(&mut self, now: f64, cost: u32) -> f32 {
+        let slot = self.next.max(now);
+        self.next = slot + self.interval() * cost as f64;
+        (slot - now) as f32
+    }
+
+}
+
+impl RateLimiter for LeakyBucket {
+
+    fn algorithm(&self) -> Algorithm {
+        Algorithm::LeakyBucket
+    }
+
+    fn get_weighted_delay(&mut self, cost: u32) -> f32 {
+        self.delay_at(self.clock.now(), cost.clamp(1, self.limit))
+    }
+
+    fn release(&mut self, cost: u32) {
+        self.next -= self.interval() * cost.min(self.limit) as f64;
+    }
+
+    /// As requests are never let through in bursts, at most one request can
+    /// be made without a delay.
+    fn quota(&self) -> Quota {
+        let now = self.clock.now();
+        Quota {
+            remaining: if self.next <= now { 1 } else { 0 },
+            reset: (self.next - now).max(0.0) as f32,
+        }
+    }
+
+    fn reconfigure(&mut self, limit: u32, period: u32) -> Result<(), Error> {
+        error::check_limit(limit, period)?;
+
+        // The last request made keeps its slot, only the interval after it changes
+        let last = self.next - self.interval();
+        self.limit = limit;
+        self.period_in_secs = period as f64;
+        self.next = last + self.interval();
+        Ok(())
+    }
+
+    fn limit(&self) -> u32 {
+        self.limit
+    }
+
+    fn period(&self) -> u32 {
+        self.period_in_secs as u32
+    }
+
+    fn state(&self) -> LimiterState {
+        LimiterState::LeakyBucket { next: self.next }
+    }
+
+    fn restore(&mut self, state: &LimiterState) {
+        if let LimiterState::LeakyBucket { next } = *state {
+            self.next = next;
+        }
+    }
+
+}
+
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use crate::RateLimiter;
+    use crate::limiter::LeakyBucket;
+
+    #[test]
+    /// Requests are spaced by `period / limit`, and an idle bucket does not
+    /// accumulate a burst.
+    fn constant_rate() {
+        let mut bucket = LeakyBucket::new(4, 2);
+        assert_eq!(bucket.delay_at(100.0, 1), 0.0);
+        assert_eq!(bucket.delay_at(100.0, 1), 0.5);
+        assert_eq!(bucket.delay_at(100.25, 1), 0.75);
+
+        assert_eq!(bucket.delay_at(200.0, 1), 0.0);
+        assert_eq!(bucket.delay_at(200.0, 1), 0.5);
+    }
+
+    #[test]
+    fn reconfigure() {
+        let mut bucket = LeakyBucket::new(4, 2);
+        bucket.delay_at(100.0, 1);
+        bucket.reconfigure(1, 2).unwrap();
+        assert_eq!(bucket.delay_at(100.0, 1), 2.0);
+    }
+}
@@ -0,0 +1,153 @@
+use std::sync::Arc;
+
+use crate::clock::{self, Clock};
+use crate::error::{self, Error};
+use crate::limiter::fixed_window::Windows;
+use crate::limiter::{Algorithm, LimiterState, Quota, RateLimiter};
+
+
+/// Approximation of a sliding log using only the counters of the current and
+/// previous windows. The requests of the previous window are assumed to be
+/// evenly spread, so the number of requests within the last `period` seconds
+/// is estimated as the current count plus the share of the previous count
+/// that still overlaps it.
+pub struct SlidingWindowCounter {
+    limit: u32,
+    windows: Windows,
+    clock: Arc<dyn Clock>,
+}
+
+impl SlidingWindowCounter {
+
+    pub fn new(limit: u32, period: u32) -> Self {
+        assert!(limit > 0, "Max requests per period must be greater than 0.");
+        assert!(period > 0, "Period must be greater than 0.");
+
+        SlidingWindowCounter {
+            limit,
+            windows: Windows::new(period as f64),
+            clock: clock::default_clock(),
+        }
+    }
+
+    /// Takes the current time from `clock` instead of the default monotonic
+    /// clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    fn delay_at(&mut self, now: f64, cost: u32) -> f32 {
+        let limit = self.limit as f64;
+        let cost = cost as f64;
+        let mut index = self.windows.index(now);
+        self.windows.expire(index - 1);
+
+        loop {
+            let previous = self.windows.count(index - 1) as f64;
+            let current = self.windows.count(index) as f64;
+
+            if current + cost <= limit {
+                // Fraction of the window after which the previous window's
+                // share is small enough to fit the cost of the request
+                let overlap = if previous > 0.0 { (1.0 - (limit - current - cost) / previous).max(0.0) } else { 0.0 };
+
+                if overlap < 1.0 {
+                    let start = self.windows.start(index);
+                    let at = (start + overlap * self.windows.period_in_secs()).max(now);
+                    self.windows.add(index, cost as u32);
+                    return (at - now) as f32;
+                }
+            }
+            index += 1;
+        }
+    }
+
+}
+
+impl RateLimiter for SlidingWindowCounter {
+
+    fn algorithm(&self) -> Algorithm {
+        Algorithm::SlidingWindow
+    }
+
+    fn get_weighted_delay(&mut self, cost: u32) -> f32 {
+        self.delay_at(self.clock.now(), cost.clamp(1, self.limit))
+    }
+
+    fn release(&mut self, cost: u32) {
+        self.windows.remove(cost.min(self.limit));
+    }
+
+    /// The requests of a window keep counting until it has slid out of the
+    /// period entirely, a period after it ended.
+    fn quota(&self) -> Quota {
+        let now = self.clock.now();
+        let period = self.windows.period_in_secs();
+        let index = self.windows.index(now);
+
+        let elapsed = (now - self.windows.start(index)) / period;
+        let estimate = self.windows.count(index) as f64 + self.windows.count(index - 1) as f64 * (1.0 - elapsed);
+        let end = self.windows.last_start().map_or(now, |start| start + 2.0 * period);
+
+        Quota {
+            remaining: (self.limit as f64 - estimate).max(0.0) as u32,
+            reset: (end - now).max(0.0) as f32,
+        }
+    }
+
+    fn reconfigure(&mut self, limit: u32, period: u32) -> Result<(), Error> {
+        error::check_limit(limit, period)?;
+
+        self.limit = limit;
+        self.windows.set_period(period as f64);
+        Ok(())
+    }
+
+    fn limit(&self) -> u32 {
+        self.limit
+    }
+
+    fn period(&self) -> u32 {
+        self.windows.period_in_secs() as u32
+    }
+
+    fn state(&self) -> LimiterState {
+        LimiterState::SlidingWindow { windows: self.windows.state() }
+    }
+
+    fn restore(&mut self, state: &LimiterState) {
+        if let LimiterState::SlidingWindow { windows } = state {
+            self.windows.restore(windows);
+        }
+    }
+
+}
+
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use crate::limiter::SlidingWindowCounter;
+
+    #[test]
+    /// The previous window's requests count proportionally to how much of it
+    /// overlaps the last period.
+    fn weighted_previous_window() {
+        let mut window = SlidingWindowCounter::new(4, 10);
+        for _ in 0..4 {
+            assert_eq!(window.delay_at(105.0, 1), 0.0);
+        }
+
+        // At 112.5, a quarter of the previous window has slid out: 4 * 0.75 = 3
+        assert_eq!(window.delay_at(112.5, 1), 0.0);
+
+        // Every further request needs another quarter of the previous window to slide out
+        assert_eq!(window.delay_at(112.5, 1), 2.5);
+        assert_eq!(window.delay_at(112.5, 1), 5.0);
+
+        // The previous window no longer leaves room in the current one, and the
+        // next window starts with the 3 requests of the current one
+        assert_eq!(window.delay_at(112.5, 1), 7.5);
+    }
+}
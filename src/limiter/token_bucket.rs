@@ -0,0 +1,148 @@
+use std::sync::Arc;
+
+use crate::clock::{self, Clock};
+use crate::error::{self, Error};
+use crate::limiter::{Algorithm, LimiterState, Quota, RateLimiter};
+
+
+/// Bucket holding up to `limit` tokens, refilled at `limit / period` tokens
+/// per second. Every request takes as many tokens as it costs, and requests
+/// arriving to a bucket without enough tokens wait for the tokens they take in
+/// advance.
+pub struct TokenBucket {
+    limit: u32,
+    period_in_secs: f64,
+    tokens: f64,
+    updated: f64,
+    clock: Arc<dyn Clock>,
+}
+
+impl TokenBucket {
+
+    pub fn new(limit: u32, period: u32) -> Self {
+        assert!(limit > 0, "Max requests per period must be greater than 0.");
+        assert!(period > 0, "Period must be greater than 0.");
+
+        TokenBucket {
+            limit,
+            period_in_secs: period as f64,
+            tokens: limit as f64,
+            updated: f64::NEG_INFINITY,
+            clock: clock::default_clock(),
+        }
+    }
+
+    /// Takes the current time from `clock` instead of the default monotonic
+    /// clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    fn rate(&self) -> f64 {
+        self.limit as f64 / self.period_in_secs
+    }
+
+    /// Tokens in the bucket at `now`, which must not be before `updated`.
+    fn tokens_at(&self, now: f64) -> f64 {
+        (self.tokens + (now - self.updated) * self.rate()).min(self.limit as f64)
+    }
+
+    fn refill(&mut self, now: f64) {
+        if now > self.updated {
+            self.tokens = self.tokens_at(now);
+            self.updated = now;
+        }
+    }
+
+    fn delay_at(&mut self, now: f64, cost: u32) -> f32 {
+        self.refill(now);
+        self.tokens -= cost as f64;
+
+        if self.tokens >= 0.0 {
+            return 0.0;
+        }
+        (-self.tokens * self.period_in_secs / self.limit as f64) as f32
+    }
+
+}
+
+impl RateLimiter for TokenBucket {
+
+    fn algorithm(&self) -> Algorithm {
+        Algorithm::TokenBucket
+    }
+
+    fn get_weighted_delay(&mut self, cost: u32) -> f32 {
+        self.delay_at(self.clock.now(), cost.clamp(1, self.limit))
+    }
+
+    fn release(&mut self, cost: u32) {
+        self.refill(self.clock.now());
+        self.tokens = (self.tokens + cost.min(self.limit) as f64).min(self.limit as f64);
+    }
+
+    fn quota(&self) -> Quota {
+        let now = self.clock.now();
+        let tokens = if now > self.updated { self.tokens_at(now) } else { self.tokens };
+
+        Quota {
+            remaining: tokens.max(0.0) as u32,
+            reset: ((self.limit as f64 - tokens) / self.rate()) as f32,
+        }
+    }
+
+    fn reconfigure(&mut self, limit: u32, period: u32) -> Result<(), Error> {
+        error::check_limit(limit, period)?;
+
+        self.refill(self.clock.now());
+        self.limit = limit;
+        self.period_in_secs = period as f64;
+        self.tokens = self.tokens.min(limit as f64);
+        Ok(())
+    }
+
+    fn limit(&self) -> u32 {
+        self.limit
+    }
+
+    fn period(&self) -> u32 {
+        self.period_in_secs as u32
+    }
+
+    fn state(&self) -> LimiterState {
+        LimiterState::TokenBucket { tokens: self.tokens, updated: self.updated }
+    }
+
+    fn restore(&mut self, state: &LimiterState) {
+        if let LimiterState::TokenBucket { tokens, updated } = *state {
+            self.tokens = tokens.min(self.limit as f64);
+            self.updated = updated;
+        }
+    }
+
+}
+
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use crate::limiter::TokenBucket;
+
+    #[test]
+    /// A full bucket allows a burst of `limit` requests, after which requests
+    /// are spaced by the refill rate.
+    fn burst_then_refill() {
+        let mut bucket = TokenBucket::new(4, 2);
+        for _ in 0..4 {
+            assert_eq!(bucket.delay_at(100.0, 1), 0.0);
+        }
+        assert_eq!(bucket.delay_at(100.0, 1), 0.5);
+        assert_eq!(bucket.delay_at(100.0, 1), 1.0);
+
+        // Both waiting requests have taken the tokens refilled within a second
+        assert_eq!(bucket.delay_at(101.0, 1), 0.5);
+        assert_eq!(bucket.delay_at(110.0, 1), 0.0);
+        assert_eq!(bucket.tokens, 3.0);
+    }
+}
@@ -0,0 +1,80 @@
+use std::io::IsTerminal;
+
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::EnvFilter;
+
+
+/// Environment variable setting the verbosity of the logs when `--log-level`
+/// is not given, as a level such as `debug` or as directives such as
+/// `warn,jarl::server=debug`.
+pub const LOG_ENV: &str = "JARL_LOG";
+
+
+/// How events are written to stdout.
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum)]
+pub enum LogFormat {
+    /// One line of text per event
+    #[default]
+    Human,
+    /// One JSON object per line
+    Json,
+}
+
+
+/// Writes the events of jarl to stdout from now on, at the verbosity given by
+/// `level`, or else by the `JARL_LOG` environment variable, or else `info`.
+pub fn init(level: Option<LevelFilter>, format: LogFormat) {
+    let directives = std::env::var(LOG_ENV).ok();
+    let (filter, invalid) = filter(level, directives.as_deref());
+
+    let logger = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stdout)
+        .with_ansi(std::io::stdout().is_terminal());
+    let _ = match format {
+        LogFormat::Human => logger.try_init(),
+        LogFormat::Json => logger.json().try_init(),
+    };
+
+    if invalid {
+        tracing::warn!(variable = LOG_ENV, directives = directives.as_deref().unwrap_or_default(), "ignoring invalid log directives");
+    }
+}
+
+/// Filter of the events to log, and whether `directives` were given but
+/// could not be parsed.
+fn filter(level: Option<LevelFilter>, directives: Option<&str>) -> (EnvFilter, bool) {
+    if let Some(level) = level {
+        return (EnvFilter::default().add_directive(level.into()), false);
+    }
+
+    match directives.map(EnvFilter::try_new) {
+        Some(Ok(filter)) => (filter, false),
+        parsed => (EnvFilter::default().add_directive(LevelFilter::INFO.into()), parsed.is_some()),
+    }
+}
+
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use tracing::level_filters::LevelFilter;
+
+    use crate::logging::filter;
+
+    #[test]
+    /// The level given on the CLI takes precedence over the environment,
+    /// which is ignored if invalid.
+    fn filter_precedence() {
+        let level = |level, directives| {
+            let (filter, invalid) = filter(level, directives);
+            (filter.max_level_hint(), invalid)
+        };
+
+        assert_eq!(level(None, None), (Some(LevelFilter::INFO), false));
+        assert_eq!(level(None, Some("warn")), (Some(LevelFilter::WARN), false));
+        assert_eq!(level(None, Some("error,jarl::server=debug")), (Some(LevelFilter::DEBUG), false));
+        assert_eq!(level(Some(LevelFilter::DEBUG), Some("error")), (Some(LevelFilter::DEBUG), false));
+        assert_eq!(level(None, Some("jarl=loud")), (Some(LevelFilter::INFO), true));
+    }
+}
@@ -1,31 +1,192 @@
 use clap::Parser;
-use jarl::{Cli, Keeper};
+use jarl::{Cli, Config, Registry, Snapshot};
+use jarl::server::{AdminCommand, AdminRequest, Server};
 
-use tokio::io::*;
-use tokio::net::{ TcpListener, TcpStream };
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{Instant, Interval, MissedTickBehavior};
 
 
-type TimeKeeper = Arc<Mutex<Keeper>>;
+fn main() {
+    let args = Cli::parse();
+    jarl::logging::init(args.log_level, args.log_format);
 
+    let config = match Config::from_cli(&args) {
+        Ok(config) => config,
+        Err(error) => exit_with(error.into()),
+    };
+    tracing::info!(
+        version = env!("CARGO_PKG_VERSION"),
+        threads = config.server.threads(),
+        state_file = config.state.path.as_ref().map(|path| tracing::field::display(path.display())),
+        redis = config.store.redis,
+        "starting",
+    );
+    log_services(&config);
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() {
-    let args = Cli::parse();
-    
-    let address = format!("{}:{}", args.ip, args.port);
-    let listener = TcpListener::bind(address).await.unwrap();
+    let mut runtime = match config.server.threads() {
+        1 => tokio::runtime::Builder::new_current_thread(),
+        threads => {
+            let mut runtime = tokio::runtime::Builder::new_multi_thread();
+            runtime.worker_threads(threads);
+            runtime
+        }
+    };
+    let runtime = match runtime.enable_all().build() {
+        Ok(runtime) => runtime,
+        Err(error) => exit_with(jarl::Error::Runtime(error)),
+    };
+    runtime.block_on(run(args, config));
+}
+
+fn exit_with(error: jarl::Error) -> ! {
+    tracing::error!(%error, "could not start");
+    std::process::exit(1);
+}
+
+fn log_services(config: &Config) {
+    for service in &config.services {
+        tracing::info!(
+            service = service.name,
+            algorithm = %service.algorithm,
+            requests = service.requests,
+            period = service.period,
+            "service configured",
+        );
+    }
+}
+
+async fn run(args: Cli, mut config: Config) {
+    let registry = Arc::new(Registry::from_config(&config));
+    if let Some(path) = &config.state.path {
+        match Snapshot::load(path) {
+            Ok(Some(snapshot)) => {
+                snapshot.restore(&registry);
+                tracing::info!(path = %path.display(), "state restored");
+            }
+            Ok(None) => {}
+            Err(error) => tracing::error!(path = %path.display(), %error, "could not restore state"),
+        }
+    }
+
+    let (admin, mut commands) = mpsc::channel(8);
+    tokio::spawn(reload_on_hangup(admin.clone()));
+    let mut server = match Server::start(&config, registry, admin).await {
+        Ok(server) => server,
+        Err(error) => exit_with(error),
+    };
+
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    let mut saves = save_interval(&config);
+
+    loop {
+        tokio::select! {
+            Some(request) = commands.recv() => {
+                let outcome = match request.command {
+                    AdminCommand::Reload => reload(&args, &mut server).await
+                        .map(|reloaded| {
+                            if reloaded.state != config.state {
+                                saves = save_interval(&reloaded);
+                            }
+                            config = reloaded;
+                        }),
+                };
+                let _ = request.reply.send(outcome);
+            }
+            _ = saves.tick(), if config.state.path.is_some() => {
+                save_state(&config, server.registry()).await;
+            }
+            _ = &mut shutdown => break,
+        }
+    }
+
+    // Commands still queued are dropped, so their connections are not kept
+    // waiting for a reply
+    drop(commands);
+    tracing::info!("shutting down");
+    let registry = server.registry().clone();
+    let timeout = config.server.shutdown_timeout();
+    if !server.shutdown(timeout).await {
+        tracing::warn!(timeout = timeout.as_secs(), "connections still open after the shutdown timeout, exiting");
+    }
+    save_state(&config, &registry).await;
+}
+
+fn save_interval(config: &Config) -> Interval {
+    let period = config.state.interval();
+    let mut interval = tokio::time::interval_at(Instant::now() + period, period);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    interval
+}
+
+/// Saves the requests recorded by every service to the state file, if one is
+/// configured.
+async fn save_state(config: &Config, registry: &Registry) {
+    let Some(path) = &config.state.path else {
+        return;
+    };
+
+    if let Err(error) = Snapshot::capture(registry).save(path).await {
+        tracing::error!(path = %path.display(), %error, "could not save state");
+    }
+}
+
+/// Reads the configuration file again, with the same CLI overrides given at
+/// startup, and applies it to the running server.
+async fn reload(args: &Cli, server: &mut Server) -> Result<Config, String> {
+    let reloaded = match Config::from_cli(args) {
+        Ok(config) => server.reload(&config).await.map(|()| config),
+        Err(error) => Err(error.into()),
+    };
 
-    let keeper = Arc::new(Mutex::new(
-        Keeper::new(args.requests, args.period)
-    ));
+    match reloaded {
+        Ok(config) => {
+            tracing::info!("configuration reloaded");
+            log_services(&config);
+            Ok(config)
+        }
+        Err(error) => {
+            tracing::error!(%error, "reload failed");
+            Err(error.to_string())
+        }
+    }
+}
 
-    while let Ok((stream, _address)) = listener.accept().await {
-        tokio::spawn(handle_connection(stream, keeper.clone()));
+/// Resolves when the process receives SIGTERM or SIGINT (Ctrl+C).
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        if let Ok(mut terminate) = signal(SignalKind::terminate()) {
+            tokio::select! {
+                _ = terminate.recv() => return,
+                _ = tokio::signal::ctrl_c() => return,
+            }
+        }
     }
+
+    let _ = tokio::signal::ctrl_c().await;
 }
 
-async fn handle_connection(mut stream: TcpStream, keeper: TimeKeeper) {
-    let response = keeper.lock().unwrap().get_delay();
-    stream.write_all((format!("{:.3}", response)).as_bytes()).await.unwrap();
+/// Sends a reload command every time the process receives SIGHUP.
+#[cfg(unix)]
+async fn reload_on_hangup(admin: mpsc::Sender<AdminRequest>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let Ok(mut hangup) = signal(SignalKind::hangup()) else {
+        return;
+    };
+
+    while hangup.recv().await.is_some() {
+        let (reply, response) = oneshot::channel();
+        if admin.send(AdminRequest { command: AdminCommand::Reload, reply }).await.is_err() {
+            return;
+        }
+        let _ = response.await;
+    }
 }
+
+#[cfg(not(unix))]
+async fn reload_on_hangup(_admin: mpsc::Sender<AdminRequest>) {}
@@ -0,0 +1,221 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{error, Registry, SharedLimiter};
+
+
+/// Upper bounds, in seconds, of the buckets of the delay histogram.
+pub const DELAY_BUCKETS: [f64; 12] = [0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0];
+
+
+/// Counters of the requests recorded for a service, kept across reloads of
+/// its configuration.
+#[derive(Default)]
+pub struct ServiceMetrics {
+    /// Delayed requests
+    delayed: AtomicU64,
+    /// Requests whose delay falls in each bucket of `DELAY_BUCKETS`, followed
+    /// by the ones with a longer delay. Not cumulative
+    buckets: [AtomicU64; DELAY_BUCKETS.len() + 1],
+    /// Sum of the delays, in microseconds
+    delay_sum: AtomicU64,
+    /// Connections that failed before the client got its delay
+    connection_errors: AtomicU64,
+}
+
+impl ServiceMetrics {
+
+    /// Counts a request that got a delay of `delay` seconds.
+    pub fn record(&self, delay: f32) {
+        let delay = delay as f64;
+        let bucket = DELAY_BUCKETS.iter().position(|bound| delay <= *bound).unwrap_or(DELAY_BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.delay_sum.fetch_add((delay * 1e6) as u64, Ordering::Relaxed);
+        if delay > 0.0 {
+            self.delayed.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn record_connection_error(&self) {
+        self.connection_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Requests recorded since the service was added.
+    pub fn acquisitions(&self) -> u64 {
+        self.buckets.iter().map(|bucket| bucket.load(Ordering::Relaxed)).sum()
+    }
+
+    pub fn delayed(&self) -> u64 {
+        self.delayed.load(Ordering::Relaxed)
+    }
+
+    pub fn connection_errors(&self) -> u64 {
+        self.connection_errors.load(Ordering::Relaxed)
+    }
+
+}
+
+
+/// Values of the metrics of a service, read at once.
+struct Sample {
+    /// Name of the service, escaped for a label value
+    label: String,
+    buckets: Vec<u64>,
+    delay_sum: f64,
+    delayed: u64,
+    connection_errors: u64,
+    backoff_count: f32,
+    queue_fill: f64,
+}
+
+impl Sample {
+
+    fn new(name: &str, keeper: &SharedLimiter) -> Self {
+        let metrics = keeper.metrics();
+        let (backoff_count, queue_fill) = {
+            let limiter = keeper.read();
+            let limit = limiter.limit();
+            let used = limit.saturating_sub(limiter.quota().remaining);
+            (limiter.backoff_count(), used as f64 / limit as f64)
+        };
+
+        Sample {
+            label: escape(name),
+            buckets: metrics.buckets.iter().map(|bucket| bucket.load(Ordering::Relaxed)).collect(),
+            delay_sum: metrics.delay_sum.load(Ordering::Relaxed) as f64 / 1e6,
+            delayed: metrics.delayed(),
+            connection_errors: metrics.connection_errors(),
+            backoff_count,
+            queue_fill,
+        }
+    }
+
+    fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+}
+
+
+/// Renders the metrics of every service, and the failures of the whole
+/// process, in the Prometheus text format.
+pub fn render(registry: &Registry) -> String {
+    let samples: Vec<Sample> = registry.entries().iter()
+        .map(|(name, keeper)| Sample::new(name, keeper))
+        .collect();
+    let mut output = String::new();
+
+    family(&mut output, "jarl_acquisitions_total", "counter", "Requests recorded.", &samples, |sample| {
+        sample.count().to_string()
+    });
+    family(&mut output, "jarl_delayed_total", "counter", "Requests recorded with a non-zero delay.", &samples, |sample| {
+        sample.delayed.to_string()
+    });
+
+    header(&mut output, "jarl_delay_seconds", "histogram", "Delays replied to requests.");
+    for sample in &samples {
+        let mut cumulative = 0;
+        for (bound, count) in DELAY_BUCKETS.iter().zip(&sample.buckets) {
+            cumulative += count;
+            let _ = writeln!(output, "jarl_delay_seconds_bucket{{service=\"{}\",le=\"{}\"}} {}", sample.label, bound, cumulative);
+        }
+        let _ = writeln!(output, "jarl_delay_seconds_bucket{{service=\"{}\",le=\"+Inf\"}} {}", sample.label, sample.count());
+        let _ = writeln!(output, "jarl_delay_seconds_sum{{service=\"{}\"}} {}", sample.label, sample.delay_sum);
+        let _ = writeln!(output, "jarl_delay_seconds_count{{service=\"{}\"}} {}", sample.label, sample.count());
+    }
+
+    family(&mut output, "jarl_backoff_count", "gauge", "Units the delay of the next delayed request backs off by.", &samples, |sample| {
+        sample.backoff_count.to_string()
+    });
+    family(&mut output, "jarl_queue_fill_ratio", "gauge", "Share of the limit taken by recorded requests.", &samples, |sample| {
+        sample.queue_fill.to_string()
+    });
+    family(&mut output, "jarl_connection_errors_total", "counter", "Connections that failed before the client got its delay.", &samples, |sample| {
+        sample.connection_errors.to_string()
+    });
+
+    header(&mut output, "jarl_failures_total", "counter", "Failures reported without stopping jarl, such as failed connections.");
+    let _ = writeln!(output, "jarl_failures_total {}", error::failures());
+    output
+}
+
+fn header(output: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(output, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+}
+
+/// Writes a metric with a single value per service.
+fn family(output: &mut String, name: &str, kind: &str, help: &str, samples: &[Sample], value: impl Fn(&Sample) -> String) {
+    header(output, name, kind, help);
+    for sample in samples {
+        let _ = writeln!(output, "{}{{service=\"{}\"}} {}", name, sample.label, value(sample));
+    }
+}
+
+/// Escapes a label value.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::{Algorithm, Keeper, ManualClock, Registry};
+    use crate::metrics::{escape, render};
+
+    #[test]
+    /// Every request recorded through the registry is counted, in a
+    /// cumulative histogram of its delay.
+    fn render_services() {
+        let clock = ManualClock::new(100.0);
+        let registry = Registry::new();
+        let keeper = registry.insert("api", Algorithm::FixedWindow.build_with_clock(2, 10, Arc::new(clock.clone()))).unwrap();
+        registry.insert("other", Box::new(Keeper::try_new(4, 1).unwrap())).unwrap();
+
+        keeper.get_delay();
+        clock.advance(Duration::from_secs(4));
+        keeper.get_delay();
+        keeper.get_delay();
+        keeper.metrics().record_connection_error();
+
+        let output = render(&registry);
+        let lines: Vec<&str> = output.lines().collect();
+        for line in [
+            "# TYPE jarl_delay_seconds histogram",
+            "jarl_acquisitions_total{service=\"api\"} 3",
+            "jarl_delayed_total{service=\"api\"} 1",
+            "jarl_delay_seconds_bucket{service=\"api\",le=\"0.001\"} 2",
+            "jarl_delay_seconds_bucket{service=\"api\",le=\"5\"} 2",
+            "jarl_delay_seconds_bucket{service=\"api\",le=\"10\"} 3",
+            "jarl_delay_seconds_bucket{service=\"api\",le=\"+Inf\"} 3",
+            "jarl_delay_seconds_sum{service=\"api\"} 6",
+            "jarl_delay_seconds_count{service=\"api\"} 3",
+            "jarl_queue_fill_ratio{service=\"api\"} 1",
+            "jarl_connection_errors_total{service=\"api\"} 1",
+            "jarl_acquisitions_total{service=\"other\"} 0",
+            "jarl_queue_fill_ratio{service=\"other\"} 0",
+        ] {
+            assert!(lines.contains(&line), "Missing `{}` in:\n{}", line, output);
+        }
+    }
+
+    #[test]
+    /// The backoff count of the default algorithm grows with consecutive
+    /// delayed requests.
+    fn backoff_count() {
+        let registry = Registry::new();
+        let keeper = registry.insert("api", Box::new(Keeper::try_new(1, 60).unwrap())).unwrap();
+        for _ in 0..3 {
+            keeper.get_delay();
+        }
+        assert!(render(&registry).lines().any(|line| line == "jarl_backoff_count{service=\"api\"} 2"));
+    }
+
+    #[test]
+    fn escape_labels() {
+        assert_eq!(escape("a\"b\\c"), "a\\\"b\\\\c");
+    }
+}
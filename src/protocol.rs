@@ -0,0 +1,236 @@
+use std::fmt;
+
+use crate::{error, Algorithm, Error, Registry};
+
+
+/// Version of the line protocol, sent at the start of every reply.
+pub const VERSION: u32 = 1;
+
+/// Words starting a command, which services cannot be named after, as the
+/// name sent by a legacy client would be read as a command.
+pub const COMMANDS: [&str; 4] = ["ACQUIRE", "PEEK", "RELEASE", "STATUS"];
+
+
+/// Whether `word` starts a command, whatever its case.
+pub fn is_command(word: &str) -> bool {
+    COMMANDS.iter().any(|command| command.eq_ignore_ascii_case(word))
+}
+
+
+/// Request of the line protocol, sent as a single line of whitespace-separated
+/// words. Commands are case-insensitive, and costs default to 1.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    /// `ACQUIRE <service> [cost]`: records a request and replies with its delay
+    Acquire { service: String, cost: u32 },
+    /// `PEEK <service> [cost]`: replies with the delay a request would get,
+    /// without recording it
+    Peek { service: String, cost: u32 },
+    /// `RELEASE <service> [cost]`: gives back the units of a request that was
+    /// not made after all
+    Release { service: String, cost: u32 },
+    /// `STATUS [service]`: replies with the limits of a service, or with the
+    /// number of services of the server
+    Status { service: Option<String> },
+}
+
+impl Command {
+
+    pub fn parse(line: &str) -> Result<Self, ProtocolError> {
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or_default().to_ascii_uppercase();
+
+        let command = match command.as_str() {
+            "ACQUIRE" | "PEEK" | "RELEASE" => {
+                let service = words.next().ok_or(ProtocolError::MissingService)?.to_string();
+                let cost = match words.next() {
+                    Some(cost) => match cost.parse::<u32>() {
+                        Ok(cost) if cost > 0 => cost,
+                        _ => return Err(ProtocolError::InvalidCost(cost.to_string())),
+                    },
+                    None => 1,
+                };
+
+                match command.as_str() {
+                    "ACQUIRE" => Command::Acquire { service, cost },
+                    "PEEK" => Command::Peek { service, cost },
+                    _ => Command::Release { service, cost },
+                }
+            }
+            "STATUS" => Command::Status { service: words.next().map(str::to_string) },
+            _ => return Err(ProtocolError::UnknownCommand(line.trim().to_string())),
+        };
+
+        match words.next() {
+            Some(word) => Err(ProtocolError::UnexpectedArgument(word.to_string())),
+            None => Ok(command),
+        }
+    }
+
+    /// Carries out the command against the services of `registry`.
+    pub fn execute(&self, registry: &Registry) -> Result<Response, ProtocolError> {
+        let service = match self {
+            Command::Acquire { service, .. } | Command::Peek { service, .. } | Command::Release { service, .. } => service,
+            Command::Status { service: Some(service) } => service,
+            Command::Status { service: None } => return Ok(Response::Server { services: registry.len() }),
+        };
+        let keeper = match self {
+            Command::Acquire { .. } => registry.get_available(service)?,
+            _ => registry.get(service).ok_or_else(|| ProtocolError::UnknownService(service.clone()))?,
+        };
+
+        Ok(match *self {
+            Command::Acquire { cost, .. } => Response::Delay(keeper.try_get_weighted_delay(cost).map_err(unavailable)?),
+            Command::Peek { cost, .. } => Response::Delay(keeper.lock().try_peek_weighted_delay(cost).map_err(unavailable)?),
+            Command::Release { cost, .. } => {
+                keeper.lock().release(cost);
+                Response::Released
+            }
+            Command::Status { .. } => {
+                let keeper = keeper.read();
+                Response::Service { algorithm: keeper.algorithm(), requests: keeper.limit(), period: keeper.period() }
+            }
+        })
+    }
+
+}
+
+
+impl fmt::Display for Command {
+    /// Formats the command as sent by clients, without the newline.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Command::Acquire { service, cost } => write!(f, "ACQUIRE {} {}", service, cost),
+            Command::Peek { service, cost } => write!(f, "PEEK {} {}", service, cost),
+            Command::Release { service, cost } => write!(f, "RELEASE {} {}", service, cost),
+            Command::Status { service: Some(service) } => write!(f, "STATUS {}", service),
+            Command::Status { service: None } => write!(f, "STATUS"),
+        }
+    }
+}
+
+
+/// Successful outcome of a command.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Response {
+    /// Seconds to wait before making the request
+    Delay(f32),
+    Released,
+    Service { algorithm: Algorithm, requests: u32, period: u32 },
+    Server { services: usize },
+}
+
+
+/// Reason a command was rejected, replied with a stable code followed by a
+/// description.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ProtocolError {
+    UnknownCommand(String),
+    MissingService,
+    InvalidCost(String),
+    UnexpectedArgument(String),
+    UnknownService(String),
+    /// The node is out of contact with most of its cluster, so it cannot
+    /// tell how much of the limit is left, or the store of the requests failed
+    Unavailable,
+}
+
+impl ProtocolError {
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            ProtocolError::UnknownCommand(_) => "unknown-command",
+            ProtocolError::MissingService => "missing-service",
+            ProtocolError::InvalidCost(_) => "invalid-cost",
+            ProtocolError::UnexpectedArgument(_) => "unexpected-argument",
+            ProtocolError::UnknownService(_) => "unknown-service",
+            ProtocolError::Unavailable => "unavailable",
+        }
+    }
+
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::UnknownCommand(line) => write!(f, "unknown command `{}`", line),
+            ProtocolError::MissingService => write!(f, "a service name is required"),
+            ProtocolError::InvalidCost(cost) => write!(f, "cost `{}` is not a positive integer", cost),
+            ProtocolError::UnexpectedArgument(word) => write!(f, "unexpected argument `{}`", word),
+            ProtocolError::UnknownService(name) => write!(f, "no service named `{}`", name),
+            ProtocolError::Unavailable => write!(f, "requests cannot be recorded until the cluster or store is reached"),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+
+/// Reports a failure of the store of the requests, replied to the client as
+/// `unavailable`.
+pub(crate) fn unavailable(error: Error) -> ProtocolError {
+    error::report(&error);
+    ProtocolError::Unavailable
+}
+
+
+/// Formats the reply to a command as a single line, without the newline:
+/// `JARL/<version> OK [values]` or `JARL/<version> ERR <code> <description>`.
+pub fn reply(outcome: &Result<Response, ProtocolError>) -> String {
+    match outcome {
+        Ok(Response::Delay(delay)) => format!("JARL/{} OK {:.3}", VERSION, delay),
+        Ok(Response::Released) => format!("JARL/{} OK", VERSION),
+        Ok(Response::Service { algorithm, requests, period }) =>
+            format!("JARL/{} OK algorithm={} requests={} period={}", VERSION, algorithm, requests, period),
+        Ok(Response::Server { services }) => format!("JARL/{} OK services={}", VERSION, services),
+        Err(error) => format!("JARL/{} ERR {} {}", VERSION, error.code(), error),
+    }
+}
+
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use crate::{Keeper, Registry};
+    use crate::protocol::{is_command, reply, Command, ProtocolError};
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(Command::parse("ACQUIRE api"), Ok(Command::Acquire { service: String::from("api"), cost: 1 }));
+        assert_eq!(Command::parse("peek api 5"), Ok(Command::Peek { service: String::from("api"), cost: 5 }));
+        assert_eq!(Command::parse(" Release  api 2 "), Ok(Command::Release { service: String::from("api"), cost: 2 }));
+        assert_eq!(Command::parse("STATUS"), Ok(Command::Status { service: None }));
+        assert_eq!(Command::parse("STATUS api"), Ok(Command::Status { service: Some(String::from("api")) }));
+
+        assert_eq!(Command::parse("api"), Err(ProtocolError::UnknownCommand(String::from("api"))));
+        assert_eq!(Command::parse("ACQUIRE"), Err(ProtocolError::MissingService));
+        assert_eq!(Command::parse("ACQUIRE api 0"), Err(ProtocolError::InvalidCost(String::from("0"))));
+        assert_eq!(Command::parse("PEEK api -1"), Err(ProtocolError::InvalidCost(String::from("-1"))));
+        assert_eq!(Command::parse("STATUS api 2"), Err(ProtocolError::UnexpectedArgument(String::from("2"))));
+
+        assert!(is_command("status") && is_command("ACQUIRE") && !is_command("api"));
+
+        for line in ["ACQUIRE api 1", "PEEK api 5", "RELEASE api 2", "STATUS api", "STATUS"] {
+            assert_eq!(Command::parse(line).unwrap().to_string(), line);
+        }
+    }
+
+    #[test]
+    /// Peeking does not record a request, and releasing gives back the units
+    /// of one.
+    fn execute_commands() {
+        let registry = Registry::new();
+        registry.insert("api", Box::new(Keeper::try_new(2, 60).unwrap())).unwrap();
+        let run = |line: &str| reply(&Command::parse(line).and_then(|command| command.execute(&registry)));
+
+        assert_eq!(run("PEEK api 2"), "JARL/1 OK 0.000");
+        assert_eq!(run("ACQUIRE api 2"), "JARL/1 OK 0.000");
+        assert_ne!(run("PEEK api"), "JARL/1 OK 0.000");
+        assert_eq!(run("RELEASE api"), "JARL/1 OK");
+        assert_eq!(run("ACQUIRE api"), "JARL/1 OK 0.000");
+
+        assert_eq!(run("STATUS api"), "JARL/1 OK algorithm=sliding-log requests=2 period=60");
+        assert_eq!(run("STATUS"), "JARL/1 OK services=1");
+        assert_eq!(run("ACQUIRE missing"), "JARL/1 ERR unknown-service no service named `missing`");
+    }
+}
@@ -0,0 +1,499 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::{clock, error, Algorithm, Clock, Config, Error, Keeper, ProtocolError, RateLimiter, ServiceMetrics, Store};
+use crate::config::ServiceConfig;
+use crate::store::Redis;
+
+
+/// A rate limiter shared between every connection handler that serves its service.
+pub type TimeKeeper = Arc<SharedLimiter>;
+
+
+/// A rate limiter behind a lock, to be used by several threads at once.
+///
+/// Requests are recorded under the shared side of the lock by the algorithms
+/// supporting it, so that they never wait for each other. Only gcra does:
+/// every other algorithm takes the exclusive side for each request. The lock is never
+/// poisoned: a panic while holding it does not fail the later requests.
+pub struct SharedLimiter {
+    limiter: RwLock<Box<dyn RateLimiter>>,
+    /// Name of the service, logged with its throttling transitions. Empty
+    /// for rate limiters outside a registry
+    name: String,
+    metrics: ServiceMetrics,
+    /// Whether the last delayed request increased the backoff count
+    backing_off: AtomicBool,
+}
+
+impl SharedLimiter {
+
+    pub fn new(limiter: Box<dyn RateLimiter>) -> Self {
+        SharedLimiter::named("", limiter)
+    }
+
+    /// Shares the rate limiter of the service `name`.
+    pub fn named(name: &str, limiter: Box<dyn RateLimiter>) -> Self {
+        SharedLimiter {
+            limiter: RwLock::new(limiter),
+            name: name.to_string(),
+            metrics: ServiceMetrics::default(),
+            backing_off: AtomicBool::new(false),
+        }
+    }
+
+    /// Records a new request costing `cost` units of the limit, returning the
+    /// number of seconds to wait until all of them are free. If the store of
+    /// the requests fails, the failure is reported and the request waits for
+    /// a full period.
+    pub fn get_weighted_delay(&self, cost: u32) -> f32 {
+        self.try_get_weighted_delay(cost).unwrap_or_else(|error| {
+            error::report(&error);
+            let delay = self.read().period() as f32;
+            self.metrics.record(delay);
+            delay
+        })
+    }
+
+    /// Records a new request as `get_weighted_delay` does, returning the error
+    /// of the store of the requests if it fails.
+    pub fn try_get_weighted_delay(&self, cost: u32) -> Result<f32, Error> {
+        let shared = self.read().get_weighted_delay_shared(cost);
+        let delay = match shared {
+            Some(delay) => delay,
+            None => {
+                let mut limiter = self.lock();
+                let delay = limiter.try_get_weighted_delay(cost)?;
+                self.log_backoff(limiter.backoff_count());
+                delay
+            }
+        };
+        self.metrics.record(delay);
+        Ok(delay)
+    }
+
+    /// Logs the backoff count going from 0 to non-zero, or back to 0.
+    fn log_backoff(&self, backoff_count: f32) {
+        let backing_off = backoff_count > 0.0;
+        if self.backing_off.swap(backing_off, Ordering::Relaxed) == backing_off {
+            return;
+        }
+        match backing_off {
+            true => tracing::info!(service = self.name, backoff_count, "throttling started"),
+            false => tracing::info!(service = self.name, "throttling stopped"),
+        }
+    }
+
+    pub fn get_delay(&self) -> f32 {
+        self.get_weighted_delay(1)
+    }
+
+    /// Shared access to the rate limiter, such as to read its quota.
+    pub fn read(&self) -> RwLockReadGuard<'_, Box<dyn RateLimiter>> {
+        self.limiter.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Requests recorded through this handle, kept when the rate limiter is
+    /// replaced.
+    pub fn metrics(&self) -> &ServiceMetrics {
+        &self.metrics
+    }
+
+    /// Exclusive access to the rate limiter.
+    pub fn lock(&self) -> RwLockWriteGuard<'_, Box<dyn RateLimiter>> {
+        self.limiter.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+}
+
+
+/// Named collection of rate limiters, each one enforcing the rate limit of a
+/// single upstream service. Services can be added, changed and removed while the
+/// registry is shared between connection handlers.
+pub struct Registry {
+    keepers: RwLock<HashMap<String, TimeKeeper>>,
+    clock: Arc<dyn Clock>,
+    /// Whether requests can be recorded, unset while the node of a cluster
+    /// is out of contact with most of the others
+    available: AtomicBool,
+    /// Where the sliding logs of the services are kept, if not in memory
+    store: Option<Arc<dyn Store>>,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Registry::with_clock(clock::default_clock())
+    }
+}
+
+impl Registry {
+
+    pub fn new() -> Self {
+        Registry::default()
+    }
+
+    /// Creates a registry whose rate limiters, built from a configuration,
+    /// take the current time from `clock`.
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        Registry { keepers: RwLock::default(), clock, available: AtomicBool::new(true), store: None }
+    }
+
+    /// Keeps the sliding logs of the services built from a configuration in
+    /// `store`, so that they are shared with the replicas using it. Other
+    /// algorithms are still kept in memory.
+    pub fn with_store(mut self, store: Arc<dyn Store>) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    /// Builds a rate limiter for every service of a validated configuration.
+    pub fn from_config(config: &Config) -> Self {
+        let mut registry = Registry::new();
+        if let Some(address) = &config.store.redis {
+            let redis = Redis::new(address, config.store.password.as_deref(), config.store.prefix());
+            registry = registry.with_store(Arc::new(redis));
+        }
+        registry.reload(config);
+        registry
+    }
+
+    /// Registers a new service, returning its shared rate limiter. Returns
+    /// `None` if a service with the same name is already registered.
+    pub fn insert(&self, name: &str, keeper: Box<dyn RateLimiter>) -> Option<TimeKeeper> {
+        let mut keepers = self.keepers_mut();
+        if keepers.contains_key(name) {
+            return None;
+        }
+
+        let keeper = Arc::new(SharedLimiter::named(name, keeper));
+        keepers.insert(name.to_string(), keeper.clone());
+        Some(keeper)
+    }
+
+    pub fn get(&self, name: &str) -> Option<TimeKeeper> {
+        self.keepers().get(name).cloned()
+    }
+
+    /// The rate limiter of a service, to record a request with. Fails if the
+    /// service is unknown, or if the registry is unavailable. Failures of the
+    /// store of the service are only known once a request is recorded.
+    pub fn get_available(&self, name: &str) -> Result<TimeKeeper, ProtocolError> {
+        let keeper = self.get(name).ok_or_else(|| ProtocolError::UnknownService(name.to_string()))?;
+        match self.is_available() {
+            true => Ok(keeper),
+            false => Err(ProtocolError::Unavailable),
+        }
+    }
+
+    /// Runs `task` on the registry, on a thread where blocking is allowed if
+    /// the registry keeps the sliding logs in a store, so that waiting for
+    /// the store does not hold up the other connections.
+    pub async fn run<T, F>(self: &Arc<Self>, task: F) -> T
+    where
+        T: Send + 'static,
+        F: FnOnce(&Registry) -> T + Send + 'static,
+    {
+        if self.store.is_none() {
+            return task(self);
+        }
+        let registry = self.clone();
+        tokio::task::spawn_blocking(move || task(&registry)).await
+            .unwrap_or_else(|error| std::panic::resume_unwind(error.into_panic()))
+    }
+
+    pub fn is_available(&self) -> bool {
+        self.available.load(Ordering::Relaxed)
+    }
+
+    /// Stops or resumes recording requests, such as when the node of a
+    /// cluster loses or regains contact with most of the others.
+    pub fn set_available(&self, available: bool) {
+        self.available.store(available, Ordering::Relaxed);
+    }
+
+    /// Brings the registry in line with a validated configuration. Services
+    /// that already exist keep their recorded requests, new services are
+    /// added and services missing from the configuration are removed.
+    ///
+    /// A service whose algorithm changed starts over with a new, empty rate
+    /// limiter, as requests recorded by one algorithm cannot be carried over
+    /// to another. Services whose limit did not change are left untouched.
+    pub fn reload(&self, config: &Config) {
+        self.reload_with(config, |service| service.requests);
+    }
+
+    /// Reloads the configuration as `reload` does, limiting every service to
+    /// `limit(service)` requests per period instead of its configured limit,
+    /// such as its share of the limit of a cluster.
+    pub fn reload_with(&self, config: &Config, limit: impl Fn(&ServiceConfig) -> u32) {
+        // The services are updated once the registry is unlocked, as updating
+        // a store may take a while
+        let mut existing = Vec::new();
+        {
+            let mut keepers = self.keepers_mut();
+            keepers.retain(|name, _| config.services.iter().any(|service| &service.name == name));
+
+            for service in &config.services {
+                let requests = limit(service);
+                match keepers.get(&service.name) {
+                    Some(keeper) => existing.push((keeper.clone(), service, requests)),
+                    None => {
+                        let limiter = self.build(service, requests);
+                        keepers.insert(service.name.clone(), Arc::new(SharedLimiter::named(&service.name, limiter)));
+                    }
+                }
+            }
+        }
+
+        for (keeper, service, requests) in existing {
+            let mut keeper = keeper.lock();
+            if keeper.algorithm() != service.algorithm {
+                *keeper = self.build(service, requests);
+            } else if (keeper.limit(), keeper.period()) != (requests, service.period) {
+                if let Err(error) = keeper.reconfigure(requests, service.period) {
+                    error::report(&error);
+                }
+            }
+        }
+    }
+
+    /// Builds an empty rate limiter for a service, kept in the store if its
+    /// algorithm is a sliding log.
+    fn build(&self, service: &ServiceConfig, requests: u32) -> Box<dyn RateLimiter> {
+        let (clock, period) = (self.clock.clone(), service.period);
+        match (self.store.clone(), service.algorithm) {
+            (Some(store), Algorithm::SlidingLog) =>
+                Box::new(Keeper::build(requests, period, false).with_clock(clock).with_store(store.log(&service.name))),
+            (Some(store), Algorithm::Reservation) =>
+                Box::new(Keeper::build(requests, period, true).with_clock(clock).with_store(store.log(&service.name))),
+            (_, algorithm) => algorithm.build_with_clock(requests, period, clock),
+        }
+    }
+
+    /// Every registered service and its `Keeper`, sorted by name.
+    pub fn entries(&self) -> Vec<(String, TimeKeeper)> {
+        let mut entries: Vec<(String, TimeKeeper)> = self.keepers().iter()
+            .map(|(name, keeper)| (name.clone(), keeper.clone()))
+            .collect();
+        entries.sort_by(|(first, _), (second, _)| first.cmp(second));
+        entries
+    }
+
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
+
+    pub fn len(&self) -> usize {
+        self.keepers().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keepers().is_empty()
+    }
+
+    fn keepers(&self) -> RwLockReadGuard<'_, HashMap<String, TimeKeeper>> {
+        self.keepers.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn keepers_mut(&self) -> RwLockWriteGuard<'_, HashMap<String, TimeKeeper>> {
+        self.keepers.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+}
+
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use crate::{Algorithm, Command, Config, Error, Keeper, KeeperState, LogStore, ManualClock, MemoryLog, ProtocolError, Response, Store};
+    use crate::config::ServiceConfig;
+    use crate::registry::{Registry, SharedLimiter};
+    use crate::store::Window;
+    use crate::testing::free_port;
+
+    /// Store counting the logs it gives out and the times they are resized.
+    #[derive(Default)]
+    struct CountingStore {
+        logs: AtomicUsize,
+        resizes: Arc<AtomicUsize>,
+    }
+
+    struct CountingLog(MemoryLog, Arc<AtomicUsize>);
+
+    impl Store for CountingStore {
+        fn log(self: Arc<Self>, _name: &str) -> Box<dyn LogStore> {
+            self.logs.fetch_add(1, Ordering::Relaxed);
+            Box::new(CountingLog(MemoryLog::new(64), self.resizes.clone()))
+        }
+    }
+
+    impl LogStore for CountingLog {
+        fn acquire(&mut self, window: &Window, now: f64, cost: u32, record: bool) -> Result<f32, Error> {
+            self.0.acquire(window, now, cost, record)
+        }
+
+        fn reserve(&mut self, window: &Window, now: f64, cost: u32, record: bool) -> Result<f32, Error> {
+            self.0.reserve(window, now, cost, record)
+        }
+
+        fn release(&mut self, cost: u32) -> Result<(), Error> {
+            self.0.release(cost)
+        }
+
+        fn backoff_count(&self) -> f32 {
+            self.0.backoff_count()
+        }
+
+        fn state(&self, now: f64) -> Result<KeeperState, Error> {
+            self.0.state(now)
+        }
+
+        fn resize(&mut self, limit: u32) -> Result<(), Error> {
+            self.1.fetch_add(1, Ordering::Relaxed);
+            self.0.resize(limit)
+        }
+
+        fn replace(&mut self, window: &Window, now: f64, state: &KeeperState) -> Result<(), Error> {
+            self.0.replace(window, now, state)
+        }
+    }
+
+    #[test]
+    /// Every registered service gets its own, independent Keeper.
+    fn independent_keepers() {
+        let registry = Registry::new();
+        registry.insert("first", Box::new(Keeper::try_new(1, 60).unwrap())).unwrap();
+        registry.insert("second", Box::new(Keeper::try_new(1, 60).unwrap())).unwrap();
+
+        let first = registry.get("first").unwrap();
+        assert_eq!(first.get_delay(), 0.0);
+        assert!(first.get_delay() > 0.0, "First service should be throttled.");
+
+        let second = registry.get("second").unwrap();
+        assert_eq!(second.get_delay(), 0.0, "Second service should not be throttled.");
+    }
+
+    #[test]
+    /// A panic while holding the lock of a service does not fail the later
+    /// requests.
+    fn no_poisoning() {
+        let registry = Registry::new();
+        let keeper = registry.insert("service", Box::new(Keeper::try_new(2, 60).unwrap())).unwrap();
+
+        let held = keeper.clone();
+        let panicked = std::thread::spawn(move || {
+            let _guard = held.lock();
+            panic!("Panic while holding the lock");
+        }).join();
+        assert!(panicked.is_err());
+
+        assert_eq!(keeper.get_delay(), 0.0);
+        assert_eq!(keeper.read().quota().remaining, 1);
+    }
+
+    #[test]
+    /// The backoff count going from 0 to non-zero and back is tracked, so
+    /// that only the transitions are logged.
+    fn backoff_transitions() {
+        let clock = ManualClock::new(100.0);
+        let keeper = SharedLimiter::named("api", Box::new(Keeper::try_new(1, 1).unwrap().with_clock(Arc::new(clock.clone()))));
+
+        keeper.get_delay();
+        assert!(!keeper.backing_off.load(Ordering::Relaxed));
+        keeper.get_delay();
+        keeper.get_delay();
+        assert!(keeper.backing_off.load(Ordering::Relaxed));
+
+        clock.advance(Duration::from_secs(300));
+        keeper.get_delay();
+        assert!(!keeper.backing_off.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn reject_duplicate_names() {
+        let registry = Registry::new();
+        assert!(registry.insert("service", Box::new(Keeper::try_new(1, 1).unwrap())).is_some());
+        assert!(registry.insert("service", Box::new(Keeper::try_new(2, 2).unwrap())).is_none());
+        assert_eq!(registry.len(), 1);
+    }
+
+    #[test]
+    fn unknown_service() {
+        let registry = Registry::new();
+        assert!(registry.get("missing").is_none());
+    }
+
+    #[test]
+    /// Reloading keeps the Keeper of existing services, so their recorded
+    /// requests are not lost.
+    fn reload() {
+        let mut config = Config::default();
+        config.services.push(ServiceConfig::new("kept", 1, 60));
+        config.services.push(ServiceConfig::new("removed", 1, 60));
+
+        let registry = Registry::from_config(&config);
+        let kept = registry.get("kept").unwrap();
+        kept.get_delay();
+
+        config.services.remove(1);
+        config.services[0].period = 30;
+        config.services.push(ServiceConfig::new("added", 5, 1));
+        registry.reload(&config);
+
+        assert!(registry.get("removed").is_none());
+        assert!(registry.get("added").is_some());
+        assert!(std::sync::Arc::ptr_eq(&kept, &registry.get("kept").unwrap()));
+        assert_eq!(kept.read().period(), 30);
+        assert!(kept.get_delay() > 0.0, "Requests before the reload should count.");
+
+        config.services[0].algorithm = Algorithm::TokenBucket;
+        registry.reload(&config);
+        assert!(std::sync::Arc::ptr_eq(&kept, &registry.get("kept").unwrap()));
+        assert_eq!(kept.read().algorithm(), Algorithm::TokenBucket);
+    }
+
+    #[test]
+    /// Reloading leaves the services whose limit did not change untouched,
+    /// instead of updating their store.
+    fn reload_changed() {
+        let mut config = Config::default();
+        config.services.push(ServiceConfig::new("kept", 1, 60));
+        config.services.push(ServiceConfig::new("changed", 1, 60));
+
+        let store = Arc::new(CountingStore::default());
+        let registry = Registry::new().with_store(store.clone());
+        registry.reload(&config);
+        assert_eq!(store.logs.load(Ordering::Relaxed), 2);
+
+        config.services[1].requests = 2;
+        registry.reload(&config);
+        assert_eq!(store.logs.load(Ordering::Relaxed), 2);
+        assert_eq!(store.resizes.load(Ordering::Relaxed), 1);
+
+        config.services[1].algorithm = Algorithm::Reservation;
+        registry.reload(&config);
+        assert_eq!(store.logs.load(Ordering::Relaxed), 3);
+        assert_eq!(store.resizes.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    /// Requests to services kept in a store that cannot be reached are
+    /// rejected as unavailable, while the other algorithms are still kept in
+    /// memory.
+    fn unreachable_store() {
+        let mut config = Config::default();
+        config.store.redis = Some(format!("127.0.0.1:{}", free_port()));
+        config.services.push(ServiceConfig::new("stored", 1, 60));
+        config.services.push(ServiceConfig { algorithm: Algorithm::TokenBucket, ..ServiceConfig::new("memory", 1, 60) });
+
+        let registry = Registry::from_config(&config);
+        let acquire = |service: &str| Command::Acquire { service: service.to_string(), cost: 1 }.execute(&registry);
+        assert_eq!(acquire("stored"), Err(ProtocolError::Unavailable));
+        assert_eq!(acquire("memory"), Ok(Response::Delay(0.0)));
+    }
+}
@@ -0,0 +1,1011 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::*;
+use tokio::net::{ TcpListener, TcpStream, UdpSocket };
+#[cfg(unix)]
+use tokio::net::{ UnixListener, UnixStream };
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::task::JoinHandle;
+
+use crate::{Command, Config, ProtocolError, Registry, TimeKeeper};
+use crate::cluster::Cluster;
+use crate::config::{ClusterConfig, StoreConfig};
+use crate::{error, http, protocol, udp};
+
+
+/// Longest line (plus newline) read from clients of the named and admin ports.
+const MAX_LINE_LENGTH: u64 = 256;
+
+/// Time to wait before accepting connections again after failing to accept
+/// one, such as when the process runs out of file descriptors.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(50);
+
+
+/// Command received on the admin port, to be carried out by the owner of the
+/// `Server`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AdminCommand {
+    /// Read the configuration again and apply it
+    Reload,
+}
+
+pub struct AdminRequest {
+    pub command: AdminCommand,
+    pub reply: oneshot::Sender<std::result::Result<(), String>>,
+}
+
+
+/// What a listener serves to the clients connecting to it.
+#[derive(Clone, Debug, PartialEq)]
+enum Target {
+    /// The delay of a single service, without reading from the client
+    Service(String),
+    /// The delay of the service named by the client
+    Named,
+    /// Administrative commands
+    Admin,
+    /// The HTTP/JSON API
+    Http,
+    /// The gRPC API
+    #[cfg(feature = "grpc")]
+    Grpc,
+    /// Metrics for Prometheus
+    Metrics,
+}
+
+impl std::fmt::Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Target::Service(name) => write!(f, "service {}", name),
+            Target::Named => write!(f, "named"),
+            Target::Admin => write!(f, "admin"),
+            Target::Http => write!(f, "http"),
+            #[cfg(feature = "grpc")]
+            Target::Grpc => write!(f, "grpc"),
+            Target::Metrics => write!(f, "metrics"),
+        }
+    }
+}
+
+struct Listener {
+    target: watch::Sender<Target>,
+    task: JoinHandle<()>,
+}
+
+
+/// The listeners of a jarl process and the registry of services they serve.
+pub struct Server {
+    registry: Arc<Registry>,
+    admin: mpsc::Sender<AdminRequest>,
+    listeners: HashMap<SocketAddr, Listener>,
+    /// Address and task of the UDP listener, if enabled
+    udp: Option<(SocketAddr, JoinHandle<()>)>,
+    /// Path and task of the Unix socket listener, if enabled
+    unix: Option<(PathBuf, JoinHandle<()>)>,
+    /// Peers sharing the limit of the services, if configured
+    cluster: Option<Cluster>,
+    /// Sections of the configuration only read on startup, to warn when a
+    /// reload changes them
+    startup: (ClusterConfig, StoreConfig),
+    /// Held by every connection being handled, so that shutting down can wait
+    /// until all of them have been dropped.
+    connections: (mpsc::Sender<()>, mpsc::Receiver<()>),
+    /// Set when shutting down, so that persistent connections close once the
+    /// commands they already received have been replied to.
+    closing: watch::Sender<bool>,
+}
+
+impl Server {
+
+    /// Binds every listener of a validated configuration, serving the
+    /// services of `registry`. Commands received on the admin port are
+    /// forwarded to `admin`.
+    pub async fn start(
+        config: &Config,
+        registry: Arc<Registry>,
+        admin: mpsc::Sender<AdminRequest>,
+    ) -> std::result::Result<Self, crate::Error> {
+        let mut server = Server {
+            registry,
+            admin,
+            listeners: HashMap::new(),
+            udp: None,
+            unix: None,
+            cluster: None,
+            startup: (config.cluster.clone(), config.store.clone()),
+            connections: mpsc::channel(1),
+            closing: watch::channel(false).0,
+        };
+
+        // Requests are rejected until the cluster is reached, so it is joined
+        // before any listener is bound
+        if let Some(address) = config.cluster.address {
+            let cluster = Cluster::start(config, server.registry.clone()).await
+                .map_err(|error| crate::Error::Bind(format!("cluster {}", address), error))?;
+            server.cluster = Some(cluster);
+        }
+        for (address, target) in targets(config) {
+            server.bind(address, target).await
+                .map_err(|error| crate::Error::Bind(address.to_string(), error))?;
+        }
+        if let Some(address) = config.udp_address() {
+            server.bind_udp(address).await
+                .map_err(|error| crate::Error::Bind(format!("udp {}", address), error))?;
+        }
+        if let Some((path, mode)) = config.unix_socket() {
+            server.bind_unix(path, mode).await
+                .map_err(|error| crate::Error::Bind(path.display().to_string(), error))?;
+        }
+        Ok(server)
+    }
+
+    /// Applies a new validated configuration. Services keep their recorded
+    /// requests, listeners that are no longer configured are closed and new
+    /// ones are bound. Connections already accepted are not interrupted.
+    ///
+    /// Every listener is attempted even if binding one of them fails, in
+    /// which case the last error is returned. The cluster and the store are
+    /// only configured on startup, and changes to them are logged and
+    /// ignored.
+    pub async fn reload(&mut self, config: &Config) -> std::result::Result<(), crate::Error> {
+        if config.cluster != self.startup.0 {
+            tracing::warn!(section = "cluster", "changes ignored until restart");
+        }
+        if config.store != self.startup.1 {
+            tracing::warn!(section = "store", "changes ignored until restart");
+        }
+
+        match &self.cluster {
+            Some(cluster) => cluster.reload(config),
+            None => {
+                let config = config.clone();
+                self.registry.run(move |registry| registry.reload(&config)).await;
+            }
+        }
+
+        let mut targets = targets(config);
+        let mut outcome = Ok(());
+
+        self.listeners.retain(|address, listener| match targets.remove(address) {
+            Some(target) => {
+                listener.target.send_if_modified(|current| {
+                    let modified = *current != target;
+                    if modified {
+                        tracing::info!(%address, listener = %target, "serving");
+                    }
+                    *current = target;
+                    modified
+                });
+                true
+            }
+            None => {
+                tracing::info!(%address, "stopped listening");
+                listener.task.abort();
+                false
+            }
+        });
+
+        for (address, target) in targets {
+            if let Err(error) = self.bind(address, target).await {
+                outcome = Err(bind_failed(address.to_string(), error));
+            }
+        }
+
+        let udp = config.udp_address();
+        if self.udp.as_ref().map(|(address, _task)| *address) != udp {
+            if let Some((address, task)) = self.udp.take() {
+                tracing::info!(%address, listener = "udp", "stopped listening");
+                task.abort();
+                let _ = task.await;
+            }
+            if let Some(address) = udp {
+                if let Err(error) = self.bind_udp(address).await {
+                    outcome = Err(bind_failed(format!("udp {}", address), error));
+                }
+            }
+        }
+
+        let unix = config.unix_socket();
+        let moved = self.unix_socket() != unix.map(|(path, _mode)| path);
+        if moved {
+            self.close_unix().await;
+        }
+        if let Some((path, mode)) = unix {
+            let bound = match moved {
+                true => self.bind_unix(path, mode).await,
+                false => set_mode(path, mode),
+            };
+            if let Err(error) = bound {
+                outcome = Err(bind_failed(path.display().to_string(), error));
+            }
+        }
+        outcome
+    }
+
+    pub fn registry(&self) -> &Arc<Registry> {
+        &self.registry
+    }
+
+    /// Addresses currently being listened on over TCP.
+    pub fn addresses(&self) -> Vec<SocketAddr> {
+        self.listeners.keys().copied().collect()
+    }
+
+    /// Address currently being listened on over UDP, if any.
+    pub fn udp_address(&self) -> Option<SocketAddr> {
+        self.udp.as_ref().map(|(address, _task)| *address)
+    }
+
+    /// Path of the Unix socket currently being listened on, if any.
+    pub fn unix_socket(&self) -> Option<&Path> {
+        self.unix.as_ref().map(|(path, _task)| path.as_path())
+    }
+
+    /// Stops accepting connections and waits up to `timeout` for the ones
+    /// already accepted to be handled. Returns `false` if some connections
+    /// were still being handled when the timeout expired.
+    pub async fn shutdown(mut self, timeout: Duration) -> bool {
+        for (_address, listener) in self.listeners.drain() {
+            listener.task.abort();
+            let _ = listener.task.await;
+        }
+        if let Some((_address, task)) = self.udp.take() {
+            task.abort();
+        }
+        self.close_unix().await;
+        self.closing.send_replace(true);
+
+        let (guard, mut connections) = std::mem::replace(&mut self.connections, mpsc::channel(1));
+        drop(guard);
+        tokio::time::timeout(timeout, connections.recv()).await.is_ok()
+    }
+
+    async fn bind(&mut self, address: SocketAddr, target: Target) -> Result<()> {
+        let listener = TcpListener::bind(address).await?;
+        tracing::info!(%address, listener = %target, "listening");
+        let (target, receiver) = watch::channel(target);
+        let task = tokio::spawn(serve(
+            listener, receiver, self.registry.clone(), self.admin.clone(),
+            self.connections.0.clone(), self.closing.subscribe(),
+        ));
+
+        self.listeners.insert(address, Listener { target, task });
+        Ok(())
+    }
+
+    async fn bind_udp(&mut self, address: SocketAddr) -> Result<()> {
+        let socket = UdpSocket::bind(address).await?;
+        tracing::info!(%address, listener = "udp", "listening");
+        let task = tokio::spawn(udp::serve_udp(socket, self.registry.clone()));
+        self.udp = Some((address, task));
+        Ok(())
+    }
+
+    /// Binds the Unix socket at `path`, replacing the socket file left there
+    /// by a process that is no longer listening on it.
+    #[cfg(unix)]
+    async fn bind_unix(&mut self, path: &Path, mode: u32) -> Result<()> {
+        remove_stale_socket(path).await?;
+        let listener = bind_private(path, mode)?;
+        tracing::info!(path = %path.display(), listener = "unix", "listening");
+
+        let task = tokio::spawn(serve_unix(
+            listener, self.registry.clone(), self.connections.0.clone(), self.closing.subscribe(),
+        ));
+        self.unix = Some((path.to_path_buf(), task));
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    async fn bind_unix(&mut self, _path: &Path, _mode: u32) -> Result<()> {
+        Err(Error::new(ErrorKind::Unsupported, "Unix sockets are not available on this platform"))
+    }
+
+    /// Stops listening on the Unix socket, if any, and removes its file.
+    async fn close_unix(&mut self) {
+        if let Some((path, task)) = self.unix.take() {
+            tracing::info!(path = %path.display(), listener = "unix", "stopped listening");
+            task.abort();
+            let _ = task.await;
+            let _ = std::fs::remove_file(path);
+        }
+    }
+
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        for listener in self.listeners.values() {
+            listener.task.abort();
+        }
+        if let Some((_address, task)) = &self.udp {
+            task.abort();
+        }
+        if let Some((path, task)) = &self.unix {
+            task.abort();
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+
+fn targets(config: &Config) -> HashMap<SocketAddr, Target> {
+    let mut targets = HashMap::new();
+
+    for service in &config.services {
+        if let Some(address) = config.service_address(service) {
+            targets.insert(address, Target::Service(service.name.clone()));
+        }
+    }
+    if let Some(address) = config.named_address() {
+        targets.insert(address, Target::Named);
+    }
+    if let Some(address) = config.admin_address() {
+        targets.insert(address, Target::Admin);
+    }
+    if let Some(address) = config.http_address() {
+        targets.insert(address, Target::Http);
+    }
+    #[cfg(feature = "grpc")]
+    if let Some(address) = config.grpc_address() {
+        targets.insert(address, Target::Grpc);
+    }
+    if let Some(address) = config.metrics_address() {
+        targets.insert(address, Target::Metrics);
+    }
+    targets
+}
+
+async fn serve(
+    listener: TcpListener,
+    target: watch::Receiver<Target>,
+    registry: Arc<Registry>,
+    admin: mpsc::Sender<AdminRequest>,
+    connections: mpsc::Sender<()>,
+    closing: watch::Receiver<bool>,
+) {
+    // Connections handed over to the gRPC server, started with the first one
+    #[cfg(feature = "grpc")]
+    let mut grpc: Option<mpsc::Sender<TcpStream>> = None;
+
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _address)) => stream,
+            Err(error) => {
+                accept_failed(error).await;
+                continue;
+            }
+        };
+
+        let current = target.borrow().clone();
+        match current {
+            Target::Service(name) => {
+                spawn_tracked(&connections, handle_service_connection(stream, registry.clone(), name));
+            }
+            Target::Named => {
+                spawn_tracked(&connections, handle_named_connection(stream, registry.clone(), closing.clone()));
+            }
+            Target::Admin => {
+                spawn_tracked(&connections, handle_admin_connection(stream, admin.clone()));
+            }
+            Target::Http => {
+                spawn_tracked(&connections, http::handle_http_connection(stream, registry.clone()));
+            }
+            Target::Metrics => {
+                spawn_tracked(&connections, http::handle_metrics_connection(stream, registry.clone()));
+            }
+            #[cfg(feature = "grpc")]
+            Target::Grpc => {
+                let sender = grpc.get_or_insert_with(|| {
+                    let (sender, receiver) = mpsc::channel(16);
+                    spawn_tracked(&connections, crate::grpc::serve(receiver, registry.clone(), closing.clone()));
+                    sender
+                });
+                let _ = sender.send(stream).await;
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn serve_unix(
+    listener: UnixListener,
+    registry: Arc<Registry>,
+    connections: mpsc::Sender<()>,
+    closing: watch::Receiver<bool>,
+) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _address)) =>
+                spawn_tracked(&connections, handle_named_connection(stream, registry.clone(), closing.clone())),
+            Err(error) => accept_failed(error).await,
+        }
+    }
+}
+
+/// Logs a listener that could not be bound while reloading, as only the last
+/// failure is returned.
+fn bind_failed(listener: String, error: Error) -> crate::Error {
+    let error = crate::Error::Bind(listener, error);
+    tracing::warn!(%error, "listener not bound");
+    error
+}
+
+/// Reports a connection that could not be accepted, and waits for
+/// `ACCEPT_BACKOFF` before the next one.
+pub(crate) async fn accept_failed(error: Error) {
+    error::report(&crate::Error::Connection(error));
+    tokio::time::sleep(ACCEPT_BACKOFF).await;
+}
+
+/// Removes the file at `path` if it is a socket nobody listens on anymore,
+/// such as the one left by a process that crashed. Fails if another process
+/// is listening on it, or if the file is not a socket.
+#[cfg(unix)]
+async fn remove_stale_socket(path: &Path) -> Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(error) => return Err(error),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(Error::new(ErrorKind::AlreadyExists, "file exists and is not a socket"));
+    }
+
+    match UnixStream::connect(path).await {
+        Ok(_stream) => Err(Error::new(ErrorKind::AddrInUse, "socket is in use by another process")),
+        Err(error) if error.kind() == ErrorKind::ConnectionRefused => std::fs::remove_file(path),
+        Err(error) => Err(error),
+    }
+}
+
+/// Binds a Unix socket at `path` with the permissions of `mode`. The socket
+/// is bound in a directory that only this process can enter, and moved to
+/// `path` once its permissions are set, so that no client connects before.
+#[cfg(unix)]
+fn bind_private(path: &Path, mode: u32) -> Result<UnixListener> {
+    use std::os::unix::fs::DirBuilderExt;
+
+    let name = path.file_name().ok_or_else(|| Error::new(ErrorKind::InvalidInput, "path has no file name"))?;
+    let staging = path.with_file_name(format!(".{}.{}", name.to_string_lossy(), std::process::id()));
+    let _ = std::fs::remove_dir_all(&staging);
+    std::fs::DirBuilder::new().mode(0o700).create(&staging)?;
+
+    let socket = staging.join(name);
+    let listener = UnixListener::bind(&socket).and_then(|listener| {
+        set_mode(&socket, mode)?;
+        std::fs::rename(&socket, path)?;
+        Ok(listener)
+    });
+    let _ = std::fs::remove_file(&socket);
+    let _ = std::fs::remove_dir(&staging);
+    listener
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: u32) -> Result<()> {
+    Ok(())
+}
+
+/// Spawns a connection handler holding a clone of `connections` until it ends.
+fn spawn_tracked<F>(connections: &mpsc::Sender<()>, handler: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let guard = connections.clone();
+    tokio::spawn(async move {
+        handler.await;
+        drop(guard);
+    });
+}
+
+/// Reads a line from the client. Returns `None` at the end of the stream, or
+/// if the line is longer than `MAX_LINE_LENGTH`.
+async fn read_line<S: AsyncRead + Unpin>(reader: &mut BufReader<S>) -> Option<String> {
+    let mut line = String::new();
+    let length = reader.take(MAX_LINE_LENGTH).read_line(&mut line).await.ok()?;
+
+    if length == 0 || (length as u64 == MAX_LINE_LENGTH && !line.ends_with('\n')) {
+        return None;
+    }
+    Some(line.trim().to_string())
+}
+
+/// Replies with the delay of the service `name`, without reading from the
+/// client. The connection is closed without a reply while the service is
+/// unavailable.
+async fn handle_service_connection(stream: TcpStream, registry: Arc<Registry>, name: String) {
+    let request = registry.run(move |registry| {
+        let keeper = get_available(registry, &name)?;
+        let delay = keeper.try_get_weighted_delay(1);
+        Some((keeper, delay))
+    });
+    if let Some((keeper, delay)) = request.await {
+        reply_delay(stream, keeper, delay).await;
+    }
+}
+
+/// The rate limiter of a service, reporting the requests dropped while it is
+/// unavailable.
+fn get_available(registry: &Registry, name: &str) -> Option<TimeKeeper> {
+    match registry.get_available(name) {
+        Ok(keeper) => Some(keeper),
+        Err(ProtocolError::Unavailable) => {
+            error::report(&crate::Error::Unavailable(name.to_string()));
+            None
+        }
+        Err(_) => None,
+    }
+}
+
+/// Writes the delay of a request, or closes the connection without a reply
+/// if the store of the requests failed.
+async fn reply_delay<S: AsyncWrite + Unpin>(mut stream: S, keeper: TimeKeeper, delay: std::result::Result<f32, crate::Error>) {
+    let response = match delay {
+        Ok(delay) => delay,
+        Err(error) => return error::report(&error),
+    };
+    if let Err(error) = stream.write_all((format!("{:.3}", response)).as_bytes()).await {
+        keeper.metrics().record_connection_error();
+        error::report(&crate::Error::Connection(error));
+    }
+}
+
+/// Reads commands of the line protocol and replies to each of them, in order,
+/// until the client closes the connection or the server shuts down. Clients
+/// may send commands without waiting for the replies to the previous ones.
+///
+/// A first line that is not a command is handled in legacy mode: it holds
+/// the service name, optionally followed by the cost of the request, and the
+/// reply is the bare delay. The connection is closed without a reply if the
+/// service is unknown or the cost is not a positive number.
+pub async fn handle_named_connection<S>(stream: S, registry: Arc<Registry>, mut closing: watch::Receiver<bool>)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut reader = BufReader::new(stream);
+    let Some(mut line) = read_line(&mut reader).await else {
+        return;
+    };
+
+    if let Err(ProtocolError::UnknownCommand(_)) = Command::parse(&line) {
+        return handle_legacy_request(reader.into_inner(), &line, &registry).await;
+    }
+
+    let mut responses = String::new();
+    loop {
+        if !line.is_empty() {
+            let outcome = match Command::parse(&line) {
+                Ok(command) => registry.run(move |registry| command.execute(registry)).await,
+                Err(error) => Err(error),
+            };
+            responses.push_str(&protocol::reply(&outcome));
+            responses.push('\n');
+        }
+
+        // Replies to pipelined commands are written together, once every
+        // command already received has been handled
+        if reader.buffer().is_empty() {
+            if let Err(error) = reader.get_mut().write_all(responses.as_bytes()).await {
+                return error::report(&crate::Error::Connection(error));
+            }
+            responses.clear();
+        }
+
+        line = tokio::select! {
+            line = read_line(&mut reader) => match line {
+                Some(line) => line,
+                None => break,
+            },
+            _ = closing.wait_for(|closing| *closing), if responses.is_empty() => break,
+        };
+    }
+    if let Err(error) = reader.get_mut().write_all(responses.as_bytes()).await {
+        error::report(&crate::Error::Connection(error));
+    }
+}
+
+async fn handle_legacy_request<S: AsyncWrite + Unpin>(stream: S, line: &str, registry: &Arc<Registry>) {
+    let mut words = line.split_whitespace();
+    let name = words.next().unwrap_or_default();
+    let cost = match words.next().map(str::parse::<u32>) {
+        None => 1,
+        Some(Ok(cost)) if cost > 0 => cost,
+        Some(_) => return,
+    };
+    if words.next().is_some() {
+        return;
+    }
+
+    let name = name.to_string();
+    let request = registry.run(move |registry| {
+        let keeper = get_available(registry, &name)?;
+        let delay = keeper.try_get_weighted_delay(cost);
+        Some((keeper, delay))
+    });
+    if let Some((keeper, delay)) = request.await {
+        reply_delay(stream, keeper, delay).await;
+    }
+}
+
+/// Reads an administrative command and replies with `OK` once it has been
+/// carried out, or `ERR` followed by the reason it failed.
+pub async fn handle_admin_connection(stream: TcpStream, admin: mpsc::Sender<AdminRequest>) {
+    let mut reader = BufReader::new(stream);
+    let Some(line) = read_line(&mut reader).await else {
+        return;
+    };
+
+    let outcome = match line.to_ascii_uppercase().as_str() {
+        "RELOAD" => {
+            let (reply, response) = oneshot::channel();
+            let request = AdminRequest { command: AdminCommand::Reload, reply };
+
+            match admin.send(request).await {
+                Ok(()) => response.await.unwrap_or_else(|_| Err(String::from("no response"))),
+                Err(_) => Err(String::from("server is not accepting commands")),
+            }
+        }
+        _ => Err(format!("unknown command `{}`", line)),
+    };
+
+    let response = match outcome {
+        Ok(()) => String::from("OK\n"),
+        Err(reason) => {
+            let reason: Vec<&str> = reason.lines().map(str::trim).filter(|line| !line.is_empty()).collect();
+            format!("ERR {}\n", reason.join(" "))
+        }
+    };
+    if let Err(error) = reader.into_inner().write_all(response.as_bytes()).await {
+        error::report(&crate::Error::Connection(error));
+    }
+}
+
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tokio::io::*;
+    use tokio::net::TcpStream;
+    use tokio::sync::mpsc;
+
+    use crate::{error, Config, Registry};
+    use crate::config::ServiceConfig;
+    use crate::server::{AdminCommand, Server};
+    use crate::testing::free_port;
+
+    async fn query(address: SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        stream.shutdown().await.unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    /// Reloading changes the limits of a service without losing its recorded
+    /// requests, and moves listeners to their new addresses.
+    async fn reload() {
+        let mut config = Config::default();
+        config.server.ip = Some("127.0.0.1".parse().unwrap());
+        config.server.named_port = Some(free_port());
+        config.services.push(ServiceConfig::new("service", 2, 60));
+
+        let (sender, _receiver) = mpsc::channel(1);
+        let mut server = Server::start(&config, Arc::new(Registry::from_config(&config)), sender).await.unwrap();
+        let named = config.named_address().unwrap();
+        assert_eq!(query(named, "service\n").await, "0.000");
+
+        config.services[0].requests = 1;
+        config.services[0].port = Some(free_port());
+        server.reload(&config).await.unwrap();
+
+        let dedicated = config.service_address(&config.services[0]).unwrap();
+        assert_ne!(query(dedicated, "").await, "0.000", "Request before the reload should count.");
+        assert_eq!(query(named, "unknown\n").await, "");
+        assert_eq!(server.addresses().len(), 2);
+    }
+
+    #[tokio::test]
+    /// Clients of the named port may send the cost of their request after the
+    /// name of the service.
+    async fn weighted_request() {
+        let mut config = Config::default();
+        config.server.ip = Some("127.0.0.1".parse().unwrap());
+        config.server.named_port = Some(free_port());
+        config.services.push(ServiceConfig::new("service", 3, 60));
+
+        let (sender, _receiver) = mpsc::channel(1);
+        let _server = Server::start(&config, Arc::new(Registry::from_config(&config)), sender).await.unwrap();
+        let named = config.named_address().unwrap();
+
+        assert_eq!(query(named, "service 0\n").await, "");
+        assert_eq!(query(named, "service two\n").await, "");
+        assert_eq!(query(named, "service 2\n").await, "0.000");
+        assert_eq!(query(named, "service\n").await, "0.000");
+        assert_ne!(query(named, "service 1\n").await, "0.000");
+    }
+
+    #[tokio::test]
+    /// The named port replies to commands of the line protocol, and to bare
+    /// service names in legacy mode.
+    async fn protocol() {
+        let mut config = Config::default();
+        config.server.ip = Some("127.0.0.1".parse().unwrap());
+        config.server.named_port = Some(free_port());
+        config.services.push(ServiceConfig::new("service", 1, 60));
+
+        let (sender, _receiver) = mpsc::channel(1);
+        let _server = Server::start(&config, Arc::new(Registry::from_config(&config)), sender).await.unwrap();
+        let named = config.named_address().unwrap();
+
+        assert_eq!(query(named, "ACQUIRE service\n").await, "JARL/1 OK 0.000\n");
+        assert_eq!(query(named, "RELEASE service\n").await, "JARL/1 OK\n");
+        assert_eq!(query(named, "service\n").await, "0.000");
+        assert_eq!(query(named, "ACQUIRE unknown\n").await, "JARL/1 ERR unknown-service no service named `unknown`\n");
+        assert_eq!(query(named, "unknown\n").await, "");
+    }
+
+    #[tokio::test]
+    /// Connections to a service that cannot record requests are closed
+    /// without a reply, and counted as failures.
+    async fn unavailable_service() {
+        let mut config = Config::default();
+        config.server.ip = Some("127.0.0.1".parse().unwrap());
+        config.services.push(ServiceConfig { port: Some(free_port()), ..ServiceConfig::new("service", 1, 60) });
+
+        let registry = Arc::new(Registry::from_config(&config));
+        let (sender, _receiver) = mpsc::channel(1);
+        let _server = Server::start(&config, registry.clone(), sender).await.unwrap();
+        let dedicated = config.service_address(&config.services[0]).unwrap();
+
+        registry.set_available(false);
+        let before = error::failures();
+        assert_eq!(query(dedicated, "").await, "");
+        assert!(error::failures() > before);
+
+        registry.set_available(true);
+        assert_eq!(query(dedicated, "").await, "0.000");
+    }
+
+    #[tokio::test]
+    /// Waiting for a store that does not reply holds up the requests to the
+    /// store, but not the other connections.
+    async fn slow_store() {
+        let store = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut config = Config::default();
+        config.server.ip = Some("127.0.0.1".parse().unwrap());
+        config.server.named_port = Some(free_port());
+        config.store.redis = Some(store.local_addr().unwrap().to_string());
+        config.services.push(ServiceConfig::new("service", 1, 60));
+
+        let (sender, _receiver) = mpsc::channel(1);
+        let _server = Server::start(&config, Arc::new(Registry::from_config(&config)), sender).await.unwrap();
+        let named = config.named_address().unwrap();
+
+        let acquired = tokio::spawn(query(named, "ACQUIRE service\n"));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let status = tokio::time::timeout(Duration::from_millis(500), query(named, "STATUS\n")).await;
+        assert_eq!(status.unwrap(), "JARL/1 OK services=1\n");
+        assert!(acquired.await.unwrap().starts_with("JARL/1 ERR unavailable"));
+    }
+
+    #[tokio::test]
+    /// Waiting for a store that does not reply holds up the datagrams to the
+    /// store, but not the other datagrams.
+    async fn slow_store_udp() {
+        let store = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut config = Config::default();
+        config.server.ip = Some("127.0.0.1".parse().unwrap());
+        config.server.udp_port = Some(free_port());
+        config.store.redis = Some(store.local_addr().unwrap().to_string());
+        config.services.push(ServiceConfig::new("service", 1, 60));
+
+        let (sender, _receiver) = mpsc::channel(1);
+        let _server = Server::start(&config, Arc::new(Registry::from_config(&config)), sender).await.unwrap();
+
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(config.udp_address().unwrap()).await.unwrap();
+        client.send(b"1 ACQUIRE service").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        client.send(b"2 STATUS").await.unwrap();
+
+        let mut buffer = [0; 128];
+        let length = tokio::time::timeout(Duration::from_millis(500), client.recv(&mut buffer)).await.unwrap().unwrap();
+        assert_eq!(&buffer[..length], b"2 JARL/1 OK services=1\n");
+        let length = client.recv(&mut buffer).await.unwrap();
+        assert!(buffer[..length].starts_with(b"1 JARL/1 ERR unavailable"));
+    }
+
+    #[tokio::test]
+    /// The UDP listener answers datagrams, and moves when reloading.
+    async fn udp_listener() {
+        let mut config = Config::default();
+        config.server.ip = Some("127.0.0.1".parse().unwrap());
+        config.server.udp_port = Some(free_port());
+        config.services.push(ServiceConfig::new("service", 1, 60));
+
+        let (sender, _receiver) = mpsc::channel(1);
+        let mut server = Server::start(&config, Arc::new(Registry::from_config(&config)), sender).await.unwrap();
+
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(b"1 ACQUIRE service", config.udp_address().unwrap()).await.unwrap();
+        let mut buffer = [0; 64];
+        let length = client.recv(&mut buffer).await.unwrap();
+        assert_eq!(&buffer[..length], b"1 JARL/1 OK 0.000\n");
+
+        config.server.udp_port = Some(free_port());
+        server.reload(&config).await.unwrap();
+        assert_eq!(server.udp_address(), config.udp_address());
+
+        config.server.udp_port = None;
+        server.reload(&config).await.unwrap();
+        assert_eq!(server.udp_address(), None);
+    }
+
+    #[tokio::test]
+    #[cfg(unix)]
+    /// The Unix socket replaces a stale socket file, serves the line protocol
+    /// and is removed when shutting down.
+    async fn unix_socket() {
+        use std::os::unix::fs::PermissionsExt;
+        use tokio::net::UnixStream;
+
+        let path = std::env::temp_dir().join(format!("jarl-test-{}.sock", free_port()));
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+
+        let mut config = Config::default();
+        config.server.unix_socket = Some(path.clone());
+        config.server.unix_socket_mode = Some(0o600);
+        config.services.push(ServiceConfig::new("service", 1, 60));
+        config.validate().unwrap();
+
+        let (sender, _receiver) = mpsc::channel(1);
+        let mut server = Server::start(&config, Arc::new(Registry::from_config(&config)), sender).await.unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        let staging = format!(".{}.{}", path.file_name().unwrap().to_string_lossy(), std::process::id());
+        assert!(!path.with_file_name(staging).exists(), "Socket should be bound out of reach and moved in place.");
+
+        let mut stream = UnixStream::connect(&path).await.unwrap();
+        stream.write_all(b"ACQUIRE service\n").await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert_eq!(response, "JARL/1 OK 0.000\n");
+
+        config.server.unix_socket_mode = Some(0o660);
+        server.reload(&config).await.unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o660);
+
+        // A socket still being listened on is not replaced
+        let (sender, _receiver) = mpsc::channel(1);
+        let error = Server::start(&config, Arc::new(Registry::from_config(&config)), sender).await.err().unwrap();
+        assert!(matches!(error, crate::Error::Bind(_, ref error) if error.kind() == ErrorKind::AddrInUse));
+
+        assert!(server.shutdown(Duration::from_secs(5)).await);
+        assert!(!path.exists(), "Socket file should be removed.");
+
+        // Nor is a file that is not a socket
+        std::fs::write(&path, "").unwrap();
+        let (sender, _receiver) = mpsc::channel(1);
+        assert!(Server::start(&config, Arc::new(Registry::from_config(&config)), sender).await.is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn admin_reload() {
+        let mut config = Config::default();
+        config.server.ip = Some("127.0.0.1".parse().unwrap());
+        config.server.admin_port = Some(free_port());
+
+        let (sender, mut receiver) = mpsc::channel(1);
+        let _server = Server::start(&config, Arc::new(Registry::from_config(&config)), sender).await.unwrap();
+        let admin = config.admin_address().unwrap();
+
+        tokio::spawn(async move {
+            let request = receiver.recv().await.unwrap();
+            assert_eq!(request.command, AdminCommand::Reload);
+            request.reply.send(Err(String::from("broken file"))).unwrap();
+        });
+
+        assert_eq!(query(admin, "reload\n").await, "ERR broken file\n");
+        assert_eq!(query(admin, "nothing\n").await, "ERR unknown command `nothing`\n");
+    }
+
+    #[tokio::test]
+    /// Protocol connections stay open for pipelined commands, and are closed
+    /// once idle when shutting down.
+    async fn persistent_connection() {
+        let mut config = Config::default();
+        config.server.ip = Some("127.0.0.1".parse().unwrap());
+        config.server.named_port = Some(free_port());
+        config.services.push(ServiceConfig::new("service", 2, 60));
+
+        let (sender, _receiver) = mpsc::channel(1);
+        let server = Server::start(&config, Arc::new(Registry::from_config(&config)), sender).await.unwrap();
+
+        let stream = TcpStream::connect(config.named_address().unwrap()).await.unwrap();
+        let mut reader = BufReader::new(stream);
+        reader.get_mut().write_all(b"ACQUIRE service\nPEEK service\n\nacquire service\n").await.unwrap();
+
+        let mut responses = Vec::new();
+        for _ in 0..3 {
+            let mut line = String::new();
+            reader.read_line(&mut line).await.unwrap();
+            responses.push(line);
+        }
+        assert_eq!(responses, ["JARL/1 OK 0.000\n", "JARL/1 OK 0.000\n", "JARL/1 OK 0.000\n"]);
+
+        reader.get_mut().write_all(b"STATUS unknown\n").await.unwrap();
+        let mut line = String::new();
+        reader.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "JARL/1 ERR unknown-service no service named `unknown`\n");
+
+        assert!(server.shutdown(Duration::from_secs(5)).await, "Idle connection should be closed.");
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).await.unwrap();
+        assert_eq!(rest, "");
+    }
+
+    #[tokio::test]
+    /// Shutting down closes the listeners but lets accepted connections finish.
+    async fn shutdown() {
+        let mut config = Config::default();
+        config.server.ip = Some("127.0.0.1".parse().unwrap());
+        config.server.named_port = Some(free_port());
+        config.services.push(ServiceConfig::new("service", 1, 1));
+
+        let (sender, _receiver) = mpsc::channel(1);
+        let server = Server::start(&config, Arc::new(Registry::from_config(&config)), sender).await.unwrap();
+        let named = config.named_address().unwrap();
+
+        // Accepted, but waiting for the service name when the shutdown starts
+        let mut pending = TcpStream::connect(named).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let shutdown = tokio::spawn(server.shutdown(Duration::from_secs(5)));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(TcpStream::connect(named).await.is_err(), "Listener should be closed.");
+        assert!(!shutdown.is_finished(), "Shutdown should wait for the pending connection.");
+
+        pending.write_all(b"service\n").await.unwrap();
+        let mut response = String::new();
+        pending.read_to_string(&mut response).await.unwrap();
+        assert_eq!(response, "0.000");
+        assert!(shutdown.await.unwrap(), "Every connection should have been handled.");
+    }
+
+    #[tokio::test]
+    async fn shutdown_timeout() {
+        let mut config = Config::default();
+        config.server.ip = Some("127.0.0.1".parse().unwrap());
+        config.server.named_port = Some(free_port());
+
+        let (sender, _receiver) = mpsc::channel(1);
+        let server = Server::start(&config, Arc::new(Registry::from_config(&config)), sender).await.unwrap();
+        let _idle = TcpStream::connect(config.named_address().unwrap()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        assert!(!server.shutdown(Duration::from_millis(50)).await);
+    }
+}
@@ -0,0 +1,137 @@
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{LimiterState, Registry};
+
+
+/// Requests recorded by a single `Keeper`.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct KeeperState {
+    /// Timestamps of the requests still within the period, oldest first
+    pub timestamps: Vec<f64>,
+    pub backoff_count: f32,
+}
+
+impl KeeperState {
+
+    pub fn map_timestamps(&self, convert: impl Fn(f64) -> f64) -> Self {
+        KeeperState {
+            timestamps: self.timestamps.iter().copied().map(convert).collect(),
+            backoff_count: self.backoff_count,
+        }
+    }
+
+}
+
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ServiceState {
+    pub name: String,
+    #[serde(flatten)]
+    pub state: LimiterState,
+}
+
+
+/// State of every service of a registry, saved to a TOML file so that the
+/// requests already made are still enforced after a restart. Timestamps are
+/// kept on the UNIX clock, as the monotonic clock starts over with every
+/// process.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Snapshot {
+    #[serde(default, rename = "service")]
+    pub services: Vec<ServiceState>,
+}
+
+impl Snapshot {
+
+    pub fn capture(registry: &Registry) -> Self {
+        let clock = registry.clock();
+        let services = registry.entries().into_iter()
+            .map(|(name, keeper)| {
+                let state = keeper.read().state().map_timestamps(|timestamp| clock.local_to_unix(timestamp));
+                ServiceState { name, state }
+            })
+            .collect();
+
+        Snapshot { services }
+    }
+
+    /// Restores the state of every service that is still registered. Services
+    /// that are no longer registered, or that changed algorithm, are ignored.
+    pub fn restore(&self, registry: &Registry) {
+        let clock = registry.clock();
+        for service in &self.services {
+            if let Some(keeper) = registry.get(&service.name) {
+                keeper.lock().restore(&service.state.map_timestamps(|timestamp| clock.unix_to_local(timestamp)));
+            }
+        }
+    }
+
+    /// Reads a snapshot, returning `None` if the file does not exist yet.
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error),
+        };
+
+        toml::from_str(&contents)
+            .map(Some)
+            .map_err(|error| Error::new(ErrorKind::InvalidData, error.to_string()))
+    }
+
+    /// Writes the snapshot to a temporary file next to `path` and moves it in
+    /// place, so that a crash while saving never leaves a truncated file.
+    pub async fn save(&self, path: &Path) -> Result<()> {
+        let contents = toml::to_string(self).map_err(Error::other)?;
+
+        let mut temporary = PathBuf::from(path).into_os_string();
+        temporary.push(".tmp");
+
+        tokio::fs::write(&temporary, contents).await?;
+        tokio::fs::rename(&temporary, path).await
+    }
+
+}
+
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use crate::{Algorithm, Keeper, Registry, Snapshot};
+
+    #[tokio::test]
+    /// A saved snapshot restores the requests of the services still registered.
+    async fn save_and_restore() {
+        let path = std::env::temp_dir().join(format!("jarl-state-{}.toml", std::process::id()));
+        assert_eq!(Snapshot::load(&path).unwrap(), None);
+
+        let registry = Registry::new();
+        registry.insert("kept", Box::new(Keeper::try_new(1, 60).unwrap())).unwrap();
+        registry.insert("removed", Algorithm::TokenBucket.build(1, 60)).unwrap();
+        registry.get("kept").unwrap().get_delay();
+
+        Snapshot::capture(&registry).save(&path).await.unwrap();
+        let snapshot = Snapshot::load(&path).unwrap().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(snapshot.services.len(), 2);
+
+        let restarted = Registry::new();
+        let kept = restarted.insert("kept", Box::new(Keeper::try_new(1, 60).unwrap())).unwrap();
+        snapshot.restore(&restarted);
+
+        assert!(kept.get_delay() > 0.0, "Request before the restart should count.");
+    }
+
+    #[test]
+    fn reject_invalid_file() {
+        let path = std::env::temp_dir().join(format!("jarl-invalid-{}.toml", std::process::id()));
+        std::fs::write(&path, "[[service]]\nname = 1").unwrap();
+        let outcome = Snapshot::load(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(outcome.is_err());
+    }
+}
@@ -0,0 +1,187 @@
+use std::sync::Arc;
+
+use ::bounded_vec_deque::BoundedVecDeque;
+
+use crate::{Error, KeeperState};
+
+mod redis;
+
+pub use redis::{Redis, RedisLog};
+
+
+/// Limit enforced by a sliding log, given to its store with every update.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Window {
+    pub limit: u32,
+    pub period: f64,
+    /// Seconds added to the delay for every unit of the backoff count
+    pub base_delay: f32,
+}
+
+
+/// Where a sliding log keeps the timestamps of the units it recorded, oldest
+/// first, and its backoff count.
+///
+/// Every update is atomic, so that several replicas of jarl sharing the same
+/// external store enforce a single limit. Such a store may take the time from
+/// a clock of its own instead of `now`, so that the replicas agree on it, and
+/// then shifts the timestamps it is given or returns to the clock of `now`.
+pub trait LogStore: Send + Sync {
+
+    /// Pushes `cost` timestamps at `now`, keeping the last `limit` ones, and
+    /// returns the delay until the newest timestamp pushed out is a full
+    /// period old, backing off by the units of the consecutive delayed
+    /// requests. Only returns the delay if `record` is false.
+    fn acquire(&mut self, window: &Window, now: f64, cost: u32, record: bool) -> Result<f32, Error>;
+
+    /// Pushes `cost` timestamps at the earliest slot, no earlier than `now`,
+    /// that keeps no more than `limit` units within any period, spaced by at
+    /// least `period / limit` seconds from the newest one when it has to
+    /// wait. Returns the delay until the slot, and only returns it if
+    /// `record` is false.
+    fn reserve(&mut self, window: &Window, now: f64, cost: u32, record: bool) -> Result<f32, Error>;
+
+    /// Drops the `cost` newest timestamps, along with their share of the
+    /// backoff count.
+    fn release(&mut self, cost: u32) -> Result<(), Error>;
+
+    /// Backoff count after the last update made through this store. Stores
+    /// shared with other replicas may hold a more recent one.
+    fn backoff_count(&self) -> f32;
+
+    /// Timestamps and backoff count, as of `now`.
+    fn state(&self, now: f64) -> Result<KeeperState, Error>;
+
+    /// Drops the oldest timestamps beyond `limit`.
+    fn resize(&mut self, limit: u32) -> Result<(), Error>;
+
+    /// Replaces the timestamps and backoff count with the ones of `state`,
+    /// which must hold no more than `limit` timestamps, at `now`.
+    fn replace(&mut self, window: &Window, now: f64, state: &KeeperState) -> Result<(), Error>;
+
+}
+
+
+/// External backend keeping the sliding logs of the services, such as a
+/// Redis server.
+pub trait Store: Send + Sync {
+
+    /// Log of the service `name`, shared with the replicas using the same
+    /// backend.
+    fn log(self: Arc<Self>, name: &str) -> Box<dyn LogStore>;
+
+}
+
+
+/// Delay of a request costing `cost` units at `now`, and the backoff count
+/// after it, from the newest timestamp it pushes out of the log.
+fn delay(window: &Window, now: f64, cost: u32, last: Option<f64>, backoff_count: f32) -> (f32, f32) {
+    if let Some(last) = last {
+        let diff = now - last;
+
+        if diff < window.period {
+            let adjustment = (window.period - diff) as f32;
+            let backoff_count = backoff_count + cost as f32;
+
+            return (window.base_delay * backoff_count + adjustment, backoff_count);
+        }
+    }
+    (0.0, 0.0)
+}
+
+
+/// Sliding log kept in the memory of this process.
+pub struct MemoryLog {
+    queue: BoundedVecDeque<f64>,
+    backoff_count: f32,
+}
+
+impl MemoryLog {
+
+    pub fn new(limit: u32) -> Self {
+        MemoryLog {
+            queue: BoundedVecDeque::new((limit + 1) as usize),
+            backoff_count: 0.0,
+        }
+    }
+
+    fn push(&mut self, limit: u32, timestamp: f64, cost: u32) {
+        for _ in 0..cost {
+            self.queue.push_back(timestamp);
+            if self.queue.len() > limit as usize {
+                self.queue.pop_front();
+            }
+        }
+    }
+
+}
+
+impl LogStore for MemoryLog {
+
+    fn acquire(&mut self, window: &Window, now: f64, cost: u32, record: bool) -> Result<f32, Error> {
+        // The newest timestamp pushed out of the queue is the one that must be
+        // a full period old for every unit of the request to fit
+        let pushed_out = (self.queue.len() + cost as usize).checked_sub(window.limit as usize + 1);
+        let last = pushed_out.and_then(|index| self.queue.get(index).copied());
+        let (delay, backoff_count) = delay(window, now, cost, last, self.backoff_count);
+
+        if record {
+            self.push(window.limit, now, cost);
+            self.backoff_count = backoff_count;
+        }
+        Ok(delay)
+    }
+
+    fn reserve(&mut self, window: &Window, now: f64, cost: u32, record: bool) -> Result<f32, Error> {
+        let mut slot = now;
+        let room = (window.limit - cost) as usize;
+
+        if self.queue.len() > room {
+            let oldest = self.queue[self.queue.len() - room - 1];
+            if oldest + window.period > now {
+                let newest = self.queue.back().copied().unwrap_or(oldest);
+                let spacing = window.period / window.limit as f64;
+                slot = (oldest + window.period).max(newest + spacing);
+            }
+        }
+
+        if record {
+            self.push(window.limit, slot, cost);
+        }
+        Ok((slot - now) as f32)
+    }
+
+    fn release(&mut self, cost: u32) -> Result<(), Error> {
+        for _ in 0..cost {
+            self.queue.pop_back();
+        }
+        self.backoff_count = (self.backoff_count - cost as f32).max(0.0);
+        Ok(())
+    }
+
+    fn backoff_count(&self) -> f32 {
+        self.backoff_count
+    }
+
+    fn state(&self, _now: f64) -> Result<KeeperState, Error> {
+        Ok(KeeperState {
+            timestamps: self.queue.iter().copied().collect(),
+            backoff_count: self.backoff_count,
+        })
+    }
+
+    fn resize(&mut self, limit: u32) -> Result<(), Error> {
+        let excess = self.queue.len().saturating_sub(limit as usize);
+        self.queue.drain(..excess);
+        self.queue.set_max_len((limit + 1) as usize);
+        Ok(())
+    }
+
+    fn replace(&mut self, _window: &Window, _now: f64, state: &KeeperState) -> Result<(), Error> {
+        self.queue.clear();
+        self.queue.extend(state.timestamps.iter().copied());
+        self.backoff_count = state.backoff_count;
+        Ok(())
+    }
+
+}
@@ -0,0 +1,587 @@
+use std::io::{self, BufRead, BufReader, ErrorKind, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::{Error, KeeperState};
+use crate::store::{LogStore, Store, Window};
+
+
+/// Script updating a sliding log atomically, loaded once per connection.
+const SCRIPT: &str = include_str!("sliding_log.lua");
+
+/// Longest wait to connect to the server, or for it to reply.
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Shortest wait between two attempts to reconnect to the server.
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Most connections kept open while idle, the others are closed once used.
+const MAX_IDLE_CONNECTIONS: usize = 16;
+
+
+/// Reply of a Redis server, in RESP2.
+#[derive(Debug, PartialEq)]
+enum Value {
+    Status(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Option<Vec<Value>>),
+}
+
+impl Value {
+
+    fn into_string(self) -> io::Result<String> {
+        match self {
+            Value::Status(text) => Ok(text),
+            Value::Bulk(Some(bytes)) => String::from_utf8(bytes).map_err(|_| invalid("reply is not UTF-8")),
+            Value::Error(message) => Err(io::Error::other(message)),
+            value => Err(invalid(&format!("unexpected reply {:?}", value))),
+        }
+    }
+
+    fn into_number(self) -> io::Result<f64> {
+        let text = self.into_string()?;
+        text.parse().map_err(|_| invalid(&format!("{:?} is not a number", text)))
+    }
+
+    fn into_array(self) -> io::Result<Vec<Value>> {
+        match self {
+            Value::Array(Some(values)) => Ok(values),
+            Value::Error(message) => Err(io::Error::other(message)),
+            value => Err(invalid(&format!("unexpected reply {:?}", value))),
+        }
+    }
+
+}
+
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message)
+}
+
+
+/// Reads a reply, or a command sent by a client, which is an array of bulk
+/// strings.
+fn read_value(reader: &mut impl BufRead) -> io::Result<Value> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(ErrorKind::UnexpectedEof.into());
+    }
+    let line = line.strip_suffix("\r\n").ok_or_else(|| invalid("line does not end with CRLF"))?;
+    if line.is_empty() {
+        return Err(invalid("empty line"));
+    }
+
+    let (kind, text) = line.split_at(1);
+    let length = || text.parse::<i64>().map_err(|_| invalid(&format!("invalid length {:?}", text)));
+    match kind {
+        "+" => Ok(Value::Status(text.to_string())),
+        "-" => Ok(Value::Error(text.to_string())),
+        ":" => Ok(Value::Integer(length()?)),
+        "$" => match usize::try_from(length()?) {
+            Ok(length) => {
+                let mut bytes = vec![0; length + 2];
+                reader.read_exact(&mut bytes)?;
+                bytes.truncate(length);
+                Ok(Value::Bulk(Some(bytes)))
+            },
+            Err(_) => Ok(Value::Bulk(None)),
+        },
+        "*" => match usize::try_from(length()?) {
+            Ok(length) => (0..length).map(|_| read_value(reader)).collect::<io::Result<_>>().map(|values| Value::Array(Some(values))),
+            Err(_) => Ok(Value::Array(None)),
+        },
+        _ => Err(invalid(&format!("unknown reply type {:?}", kind))),
+    }
+}
+
+
+/// Writes a command as an array of bulk strings.
+fn write_command<T: AsRef<[u8]>>(writer: &mut impl Write, args: &[T]) -> io::Result<()> {
+    let mut buffer = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        let arg = arg.as_ref();
+        buffer.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        buffer.extend_from_slice(arg);
+        buffer.extend_from_slice(b"\r\n");
+    }
+    writer.write_all(&buffer)
+}
+
+
+/// Blocking connection to a Redis server, with the script loaded.
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    /// SHA1 digest of the script, to run it by
+    sha: String,
+}
+
+impl Connection {
+
+    fn open(address: &str, password: Option<&str>) -> io::Result<Self> {
+        let mut error = io::Error::new(ErrorKind::NotFound, format!("{} resolves to no address", address));
+        for address in address.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&address, TIMEOUT) {
+                Ok(stream) => return Connection::start(stream, password),
+                Err(failure) => error = failure,
+            }
+        }
+        Err(error)
+    }
+
+    fn start(stream: TcpStream, password: Option<&str>) -> io::Result<Self> {
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        stream.set_nodelay(true)?;
+
+        let mut connection = Connection {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+            sha: String::new(),
+        };
+        if let Some(password) = password {
+            connection.command(&["AUTH", password])?.into_string()?;
+        }
+        connection.load()?;
+        Ok(connection)
+    }
+
+    fn command<T: AsRef<[u8]>>(&mut self, args: &[T]) -> io::Result<Value> {
+        write_command(&mut self.writer, args)?;
+        read_value(&mut self.reader)
+    }
+
+    fn load(&mut self) -> io::Result<()> {
+        self.sha = self.command(&["SCRIPT", "LOAD", SCRIPT])?.into_string()?;
+        Ok(())
+    }
+
+    /// Runs the script, loading it again if the server lost it, such as after
+    /// a SCRIPT FLUSH.
+    fn eval(&mut self, keys: &[String], args: &[String]) -> io::Result<Value> {
+        let mut command = vec!["EVALSHA".to_string(), self.sha.clone(), keys.len().to_string()];
+        command.extend(keys.iter().cloned());
+        command.extend(args.iter().cloned());
+
+        match self.command(&command)? {
+            Value::Error(message) if message.starts_with("NOSCRIPT") => {
+                self.load()?;
+                command[1] = self.sha.clone();
+                self.command(&command)
+            },
+            value => Ok(value),
+        }
+    }
+
+}
+
+
+/// Idle connections to the server, and whether it could be reached.
+struct Pool {
+    idle: Vec<Connection>,
+    /// Last time a connection failed
+    failed: Option<Instant>,
+    /// Whether the server could be reached at the last attempt, to log the
+    /// changes
+    reachable: bool,
+}
+
+
+/// Redis server, or any server speaking its protocol and running its Lua
+/// scripts such as Valkey, sharing the sliding logs of the services between
+/// the replicas of jarl using it.
+///
+/// Every update of a log runs a script, so that it is atomic. Each service
+/// has its own keys, `<prefix>:{<service>}:log` and
+/// `<prefix>:{<service>}:backoff`, which expire once the requests they
+/// record are older than the period.
+///
+/// Updates block until the server replies, each one on a connection of its
+/// own taken from a pool, so that a slow update does not hold up the others.
+/// After a connection fails, updates fail right away for a second before
+/// connecting again.
+pub struct Redis {
+    address: String,
+    password: Option<String>,
+    prefix: String,
+    pool: Mutex<Pool>,
+}
+
+impl Redis {
+
+    pub fn new(address: &str, password: Option<&str>, prefix: &str) -> Self {
+        Redis {
+            address: address.to_string(),
+            password: password.map(str::to_string),
+            prefix: prefix.to_string(),
+            pool: Mutex::new(Pool { idle: Vec::new(), failed: None, reachable: true }),
+        }
+    }
+
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    fn pool(&self) -> MutexGuard<'_, Pool> {
+        self.pool.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// An idle connection, or a new one unless a connection failed less than
+    /// `RETRY_INTERVAL` ago.
+    fn connect(&self) -> io::Result<Connection> {
+        {
+            let mut pool = self.pool();
+            if let Some(connection) = pool.idle.pop() {
+                return Ok(connection);
+            }
+            if pool.failed.is_some_and(|failed| failed.elapsed() < RETRY_INTERVAL) {
+                let message = format!("{} is unreachable", self.address);
+                return Err(io::Error::new(ErrorKind::NotConnected, message));
+            }
+        }
+
+        let connection = Connection::open(&self.address, self.password.as_deref()).inspect_err(|error| self.fail(error))?;
+        let mut pool = self.pool();
+        if !pool.reachable {
+            tracing::info!(address = %self.address, "store reached");
+            pool.reachable = true;
+        }
+        Ok(connection)
+    }
+
+    /// Closes the idle connections, which most likely failed as well, and
+    /// waits before connecting again.
+    fn fail(&self, error: &io::Error) {
+        let mut pool = self.pool();
+        if pool.reachable {
+            tracing::warn!(address = %self.address, %error, "store unreachable");
+        }
+        pool.idle.clear();
+        pool.failed = Some(Instant::now());
+        pool.reachable = false;
+    }
+
+    /// Runs the script on `keys`. The connection is closed if it fails, but
+    /// not if the script does.
+    fn eval(&self, keys: &[String], args: &[String]) -> Result<Value, Error> {
+        let mut connection = self.connect().map_err(Error::Store)?;
+        let value = connection.eval(keys, args).map_err(|error| {
+            self.fail(&error);
+            Error::Store(error)
+        })?;
+
+        let mut pool = self.pool();
+        if pool.idle.len() < MAX_IDLE_CONNECTIONS {
+            pool.idle.push(connection);
+        }
+        match value {
+            Value::Error(message) => Err(Error::Store(io::Error::other(message))),
+            value => Ok(value),
+        }
+    }
+
+}
+
+
+impl Store for Redis {
+
+    fn log(self: Arc<Self>, name: &str) -> Box<dyn LogStore> {
+        Box::new(RedisLog::new(self, name))
+    }
+
+}
+
+
+/// Sliding log of a service kept by a Redis server.
+pub struct RedisLog {
+    redis: Arc<Redis>,
+    /// Keys of the timestamps and of the backoff count
+    keys: [String; 2],
+    backoff_count: f32,
+}
+
+impl RedisLog {
+
+    pub fn new(redis: Arc<Redis>, service: &str) -> Self {
+        // The braces keep both keys in the same slot of a Redis Cluster
+        let keys = [
+            format!("{}:{{{}}}:log", redis.prefix, service),
+            format!("{}:{{{}}}:backoff", redis.prefix, service),
+        ];
+        RedisLog { redis, keys, backoff_count: 0.0 }
+    }
+
+    fn eval(&self, operation: &str, args: &[String]) -> Result<Value, Error> {
+        let args: Vec<String> = [operation.to_string()].into_iter().chain(args.iter().cloned()).collect();
+        self.redis.eval(&self.keys, &args)
+    }
+
+}
+
+fn flag(value: bool) -> String {
+    (value as u8).to_string()
+}
+
+impl LogStore for RedisLog {
+
+    fn acquire(&mut self, window: &Window, _now: f64, cost: u32, record: bool) -> Result<f32, Error> {
+        let args = [cost.to_string(), window.limit.to_string(), window.period.to_string(), window.base_delay.to_string(), flag(record)];
+        let reply = self.eval("acquire", &args)?.into_array().map_err(Error::Store)?;
+
+        let mut numbers = reply.into_iter().map(Value::into_number);
+        let (Some(delay), Some(backoff_count)) = (numbers.next(), numbers.next()) else {
+            return Err(Error::Store(invalid("acquire replied less than 2 values")));
+        };
+        let (delay, backoff_count) = (delay.map_err(Error::Store)?, backoff_count.map_err(Error::Store)?);
+
+        if record {
+            self.backoff_count = backoff_count as f32;
+        }
+        Ok(delay as f32)
+    }
+
+    fn reserve(&mut self, window: &Window, _now: f64, cost: u32, record: bool) -> Result<f32, Error> {
+        let args = [cost.to_string(), window.limit.to_string(), window.period.to_string(), flag(record)];
+        let delay = self.eval("reserve", &args)?.into_number().map_err(Error::Store)?;
+        Ok(delay as f32)
+    }
+
+    fn release(&mut self, cost: u32) -> Result<(), Error> {
+        self.eval("release", &[cost.to_string()])?;
+        self.backoff_count = (self.backoff_count - cost as f32).max(0.0);
+        Ok(())
+    }
+
+    fn backoff_count(&self) -> f32 {
+        self.backoff_count
+    }
+
+    fn state(&self, now: f64) -> Result<KeeperState, Error> {
+        let reply = self.eval("state", &[])?.into_array().map_err(Error::Store)?;
+        let mut numbers = reply.into_iter().map(Value::into_number);
+
+        let (Some(server_now), Some(backoff_count)) = (numbers.next(), numbers.next()) else {
+            return Err(Error::Store(invalid("state replied less than 2 values")));
+        };
+        let (server_now, backoff_count) = (server_now.map_err(Error::Store)?, backoff_count.map_err(Error::Store)?);
+        let timestamps = numbers.map(|timestamp| timestamp.map(|timestamp| timestamp - server_now + now))
+            .collect::<io::Result<_>>()
+            .map_err(Error::Store)?;
+        Ok(KeeperState { timestamps, backoff_count: backoff_count as f32 })
+    }
+
+    fn resize(&mut self, limit: u32) -> Result<(), Error> {
+        self.eval("resize", &[limit.to_string()])?;
+        Ok(())
+    }
+
+    fn replace(&mut self, window: &Window, now: f64, state: &KeeperState) -> Result<(), Error> {
+        let mut args = vec![now.to_string(), window.period.to_string(), state.backoff_count.to_string()];
+        args.extend(state.timestamps.iter().map(f64::to_string));
+        self.eval("replace", &args)?;
+        self.backoff_count = state.backoff_count;
+        Ok(())
+    }
+
+}
+
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use std::io::{self, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::{Clock, Error, Keeper, KeeperState, ManualClock};
+    use mlua::{Lua, Value as LuaValue};
+
+    use crate::store::Store;
+    use crate::store::redis::{read_value, Redis, Value, SCRIPT};
+    use crate::testing::free_port;
+
+    fn write_value(writer: &mut impl Write, value: &Value) -> io::Result<()> {
+        match value {
+            Value::Status(text) => write!(writer, "+{}\r\n", text),
+            Value::Error(message) => write!(writer, "-{}\r\n", message),
+            Value::Integer(number) => write!(writer, ":{}\r\n", number),
+            Value::Bulk(Some(bytes)) => {
+                write!(writer, "${}\r\n", bytes.len())?;
+                writer.write_all(bytes)?;
+                writer.write_all(b"\r\n")
+            },
+            Value::Array(Some(values)) => {
+                write!(writer, "*{}\r\n", values.len())?;
+                values.iter().try_for_each(|value| write_value(writer, value))
+            },
+            Value::Bulk(None) => write!(writer, "$-1\r\n"),
+            Value::Array(None) => write!(writer, "*-1\r\n"),
+        }
+    }
+
+    fn bulk(value: impl ToString) -> Value {
+        Value::Bulk(Some(value.to_string().into_bytes()))
+    }
+
+    /// Runs the script as a Redis server would at `now`, given the number of
+    /// keys followed by the keys and the arguments.
+    fn eval(lua: &Lua, now: f64, args: &[String]) -> Value {
+        let count: usize = args[0].parse().unwrap();
+        let globals = lua.globals();
+        globals.set("NOW", now).unwrap();
+        globals.set("KEYS", args[1..=count].to_vec()).unwrap();
+        globals.set("ARGV", args[count + 1..].to_vec()).unwrap();
+
+        match lua.load(SCRIPT).eval() {
+            Ok(value) => reply(value),
+            Err(error) => Value::Error(format!("ERR {}", error)),
+        }
+    }
+
+    /// Converts a value returned by a script into a reply, as Redis does.
+    fn reply(value: LuaValue<'_>) -> Value {
+        match value {
+            LuaValue::Boolean(true) => Value::Integer(1),
+            LuaValue::Integer(number) => Value::Integer(number),
+            LuaValue::Number(number) => Value::Integer(number as i64),
+            LuaValue::String(text) => Value::Bulk(Some(text.as_bytes().to_vec())),
+            LuaValue::Table(table) => {
+                if let Some(message) = table.get::<_, Option<String>>("err").unwrap() {
+                    return Value::Error(message);
+                }
+                if let Some(text) = table.get::<_, Option<String>>("ok").unwrap() {
+                    return Value::Status(text);
+                }
+                Value::Array(Some(table.sequence_values().map(|value| reply(value.unwrap())).collect()))
+            },
+            _ => Value::Bulk(None),
+        }
+    }
+
+    /// Runs the script in a Lua interpreter along with the commands of
+    /// `redis_mock.lua`, in place of a Redis server whose time is read from
+    /// `clock`, and returns its address.
+    fn mock(password: &'static str, clock: ManualClock) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let lua = Lua::new();
+        lua.load(include_str!("redis_mock.lua")).exec().unwrap();
+        let lua = Arc::new(Mutex::new(lua));
+
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let (lua, clock) = (lua.clone(), clock.clone());
+                thread::spawn(move || {
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    let mut writer = stream;
+                    while let Ok(Value::Array(Some(command))) = read_value(&mut reader) {
+                        let args: Vec<String> = command.into_iter().map(|arg| arg.into_string().unwrap()).collect();
+                        let reply = match args[0].as_str() {
+                            "AUTH" if args[1] == password => Value::Status("OK".to_string()),
+                            "AUTH" => Value::Error("WRONGPASS invalid password".to_string()),
+                            "SCRIPT" => bulk("sha"),
+                            "EVALSHA" => eval(&lua.lock().unwrap(), clock.now(), &args[2..]),
+                            _ => Value::Error("ERR unknown command".to_string()),
+                        };
+                        // Written at once, as the replies of Redis are
+                        let mut buffer = Vec::new();
+                        write_value(&mut buffer, &reply).unwrap();
+                        writer.write_all(&buffer).unwrap();
+                    }
+                });
+            }
+        });
+        address
+    }
+
+    #[test]
+    /// Replicas using the same server share the limit of every service, on
+    /// the clock of the server.
+    fn shared_limit() {
+        let clock = ManualClock::new(1000.0);
+        let address = mock("secret", clock.clone());
+        let keeper = |redis: &Arc<Redis>, name: &str, now: f64| {
+            let clock = Arc::new(ManualClock::new(now));
+            Keeper::try_new(2, 60).unwrap().with_clock(clock).with_store(redis.clone().log(name))
+        };
+        let replicas = [0, 1].map(|_| Arc::new(Redis::new(&address, Some("secret"), "jarl")));
+        let mut first = keeper(&replicas[0], "api", 100.0);
+        let mut second = keeper(&replicas[1], "api", 5.0);
+        let mut other = keeper(&replicas[1], "other", 5.0);
+
+        assert_eq!(first.get_delay(), 0.0);
+        assert_eq!(second.get_delay(), 0.0);
+        assert_eq!(first.peek_weighted_delay(1), 60.0 + 30.0);
+        assert_eq!(first.get_delay(), 60.0 + 30.0);
+        assert_eq!((first.backoff_count(), second.backoff_count()), (1.0, 0.0));
+        assert_eq!(other.get_delay(), 0.0);
+
+        assert_eq!(second.quota().remaining, 0);
+        first.release(1);
+        assert_eq!(second.state().timestamps, [5.0]);
+        assert_eq!(second.quota().remaining, 1);
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(second.quota().remaining, 2);
+
+        // A wrong password fails the connection
+        let redis = Arc::new(Redis::new(&address, Some("wrong"), "jarl"));
+        let mut keeper = Keeper::try_new(2, 60).unwrap().with_store(redis.log("api"));
+        assert!(keeper.try_get_weighted_delay(1).is_err());
+    }
+
+    #[test]
+    /// A request that cannot be recorded waits for a full period, unless its
+    /// error is asked for. After a failure, requests fail without connecting
+    /// again for a while.
+    fn unreachable_server() {
+        let address = format!("127.0.0.1:{}", free_port());
+        let redis = Arc::new(Redis::new(&address, None, "jarl"));
+        let mut keeper = Keeper::try_new(2, 60).unwrap().with_store(redis.log("api"));
+
+        assert_eq!(keeper.get_delay(), 60.0);
+        let error = keeper.try_get_weighted_delay(1);
+        assert!(matches!(error, Err(Error::Store(error)) if error.kind() == io::ErrorKind::NotConnected));
+        assert_eq!(keeper.state(), KeeperState::default());
+        assert_eq!(keeper.quota().remaining, 0);
+    }
+
+    #[test]
+    /// The script gives the same delays as a log kept in memory, and its keys
+    /// expire once the requests they record are older than the period.
+    fn script() {
+        let clock = ManualClock::new(100.0);
+        let redis = Arc::new(Redis::new(&mock("", clock.clone()), None, "jarl"));
+        for reserving in [false, true] {
+            let keeper = || match reserving {
+                true => Keeper::try_reserving(3, 2).unwrap(),
+                false => Keeper::try_new(3, 2).unwrap(),
+            }.with_clock(Arc::new(clock.clone()));
+            let mut memory = keeper();
+            let mut stored = keeper().with_store(redis.clone().log("api"));
+            stored.restore(&KeeperState::default());
+
+            for step in 0..40 {
+                clock.advance(Duration::from_millis(step % 7 * 150));
+                let cost = (step % 3 + 1) as u32;
+                assert!((stored.get_weighted_delay(cost) - memory.get_weighted_delay(cost)).abs() < 1e-4);
+                if step % 5 == 0 {
+                    stored.release(1);
+                    memory.release(1);
+                }
+                assert_eq!(stored.state().timestamps.len(), memory.state().timestamps.len());
+                assert_eq!(stored.quota().remaining, memory.quota().remaining);
+            }
+            stored.reconfigure(2, 2).unwrap();
+            memory.reconfigure(2, 2).unwrap();
+            assert_eq!(stored.state().timestamps.len(), memory.state().timestamps.len());
+
+            clock.advance(Duration::from_secs(100));
+            assert_eq!(stored.state(), KeeperState::default());
+        }
+    }
+}
@@ -0,0 +1,134 @@
+-- Commands of Redis used by sliding_log.lua, on keys kept in this state, for
+-- running the script in the tests. NOW is the time of the server, in seconds.
+
+local keys, deadlines = {}, {}
+
+local function get(key)
+  if deadlines[key] and deadlines[key] <= NOW then
+    keys[key], deadlines[key] = nil, nil
+  end
+  return keys[key]
+end
+
+local function set(key, value)
+  keys[key], deadlines[key] = value, nil
+end
+
+-- Redis turns the numbers given to commands into strings
+local function text(value)
+  if type(value) == 'number' then
+    return string.format('%.17g', value)
+  end
+  return value
+end
+
+local function range(list, first, last)
+  first, last = tonumber(first), tonumber(last)
+  if first < 0 then first = math.max(#list + first, 0) end
+  if last < 0 then last = #list + last end
+
+  local values = {}
+  for i = first + 1, math.min(last + 1, #list) do
+    table.insert(values, list[i])
+  end
+  return values
+end
+
+local commands = {}
+
+function commands.TIME()
+  local seconds = math.floor(NOW)
+  return {tostring(seconds), tostring(math.floor((NOW - seconds) * 1000000 + 0.5))}
+end
+
+function commands.GET(key)
+  return get(key) or false
+end
+
+function commands.SET(key, value, option, seconds)
+  set(key, text(value))
+  if option == 'EX' then
+    deadlines[key] = NOW + tonumber(seconds)
+  end
+  return {ok = 'OK'}
+end
+
+function commands.EXPIRE(key, seconds)
+  if get(key) == nil then return 0 end
+  deadlines[key] = NOW + tonumber(seconds)
+  return 1
+end
+
+function commands.TTL(key)
+  if get(key) == nil then return -2 end
+  if deadlines[key] == nil then return -1 end
+  return math.floor(deadlines[key] - NOW + 0.5)
+end
+
+function commands.DEL(...)
+  local count = 0
+  for _, key in ipairs({...}) do
+    if get(key) ~= nil then count = count + 1 end
+    set(key, nil)
+  end
+  return count
+end
+
+function commands.LLEN(key)
+  return #(get(key) or {})
+end
+
+function commands.LINDEX(key, index)
+  local list = get(key) or {}
+  index = tonumber(index)
+  if index < 0 then index = #list + index end
+  return list[index + 1] or false
+end
+
+function commands.RPUSH(key, ...)
+  local list = get(key) or {}
+  for _, value in ipairs({...}) do
+    table.insert(list, text(value))
+  end
+  keys[key] = list
+  return #list
+end
+
+function commands.RPOP(key)
+  local list = get(key)
+  if list == nil then return false end
+  local value = table.remove(list)
+  if #list == 0 then set(key, nil) end
+  return value
+end
+
+function commands.LTRIM(key, first, last)
+  local list = get(key)
+  if list then
+    list = range(list, first, last)
+    if #list == 0 then set(key, nil) else keys[key] = list end
+  end
+  return {ok = 'OK'}
+end
+
+function commands.LRANGE(key, first, last)
+  return range(get(key) or {}, first, last)
+end
+
+redis = {}
+
+function redis.call(command, ...)
+  local run = commands[command:upper()]
+  if run == nil then
+    error('unknown command ' .. command)
+  end
+  return run(...)
+end
+
+function redis.error_reply(message)
+  return {err = message}
+end
+
+function redis.replicate_commands()
+  return true
+end
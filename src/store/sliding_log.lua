@@ -0,0 +1,128 @@
+-- Sliding log of a service, as a list of timestamps, oldest first (KEYS[1]),
+-- and a backoff count (KEYS[2]), updated atomically by the replicas of jarl.
+--
+-- ARGV[1] is the operation, followed by its arguments. Numbers are replied as
+-- strings, as Redis truncates the numbers of Lua to integers. Timestamps are
+-- taken from the clock of the server, so that the replicas agree on them.
+
+local log, backoff_key = KEYS[1], KEYS[2]
+local operation = ARGV[1]
+
+-- Replicates the writes instead of the script, which reads the clock
+redis.replicate_commands()
+
+local function clock()
+  local time = redis.call('TIME')
+  return tonumber(time[1]) + tonumber(time[2]) / 1000000
+end
+
+local function backoff_count()
+  return tonumber(redis.call('GET', backoff_key)) or 0
+end
+
+-- Keeps the keys until the newest timestamp is a period old
+local function expire(newest, now, period)
+  local seconds = math.ceil(math.max(newest - now, 0) + period) + 1
+  redis.call('EXPIRE', log, seconds)
+  redis.call('EXPIRE', backoff_key, seconds)
+end
+
+local function push(timestamp, cost, limit)
+  for _ = 1, cost do
+    redis.call('RPUSH', log, timestamp)
+  end
+  redis.call('LTRIM', log, -limit, -1)
+end
+
+-- acquire COST LIMIT PERIOD BASE_DELAY RECORD, replying the delay and the
+-- backoff count after the request
+if operation == 'acquire' then
+  local now, cost, limit, period, base_delay = clock(), tonumber(ARGV[2]), tonumber(ARGV[3]), tonumber(ARGV[4]), tonumber(ARGV[5])
+
+  -- The newest timestamp pushed out of the log is the one that must be a
+  -- full period old for every unit of the request to fit
+  local length = redis.call('LLEN', log)
+  local last = nil
+  if length + cost > limit then
+    last = tonumber(redis.call('LINDEX', log, length + cost - limit - 1))
+  end
+
+  local count, delay = 0, 0
+  if last and now - last < period then
+    count = backoff_count() + cost
+    delay = base_delay * count + period - (now - last)
+  end
+
+  count = string.format('%.6f', count)
+  if ARGV[6] == '1' then
+    push(string.format('%.6f', now), cost, limit)
+    redis.call('SET', backoff_key, count)
+    expire(now, now, period)
+  end
+  return {string.format('%.6f', delay), count}
+
+-- reserve COST LIMIT PERIOD RECORD, replying the delay
+elseif operation == 'reserve' then
+  local now, cost, limit, period = clock(), tonumber(ARGV[2]), tonumber(ARGV[3]), tonumber(ARGV[4])
+  local slot = now
+  local room = limit - cost
+
+  local length = redis.call('LLEN', log)
+  if length > room then
+    local oldest = tonumber(redis.call('LINDEX', log, length - room - 1))
+    if oldest + period > now then
+      local newest = tonumber(redis.call('LINDEX', log, -1))
+      slot = math.max(oldest + period, newest + period / limit)
+    end
+  end
+
+  if ARGV[5] == '1' then
+    push(string.format('%.6f', slot), cost, limit)
+    expire(slot, now, period)
+  end
+  return string.format('%.6f', slot - now)
+
+-- release COST
+elseif operation == 'release' then
+  local cost = tonumber(ARGV[2])
+  for _ = 1, cost do
+    redis.call('RPOP', log)
+  end
+
+  -- The backoff count is only missing once it expired, along with the log
+  local ttl = redis.call('TTL', backoff_key)
+  if ttl > 0 then
+    redis.call('SET', backoff_key, string.format('%.6f', math.max(backoff_count() - cost, 0)), 'EX', ttl)
+  end
+  return 'OK'
+
+-- state, replying the time of the server and the backoff count, followed by
+-- the timestamps
+elseif operation == 'state' then
+  local state = redis.call('LRANGE', log, 0, -1)
+  table.insert(state, 1, string.format('%.6f', backoff_count()))
+  table.insert(state, 1, string.format('%.6f', clock()))
+  return state
+
+-- resize LIMIT
+elseif operation == 'resize' then
+  redis.call('LTRIM', log, -tonumber(ARGV[2]), -1)
+  return 'OK'
+
+-- replace NOW PERIOD BACKOFF_COUNT TIMESTAMP..., with timestamps taken at NOW
+-- by the clock of the replica
+elseif operation == 'replace' then
+  local now = clock()
+  local offset = now - tonumber(ARGV[2])
+  redis.call('DEL', log, backoff_key)
+  if #ARGV > 4 then
+    for i = 5, #ARGV do
+      redis.call('RPUSH', log, string.format('%.6f', tonumber(ARGV[i]) + offset))
+    end
+    redis.call('SET', backoff_key, ARGV[4])
+    expire(tonumber(ARGV[#ARGV]) + offset, now, tonumber(ARGV[3]))
+  end
+  return 'OK'
+end
+
+return redis.error_reply('unknown operation ' .. tostring(operation))
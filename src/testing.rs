@@ -0,0 +1,24 @@
+use std::net::{TcpListener, UdpSocket};
+use std::sync::atomic::{AtomicU16, Ordering};
+
+
+/// Ports handed out by `free_port`, below the ephemeral ports the system
+/// assigns when binding port 0, in a block of 100 ports per process.
+static NEXT_PORT: AtomicU16 = AtomicU16::new(0);
+
+/// A port nothing listens on over TCP or UDP, for a listener to be bound to
+/// later. Listeners are keyed by their configured address, so several of
+/// them cannot be bound to port 0. The port is never handed out twice by a
+/// process, nor assigned to the sockets bound to port 0 in the meantime.
+pub fn free_port() -> u16 {
+    let base = 20000 + (std::process::id() % 120) as u16 * 100;
+    let _ = NEXT_PORT.compare_exchange(0, base, Ordering::Relaxed, Ordering::Relaxed);
+
+    loop {
+        let port = NEXT_PORT.fetch_add(1, Ordering::Relaxed);
+        assert!(port < base + 100, "Every port of the block of the process is used.");
+        if TcpListener::bind(("127.0.0.1", port)).is_ok() && UdpSocket::bind(("127.0.0.1", port)).is_ok() {
+            return port;
+        }
+    }
+}
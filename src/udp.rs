@@ -0,0 +1,259 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use tokio::net::UdpSocket;
+
+use crate::{error, protocol, Command, ProtocolError, Registry, Response};
+
+
+/// Largest datagram read from clients. Longer datagrams are truncated, and
+/// most likely rejected.
+const MAX_DATAGRAM_LENGTH: usize = 512;
+
+/// Longest request id accepted from clients.
+const MAX_ID_LENGTH: usize = 64;
+
+/// How long the reply to a request is kept, to be sent again if the client
+/// retransmits the request.
+pub const REPLY_TTL: Duration = Duration::from_secs(10);
+
+/// Most replies kept at once. The oldest ones are dropped first.
+const MAX_CACHED_REPLIES: usize = 65536;
+
+
+/// Client address and request id of a datagram.
+type Key = (SocketAddr, String);
+
+
+/// Replies to the requests that record or release units, keyed by client
+/// address and request id, so that a request retransmitted after its reply
+/// was lost is not counted twice.
+pub struct ReplyCache {
+    replies: HashMap<Key, (Result<Response, ProtocolError>, Instant)>,
+    /// Keys in the order they were inserted, to expire the oldest first
+    order: VecDeque<(Key, Instant)>,
+    /// Keys of the requests still being executed
+    pending: HashSet<Key>,
+    ttl: Duration,
+    capacity: usize,
+}
+
+/// What to do with a request, as found in a `ReplyCache`.
+#[derive(Debug, PartialEq)]
+pub enum Lookup {
+    /// The request was already executed, and gets the same reply again
+    Reply(Result<Response, ProtocolError>),
+    /// The request is being executed, and its retransmission is dropped
+    Pending,
+    /// The request must be executed, and its reply inserted
+    Execute,
+}
+
+impl ReplyCache {
+
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        ReplyCache { replies: HashMap::new(), order: VecDeque::new(), pending: HashSet::new(), ttl, capacity }
+    }
+
+    /// Looks up a request received at `now`, replaying the cached reply if
+    /// the same request id was already received from the same address.
+    /// Delays of replayed replies are shortened by the time elapsed since the
+    /// request was first received. Otherwise, the request is pending until
+    /// its reply is inserted.
+    pub fn lookup(&mut self, key: &Key, now: Instant) -> Lookup {
+        self.expire(now);
+
+        if let Some((outcome, received)) = self.replies.get(key) {
+            return Lookup::Reply(match outcome {
+                Ok(Response::Delay(delay)) => {
+                    let elapsed = now.saturating_duration_since(*received).as_secs_f32();
+                    Ok(Response::Delay((delay - elapsed).max(0.0)))
+                }
+                outcome => outcome.clone(),
+            });
+        }
+        match self.pending.insert(key.clone()) {
+            true => Lookup::Execute,
+            false => Lookup::Pending,
+        }
+    }
+
+    /// Keeps the reply to a request received at `received`, once executed.
+    pub fn insert(&mut self, key: Key, received: Instant, outcome: Result<Response, ProtocolError>) {
+        self.pending.remove(&key);
+        if self.replies.len() >= self.capacity {
+            if let Some((oldest, _received)) = self.order.pop_front() {
+                self.replies.remove(&oldest);
+            }
+        }
+        self.order.push_back((key.clone(), received));
+        self.replies.insert(key, (outcome, received));
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some((key, received)) = self.order.front() {
+            if now.saturating_duration_since(*received) < self.ttl {
+                break;
+            }
+            self.replies.remove(key);
+            self.order.pop_front();
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.replies.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.replies.is_empty()
+    }
+
+}
+
+
+/// Answers datagrams holding a request id followed by a command of the line
+/// protocol, replying with the same id followed by the reply to the command.
+/// Datagrams without an id are dropped. Every datagram is answered by a task
+/// of its own, so that a slow request does not hold up the others.
+pub async fn serve_udp(socket: UdpSocket, registry: Arc<Registry>) {
+    let socket = Arc::new(socket);
+    let cache = Arc::new(Mutex::new(ReplyCache::new(REPLY_TTL, MAX_CACHED_REPLIES)));
+    let mut buffer = [0; MAX_DATAGRAM_LENGTH];
+
+    loop {
+        let Ok((length, address)) = socket.recv_from(&mut buffer).await else {
+            continue;
+        };
+        let Some((id, line)) = parse_datagram(&buffer[..length]) else {
+            continue;
+        };
+
+        let (id, command) = (id.to_string(), Command::parse(line));
+        tokio::spawn(answer(socket.clone(), registry.clone(), cache.clone(), address, id, command));
+    }
+}
+
+/// Executes the command of a datagram and sends its reply, unless the same
+/// request is still being executed.
+async fn answer(
+    socket: Arc<UdpSocket>,
+    registry: Arc<Registry>,
+    cache: Arc<Mutex<ReplyCache>>,
+    address: SocketAddr,
+    id: String,
+    command: Result<Command, ProtocolError>,
+) {
+    let lock = || cache.lock().unwrap_or_else(PoisonError::into_inner);
+    let outcome = match command {
+        Ok(command @ (Command::Acquire { .. } | Command::Release { .. })) => {
+            let (key, received) = ((address, id.clone()), Instant::now());
+            let lookup = lock().lookup(&key, received);
+            match lookup {
+                Lookup::Reply(outcome) => outcome,
+                Lookup::Pending => return,
+                Lookup::Execute => {
+                    let outcome = registry.run(move |registry| command.execute(registry)).await;
+                    lock().insert(key, received, outcome.clone());
+                    outcome
+                }
+            }
+        }
+        Ok(command) => registry.run(move |registry| command.execute(registry)).await,
+        Err(error) => Err(error),
+    };
+
+    let reply = format!("{} {}\n", id, protocol::reply(&outcome));
+    if let Err(error) = socket.send_to(reply.as_bytes(), address).await {
+        error::report(&crate::Error::Connection(error));
+    }
+}
+
+/// Splits a datagram into its request id and command.
+fn parse_datagram(datagram: &[u8]) -> Option<(&str, &str)> {
+    let text = std::str::from_utf8(datagram).ok()?.trim();
+    let (id, line) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+
+    if id.is_empty() || id.len() > MAX_ID_LENGTH {
+        return None;
+    }
+    Some((id, line.trim_start()))
+}
+
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use tokio::net::UdpSocket;
+
+    use crate::{Keeper, ProtocolError, Registry, Response};
+    use crate::udp::{parse_datagram, serve_udp, Lookup, ReplyCache};
+
+    #[test]
+    /// A retransmitted request gets the reply of the first one, with the time
+    /// elapsed since taken off its delay, until the reply expires. It is
+    /// dropped while the first one is still being executed.
+    fn replay_replies() {
+        let mut cache = ReplyCache::new(Duration::from_secs(10), 2);
+        let address = "127.0.0.1:1000".parse().unwrap();
+        let start = Instant::now();
+        let key = |id: &str| (address, id.to_string());
+
+        assert_eq!(cache.lookup(&key("1"), start), Lookup::Execute);
+        assert_eq!(cache.lookup(&key("1"), start), Lookup::Pending);
+        cache.insert(key("1"), start, Ok(Response::Delay(5.0)));
+        assert_eq!(cache.lookup(&key("1"), start + Duration::from_secs(2)), Lookup::Reply(Ok(Response::Delay(3.0))));
+
+        let unknown = Err(ProtocolError::UnknownService(String::from("missing")));
+        assert_eq!(cache.lookup(&key("2"), start), Lookup::Execute);
+        cache.insert(key("2"), start, unknown.clone());
+        assert_eq!(cache.lookup(&key("2"), start), Lookup::Reply(unknown));
+
+        // The oldest reply is dropped to make room
+        assert_eq!(cache.lookup(&key("3"), start), Lookup::Execute);
+        cache.insert(key("3"), start, Ok(Response::Released));
+        assert_eq!(cache.lookup(&key("1"), start), Lookup::Execute);
+        cache.insert(key("1"), start, Ok(Response::Delay(1.0)));
+
+        // Expired replies are dropped
+        assert_eq!(cache.lookup(&key("3"), start + Duration::from_secs(10)), Lookup::Execute);
+        assert_eq!(cache.len(), 0);
+    }
+
+    #[test]
+    fn parse_datagrams() {
+        assert_eq!(parse_datagram(b"7 ACQUIRE api\n"), Some(("7", "ACQUIRE api")));
+        assert_eq!(parse_datagram(b"abc  STATUS"), Some(("abc", "STATUS")));
+        assert_eq!(parse_datagram(b"7"), Some(("7", "")));
+        assert_eq!(parse_datagram(b"  \n"), None);
+        assert_eq!(parse_datagram(&[b'7', b' ', 0xff]), None);
+    }
+
+    #[tokio::test]
+    async fn answer_datagrams() {
+        let registry = Registry::new();
+        registry.insert("api", Box::new(Keeper::try_new(1, 60).unwrap())).unwrap();
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = socket.local_addr().unwrap();
+        tokio::spawn(serve_udp(socket, Arc::new(registry)));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(address).await.unwrap();
+        let mut buffer = [0; 512];
+        let mut query = async |datagram: &str| {
+            client.send(datagram.as_bytes()).await.unwrap();
+            let length = client.recv(&mut buffer).await.unwrap();
+            String::from_utf8(buffer[..length].to_vec()).unwrap()
+        };
+
+        assert_eq!(query("1 ACQUIRE api").await, "1 JARL/1 OK 0.000\n");
+        assert_eq!(query("1 ACQUIRE api").await, "1 JARL/1 OK 0.000\n", "Retransmission should not count.");
+        assert_ne!(query("2 ACQUIRE api").await, "2 JARL/1 OK 0.000\n");
+        assert_eq!(query("3 api").await, "3 JARL/1 ERR unknown-command unknown command `api`\n");
+    }
+}